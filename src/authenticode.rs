@@ -0,0 +1,135 @@
+//! Authenticode checks of downloaded executables, so a tampered vswhere is never run
+
+use crate::MsvcEnvError;
+use std::path::Path;
+
+/// Subject of the certificate Microsoft signs its tools with
+const MICROSOFT_SIGNER: &str = "Microsoft Corporation";
+
+/// Checks that `path` carries a valid Authenticode signature that chains to a
+/// trusted root, with revocation checked, and that Microsoft is the signer
+#[cfg(windows)]
+pub(crate) fn verify(path: &Path) -> Result<(), MsvcEnvError> {
+    use std::ffi::c_void;
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+    use windows_sys::Win32::Security::Cryptography::{
+        CERT_NAME_SIMPLE_DISPLAY_TYPE, CertGetNameStringW,
+    };
+    use windows_sys::Win32::Security::WinTrust::{
+        WINTRUST_ACTION_GENERIC_VERIFY_V2, WINTRUST_DATA, WINTRUST_DATA_0, WINTRUST_FILE_INFO,
+        WTD_CACHE_ONLY_URL_RETRIEVAL, WTD_CHOICE_FILE, WTD_REVOKE_WHOLECHAIN,
+        WTD_STATEACTION_CLOSE, WTD_STATEACTION_VERIFY, WTD_UI_NONE, WTHelperGetProvSignerFromChain,
+        WTHelperProvDataFromStateData, WinVerifyTrust,
+    };
+
+    let _span = tracing::debug_span!("authenticode", path = %path.display()).entered();
+    let untrusted = |reason: String| MsvcEnvError::UntrustedSignature(path.to_path_buf(), reason);
+
+    let wide_path = path
+        .as_os_str()
+        .encode_wide()
+        .chain(Some(0))
+        .collect::<Vec<u16>>();
+    let mut file_info = WINTRUST_FILE_INFO {
+        cbStruct: size_of::<WINTRUST_FILE_INFO>() as u32,
+        pcwszFilePath: wide_path.as_ptr(),
+        hFile: ptr::null_mut(),
+        pgKnownSubject: ptr::null_mut(),
+    };
+    let mut data = WINTRUST_DATA {
+        cbStruct: size_of::<WINTRUST_DATA>() as u32,
+        pPolicyCallbackData: ptr::null_mut(),
+        pSIPClientData: ptr::null_mut(),
+        dwUIChoice: WTD_UI_NONE,
+        fdwRevocationChecks: WTD_REVOKE_WHOLECHAIN,
+        dwUnionChoice: WTD_CHOICE_FILE,
+        Anonymous: WINTRUST_DATA_0 {
+            pFile: &mut file_info,
+        },
+        dwStateAction: WTD_STATEACTION_VERIFY,
+        hWVTStateData: ptr::null_mut(),
+        pwszURLReference: ptr::null_mut(),
+        // Revocation lists are only read from the local cache when offline
+        dwProvFlags: if crate::overrides::offline() {
+            WTD_CACHE_ONLY_URL_RETRIEVAL
+        } else {
+            0
+        },
+        dwUIContext: 0,
+        pSignatureSettings: ptr::null_mut(),
+    };
+    let mut action = WINTRUST_ACTION_GENERIC_VERIFY_V2;
+
+    // SAFETY: `data` and everything it points to outlive both calls, and the
+    // state opened by the verify call is closed below on every path
+    let status = unsafe {
+        WinVerifyTrust(
+            ptr::null_mut(),
+            &mut action,
+            &mut data as *mut WINTRUST_DATA as *mut c_void,
+        )
+    };
+    let signer = (status == 0).then(|| {
+        // SAFETY: the provider data belongs to the open state and is only read
+        // before the state is closed
+        unsafe {
+            let provider = WTHelperProvDataFromStateData(data.hWVTStateData);
+            let signer = WTHelperGetProvSignerFromChain(provider, 0, 0, 0);
+            if signer.is_null() || (*signer).csCertChain == 0 {
+                return None;
+            }
+            let cert = (*(*signer).pasCertChain).pCert;
+            let mut name = [0u16; 256];
+            let len = CertGetNameStringW(
+                cert,
+                CERT_NAME_SIMPLE_DISPLAY_TYPE,
+                0,
+                ptr::null(),
+                name.as_mut_ptr(),
+                name.len() as u32,
+            );
+            // The length includes the terminating null
+            Some(String::from_utf16_lossy(
+                &name[..(len as usize).saturating_sub(1)],
+            ))
+        }
+    });
+
+    data.dwStateAction = WTD_STATEACTION_CLOSE;
+    // SAFETY: closes the state opened above
+    unsafe {
+        WinVerifyTrust(
+            ptr::null_mut(),
+            &mut action,
+            &mut data as *mut WINTRUST_DATA as *mut c_void,
+        );
+    }
+
+    match signer {
+        None => Err(untrusted(format!(
+            "WinVerifyTrust failed with 0x{:08x}",
+            status as u32
+        ))),
+        Some(None) => Err(untrusted("no signer certificate".to_string())),
+        Some(Some(name)) if name == MICROSOFT_SIGNER => {
+            tracing::trace!("{} is signed by {}", path.display(), name);
+            Ok(())
+        }
+        Some(Some(name)) => Err(untrusted(format!(
+            "signed by {:?} instead of {:?}",
+            name, MICROSOFT_SIGNER
+        ))),
+    }
+}
+
+#[cfg(not(windows))]
+pub(crate) fn verify(path: &Path) -> Result<(), MsvcEnvError> {
+    Err(MsvcEnvError::UntrustedSignature(
+        path.to_path_buf(),
+        format!(
+            "Authenticode signatures can only be checked on Windows, expected {:?}",
+            MICROSOFT_SIGNER
+        ),
+    ))
+}
@@ -0,0 +1,82 @@
+//! A minimal, well-defined environment to capture on top of, so snapshots made
+//! on one machine contain nothing from the developer's personal environment
+
+use crate::Selection;
+use std::collections::BTreeMap;
+use std::env;
+
+/// Variables taken over from the current environment. `PATH` is not among
+/// them, it is rebuilt from `SystemRoot`.
+pub const BASELINE_VARS: &[&str] = &[
+    "SystemRoot",
+    "SystemDrive",
+    "windir",
+    "ComSpec",
+    "PATHEXT",
+    "TEMP",
+    "TMP",
+    "ProgramData",
+    "ProgramFiles",
+    "ProgramFiles(x86)",
+    "ProgramW6432",
+    "CommonProgramFiles",
+    "CommonProgramFiles(x86)",
+    "CommonProgramW6432",
+    "NUMBER_OF_PROCESSORS",
+    "PROCESSOR_ARCHITECTURE",
+    "OS",
+];
+
+/// The baseline for this machine
+pub fn baseline() -> BTreeMap<String, String> {
+    baseline_from(env::vars())
+}
+
+/// Picks the [`BASELINE_VARS`] out of `vars`, matching names case-insensitively
+/// like Windows does, and adds a `PATH` of just the system directories
+pub fn baseline_from(vars: impl IntoIterator<Item = (String, String)>) -> BTreeMap<String, String> {
+    let mut baseline = BTreeMap::new();
+    for (key, value) in vars {
+        if let Some(name) = BASELINE_VARS.iter().find(|x| x.eq_ignore_ascii_case(&key)) {
+            baseline.insert(name.to_string(), value);
+        }
+    }
+    if let Some(root) = baseline.get("SystemRoot") {
+        let path = format!(
+            r"{0}\system32;{0};{0}\System32\Wbem;{0}\System32\WindowsPowerShell\v1.0\",
+            root
+        );
+        baseline.insert("PATH".to_string(), path);
+    }
+    baseline
+}
+
+impl Selection {
+    /// Captures on top of the [`baseline`] instead of the calling process's
+    /// environment
+    pub fn clean_baseline(self) -> Self {
+        self.base_env(baseline())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_baseline_from() {
+        let vars = [
+            ("SYSTEMROOT", r"C:\Windows"),
+            ("TEMP", r"C:\Users\me\AppData\Local\Temp"),
+            ("Path", r"C:\Users\me\bin;C:\Windows\system32"),
+            ("GITHUB_TOKEN", "secret"),
+        ];
+        let baseline = baseline_from(vars.map(|(k, v)| (k.to_string(), v.to_string())));
+        assert_eq!(
+            baseline.keys().collect::<Vec<_>>(),
+            ["PATH", "SystemRoot", "TEMP"]
+        );
+        assert!(baseline["PATH"].starts_with(r"C:\Windows\system32;C:\Windows;"));
+        assert!(baseline_from(Vec::new()).is_empty());
+    }
+}
@@ -0,0 +1,210 @@
+//! Toolchains for other targets that Visual Studio installs next to MSVC: the
+//! Clang/LLVM of the C++ Clang tools, the Android NDK of the mobile development
+//! workload and the headers and tools of the Linux development workload
+
+use crate::MsvcEnvironment;
+use crate::layout::version_key;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where Visual Studio puts the Android NDK, relative to the system drive:
+/// VS 2022 and VS 2019 respectively
+const ANDROID_NDK_DIRS: &[&str] = &[r"Microsoft\AndroidNDK", r"Microsoft\AndroidNDK64"];
+
+/// Clang/LLVM installed with the `Microsoft.VisualStudio.Component.VC.Llvm.Clang`
+/// component
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Llvm {
+    /// e.g. `...\VC\Tools\Llvm`
+    pub root: PathBuf,
+    /// The x64-hosted `bin` directory with `clang.exe` and `clang-cl.exe`
+    pub bin: PathBuf,
+}
+
+/// An Android NDK installed by the mobile development with C++ workload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AndroidNdk {
+    /// e.g. `C:\Microsoft\AndroidNDK\android-ndk-r23c`
+    pub root: PathBuf,
+    /// `Pkg.Revision` of `source.properties`, e.g. `23.2.8568313`
+    pub version: Option<String>,
+    /// `bin` directory of the NDK's clang, which targets every Android ABI
+    pub clang_bin: PathBuf,
+}
+
+/// The Linux development with C++ workload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinuxTools {
+    /// e.g. `...\Common7\IDE\VC\Linux`
+    pub root: PathBuf,
+    /// Headers copied from remote machines for IntelliSense, one directory per
+    /// connection; empty until a connection was used
+    pub include_dirs: Vec<PathBuf>,
+}
+
+/// Everything found for an installation. Each part is `None` or empty when its
+/// workload or component is not installed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BundledToolchains {
+    pub llvm: Option<Llvm>,
+    /// Newest first
+    pub android_ndks: Vec<AndroidNdk>,
+    pub linux: Option<LinuxTools>,
+}
+
+impl BundledToolchains {
+    /// Looks for the toolchains of the installation `env` was set up from
+    pub fn detect(env: &MsvcEnvironment) -> Option<BundledToolchains> {
+        let vs_path = &env.installation()?.path;
+        let system_drive = std::env::var("SystemDrive").unwrap_or_else(|_| "C:".to_string());
+        let ndk_dirs = ANDROID_NDK_DIRS
+            .iter()
+            .map(|dir| PathBuf::from(format!(r"{}\{}", system_drive, dir)))
+            .collect::<Vec<_>>();
+        Some(BundledToolchains::at(vs_path, &ndk_dirs))
+    }
+
+    /// Looks in the installation at `vs_path`, and for NDKs in `ndk_dirs`
+    pub fn at(vs_path: &Path, ndk_dirs: &[PathBuf]) -> BundledToolchains {
+        let llvm_root = vs_path.join("VC").join("Tools").join("Llvm");
+        let llvm = Some(llvm_root.join("x64").join("bin"))
+            .filter(|bin| bin.join("clang.exe").exists())
+            .map(|bin| Llvm {
+                root: llvm_root,
+                bin,
+            });
+
+        let mut android_ndks = ndk_dirs
+            .iter()
+            .flat_map(|dir| fs::read_dir(dir).into_iter().flatten())
+            .filter_map(|entry| AndroidNdk::at(&entry.ok()?.path()))
+            .collect::<Vec<_>>();
+        android_ndks
+            .sort_by_key(|ndk| std::cmp::Reverse(ndk.version.as_deref().and_then(version_key)));
+
+        let linux_root = vs_path.join("Common7").join("IDE").join("VC").join("Linux");
+        let linux = linux_root.is_dir().then(|| LinuxTools {
+            include_dirs: fs::read_dir(linux_root.join("include"))
+                .into_iter()
+                .flatten()
+                .filter_map(|entry| Some(entry.ok()?.path()).filter(|x| x.is_dir()))
+                .collect(),
+            root: linux_root,
+        });
+
+        let found = BundledToolchains {
+            llvm,
+            android_ndks,
+            linux,
+        };
+        tracing::trace!("Bundled toolchains in {}: {:?}", vs_path.display(), found);
+        found
+    }
+}
+
+impl AndroidNdk {
+    /// The NDK at `root`, if it has a Windows-hosted clang
+    pub fn at(root: &Path) -> Option<AndroidNdk> {
+        let clang_bin = root
+            .join("toolchains")
+            .join("llvm")
+            .join("prebuilt")
+            .join("windows-x86_64")
+            .join("bin");
+        if !clang_bin.join("clang.exe").exists() {
+            return None;
+        }
+        let version = fs::read_to_string(root.join("source.properties"))
+            .ok()
+            .and_then(|text| {
+                text.lines().find_map(|line| {
+                    let (key, value) = line.split_once('=')?;
+                    (key.trim() == "Pkg.Revision").then(|| value.trim().to_string())
+                })
+            });
+        Some(AndroidNdk {
+            root: root.to_path_buf(),
+            version,
+            clang_bin,
+        })
+    }
+}
+
+impl MsvcEnvironment {
+    /// The other toolchains the installation behind this environment has, see
+    /// [`BundledToolchains`]. `None` if the installation is not known.
+    pub fn bundled_toolchains(&self) -> Option<BundledToolchains> {
+        BundledToolchains::detect(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bundled_toolchains() {
+        let root = std::env::temp_dir().join(format!("msvc-env-bundled-{}", std::process::id()));
+        let vs = root.join("VS");
+        let ndks = root.join("AndroidNDK");
+        let touch = |path: PathBuf| {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        };
+        let clang = |ndk: &str| {
+            ndks.join(ndk)
+                .join(r"toolchains/llvm/prebuilt/windows-x86_64/bin/clang.exe")
+        };
+
+        assert_eq!(
+            BundledToolchains::at(&vs, std::slice::from_ref(&ndks)),
+            BundledToolchains::default()
+        );
+
+        touch(
+            vs.join("VC")
+                .join("Tools")
+                .join("Llvm")
+                .join("x64")
+                .join("bin")
+                .join("clang.exe"),
+        );
+        fs::create_dir_all(
+            vs.join("Common7")
+                .join("IDE")
+                .join("VC")
+                .join("Linux")
+                .join("include")
+                .join("remote"),
+        )
+        .unwrap();
+        touch(clang("android-ndk-r16b"));
+        fs::write(
+            ndks.join("android-ndk-r16b").join("source.properties"),
+            "Pkg.Desc = Android NDK\nPkg.Revision = 16.1.4479499\n",
+        )
+        .unwrap();
+        touch(clang("android-ndk-r23c"));
+        fs::write(
+            ndks.join("android-ndk-r23c").join("source.properties"),
+            "Pkg.Revision = 23.2.8568313\n",
+        )
+        .unwrap();
+        // Without a Windows-hosted clang
+        fs::create_dir_all(ndks.join("android-ndk-r10")).unwrap();
+
+        let found = BundledToolchains::at(&vs, std::slice::from_ref(&ndks));
+        assert!(found.llvm.unwrap().bin.ends_with("bin"));
+        assert_eq!(
+            found
+                .android_ndks
+                .iter()
+                .map(|x| x.version.as_deref().unwrap())
+                .collect::<Vec<_>>(),
+            ["23.2.8568313", "16.1.4479499"]
+        );
+        assert_eq!(found.linux.unwrap().include_dirs.len(), 1);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,378 @@
+//! On-disk cache layout. Everything lives below a versioned directory in the
+//! cache directory (see [`overrides::CACHE_DIR`](crate::overrides::CACHE_DIR)),
+//! so CI pipelines can persist it between runs:
+//!
+//! ```text
+//! <cache dir>/
+//!   v1/                           bumped whenever this layout changes
+//!     vswhere/<version>/vswhere.exe
+//!     vswhere/<version>/vswhere.version  the version the executable reports
+//!     env/<arch>-<hash>.env       environments written by `MsvcEnv::prewarm`
+//!     bootstrapper/               Visual Studio bootstrappers
+//! ```
+//!
+//! Paths within one layout version never change. A newer vswhere is put next to
+//! the old one instead of replacing it, so a restored cache is never half updated.
+//! [`key`] changes whenever the cached content would have to, which makes it
+//! suitable as an `actions/cache` key.
+//!
+//! [`prune`] keeps the cache within a size budget, removing other layout
+//! versions and vswhere releases and then whatever was used least recently.
+
+use crate::{MsvcEnvError, Selection};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Version of the directory layout below the cache directory
+pub const LAYOUT_VERSION: u32 = 1;
+
+/// Size budget of the cache directory unless one is configured, see
+/// [`overrides::CACHE_MAX_SIZE`](crate::overrides::CACHE_MAX_SIZE)
+pub const DEFAULT_MAX_SIZE: u64 = 1 << 30;
+
+/// Release of vswhere that is downloaded when none is installed
+pub(crate) const VSWHERE_VERSION: &str = "3.1.7";
+
+const VSWHERE_EXE: &str = "vswhere.exe";
+
+/// The versioned root below `cache_dir` that everything else lives in
+pub fn root(cache_dir: &Path) -> PathBuf {
+    cache_dir.join(format!("v{}", LAYOUT_VERSION))
+}
+
+/// Where the downloaded vswhere is kept
+pub fn vswhere_path(cache_dir: &Path) -> PathBuf {
+    root(cache_dir)
+        .join("vswhere")
+        .join(VSWHERE_VERSION)
+        .join(VSWHERE_EXE)
+}
+
+/// Directory for environment snapshots
+pub fn env_dir(cache_dir: &Path) -> PathBuf {
+    root(cache_dir).join("env")
+}
+
+/// Where the environment for `selection` is kept, see
+/// [`MsvcEnv::prewarm`](crate::MsvcEnv::prewarm)
+pub fn env_path(cache_dir: &Path, selection: &Selection) -> PathBuf {
+    env_dir(cache_dir).join(env_file_name(selection))
+}
+
+/// `<arch>-<hash>.env`, with the hash of [`key`]
+pub(crate) fn env_file_name(selection: &Selection) -> String {
+    format!(
+        "{}-{:016x}.env",
+        selection.arch.as_str(),
+        selection_hash(selection)
+    )
+}
+
+/// Parses a size such as `1048576`, `512K`, `500M` or `2G`, with binary units
+/// and an optional trailing `B` or `iB`
+pub fn parse_size(text: &str) -> Result<u64, MsvcEnvError> {
+    let invalid = || MsvcEnvError::ConfigError(format!("invalid size {:?}", text));
+    let upper = text.trim().to_ascii_uppercase();
+    let number = upper.trim_end_matches("IB").trim_end_matches('B');
+    let (number, shift) = match number.as_bytes().last() {
+        Some(b'K') => (&number[..number.len() - 1], 10),
+        Some(b'M') => (&number[..number.len() - 1], 20),
+        Some(b'G') => (&number[..number.len() - 1], 30),
+        Some(b'T') => (&number[..number.len() - 1], 40),
+        _ => (number, 0),
+    };
+    let number = number.trim().parse::<u64>().map_err(|_| invalid())?;
+    number.checked_mul(1 << shift).ok_or_else(invalid)
+}
+
+/// What [`prune`] removed and what is left
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Pruned {
+    pub removed: Vec<PathBuf>,
+    /// Bytes freed
+    pub freed: u64,
+    /// Bytes still in the cache
+    pub kept: u64,
+}
+
+/// Shrinks the cache in `cache_dir` to at most `max_size` bytes. Other layout
+/// versions and vswhere releases are always removed. Then entries, such as a
+/// snapshot or a downloaded bootstrapper, go least recently used first until
+/// the rest fits. The vswhere in use is kept regardless.
+pub fn prune(cache_dir: &Path, max_size: u64) -> io::Result<Pruned> {
+    let mut pruned = Pruned::default();
+    let root = root(cache_dir);
+    let current_vswhere = vswhere_path(cache_dir);
+    let current_vswhere = current_vswhere.parent().unwrap();
+
+    let mut stale = Vec::new();
+    let mut entries = Vec::new();
+    for layout in read_dir(cache_dir)? {
+        if layout != root && is_layout_dir(&layout) {
+            stale.push(layout);
+        }
+    }
+    for dir in read_dir(&root)? {
+        if !dir.is_dir() {
+            continue;
+        }
+        for entry in read_dir(&dir)? {
+            if dir.ends_with("vswhere") && entry != current_vswhere {
+                stale.push(entry);
+            } else {
+                let (size, used) = usage(&entry)?;
+                pruned.kept += size;
+                if entry != current_vswhere {
+                    entries.push((used, size, entry));
+                }
+            }
+        }
+    }
+
+    for path in stale {
+        let (size, _) = usage(&path)?;
+        remove(&path)?;
+        pruned.freed += size;
+        pruned.removed.push(path);
+    }
+
+    entries.sort();
+    for (_, size, path) in entries {
+        if pruned.kept <= max_size {
+            break;
+        }
+        remove(&path)?;
+        pruned.kept -= size;
+        pruned.freed += size;
+        pruned.removed.push(path);
+    }
+    for path in &pruned.removed {
+        tracing::debug!("Pruned {} from the cache", path.display());
+    }
+    Ok(pruned)
+}
+
+/// [`prune`] with the configured cache directory and budget
+pub fn prune_configured() -> io::Result<Pruned> {
+    prune(
+        &crate::overrides::cache_dir(),
+        crate::overrides::cache_max_size(),
+    )
+}
+
+/// Whether `path` is named like [`root`] for some layout version
+fn is_layout_dir(path: &Path) -> bool {
+    path.is_dir()
+        && path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .and_then(|x| x.strip_prefix('v'))
+            .is_some_and(|x| x.parse::<u32>().is_ok())
+}
+
+/// Sorted entries of `dir`, none if it does not exist
+fn read_dir(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    match fs::read_dir(dir) {
+        Ok(entries) => {
+            let mut entries = entries
+                .map(|x| x.map(|x| x.path()))
+                .collect::<io::Result<Vec<_>>>()?;
+            entries.sort();
+            Ok(entries)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Total size of `path` and when anything in it was last used, by access time
+/// where the file system keeps it and modification time otherwise
+fn usage(path: &Path) -> io::Result<(u64, SystemTime)> {
+    let metadata = fs::symlink_metadata(path)?;
+    let mut used = metadata
+        .accessed()
+        .or_else(|_| metadata.modified())
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    if !metadata.is_dir() {
+        return Ok((metadata.len(), used));
+    }
+    let mut size = 0;
+    for entry in read_dir(path)? {
+        let (entry_size, entry_used) = usage(&entry)?;
+        size += entry_size;
+        used = used.max(entry_used);
+    }
+    Ok((size, used))
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// A cache key for CI such as `msvc-env-v1-vswhere3.1.7-x64-4f2a9c1e0b7d3a65`:
+/// the layout and vswhere versions, the target architecture and a hash of the
+/// whole selection. The hash is computed the same way on every platform and
+/// by every build of the crate with the same layout version.
+pub fn key(selection: &Selection) -> String {
+    format!(
+        "msvc-env-v{}-vswhere{}-{}-{:016x}",
+        LAYOUT_VERSION,
+        VSWHERE_VERSION,
+        selection.arch.as_str(),
+        selection_hash(selection)
+    )
+}
+
+/// Hash of every field of `selection`, stable like [`key`]
+pub(crate) fn selection_hash(selection: &Selection) -> u64 {
+    fnv1a(selection_text(selection).as_bytes())
+}
+
+/// Every field of `selection` in a fixed textual form, so the key does not
+/// depend on `Debug` formatting or the standard library's hasher
+fn selection_text(selection: &Selection) -> String {
+    let optional = |x: Option<String>| x.unwrap_or_default();
+    format!(
+        "arch={};host_arch={};toolset={};sdk={};min_sdk={};vs_version={};channel_id={};product_id={};installation_name={};instance_id={};installation_path={};prerelease={};prerelease_order={};legacy={};required_components={};requires_any={};start_dir={};host_fallback={};base_env={}",
+        selection.arch.as_str(),
+        selection.host_arch.as_str(),
+        optional(selection.toolset.as_ref().map(|x| x.to_string())),
+        optional(selection.sdk.as_ref().map(|x| x.to_string())),
+        optional(selection.min_sdk.as_ref().map(|x| x.to_string())),
+        optional(selection.vs_version.as_ref().map(|x| x.to_string())),
+        optional(selection.channel_id.clone()),
+        optional(selection.product_id.clone()),
+        optional(selection.installation_name.clone()),
+        optional(selection.instance_id.clone()),
+        optional(
+            selection
+                .installation_path
+                .as_ref()
+                .map(|x| x.display().to_string()),
+        ),
+        selection.prerelease,
+        optional(selection.prerelease_order.map(|x| x.to_string())),
+        selection.legacy,
+        selection.required_components.join(","),
+        selection.requires_any,
+        selection.start_dir,
+        selection.host_fallback,
+        optional(selection.base_env.as_ref().map(|vars| {
+            vars.iter()
+                .map(|(key, value)| format!("{}={}\n", key, value))
+                .collect()
+        })),
+    )
+}
+
+/// 64-bit FNV-1a, which unlike `DefaultHasher` is guaranteed never to change
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MsvcArch;
+
+    #[test]
+    fn test_layout() {
+        let dir = Path::new("cache");
+        assert_eq!(
+            vswhere_path(dir),
+            Path::new("cache/v1/vswhere")
+                .join(VSWHERE_VERSION)
+                .join("vswhere.exe")
+        );
+        assert_eq!(env_dir(dir), Path::new("cache/v1/env"));
+        assert!(
+            env_path(dir, &Selection::new(MsvcArch::Arm64))
+                .to_string_lossy()
+                .ends_with(".env")
+        );
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1024").unwrap(), 1024);
+        assert_eq!(parse_size("512K").unwrap(), 512 << 10);
+        assert_eq!(parse_size("500MiB").unwrap(), 500 << 20);
+        assert_eq!(parse_size("2gb").unwrap(), 2 << 30);
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn test_prune() {
+        use std::time::Duration;
+
+        let dir = std::env::temp_dir().join(format!("msvc-env-prune-{}", std::process::id()));
+        let write = |path: &Path, size: usize, age: u64| {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, vec![0u8; size]).unwrap();
+            let time = SystemTime::now() - Duration::from_secs(age);
+            let times = fs::FileTimes::new().set_accessed(time).set_modified(time);
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_times(times)
+                .unwrap();
+        };
+        write(&vswhere_path(&dir), 100, 1000);
+        write(
+            &root(&dir).join("vswhere").join("2.0.0").join("vswhere.exe"),
+            100,
+            0,
+        );
+        write(&dir.join("v0").join("env").join("old.env"), 100, 0);
+        write(&env_dir(&dir).join("old.env"), 100, 500);
+        write(&env_dir(&dir).join("new.env"), 100, 10);
+
+        let pruned = prune(&dir, 250).unwrap();
+        assert_eq!(
+            pruned.removed,
+            [
+                dir.join("v0"),
+                root(&dir).join("vswhere").join("2.0.0"),
+                env_dir(&dir).join("old.env")
+            ]
+        );
+        assert_eq!(pruned.kept, 200);
+        assert!(vswhere_path(&dir).exists());
+        assert!(env_dir(&dir).join("new.env").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_key() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+
+        let x64 = key(&Selection::new(MsvcArch::X64));
+        assert!(x64.starts_with(&format!("msvc-env-v1-vswhere{}-x64-", VSWHERE_VERSION)));
+        assert_eq!(x64, key(&Selection::new(MsvcArch::X64)));
+        assert_ne!(
+            x64,
+            key(&Selection::new(MsvcArch::X64).toolset("14.29".parse().unwrap()))
+        );
+        assert_ne!(
+            x64,
+            key(&Selection::new(MsvcArch::X64).base_env([("SystemRoot", r"C:\Windows")]))
+        );
+        assert!(
+            key(&Selection::new(MsvcArch::X64))
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        );
+    }
+}
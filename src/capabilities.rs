@@ -0,0 +1,179 @@
+//! What the compiler of an environment supports, read from the help text of
+//! `cl.exe`, so build systems can pick flags without compiling test programs
+
+use crate::{MsvcEnv, MsvcEnvError, MsvcEnvironment, Selection, Stage, capture};
+use std::process::Command;
+
+/// Flags `cl.exe` of a toolset accepts
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ToolCapabilities {
+    /// `VCToolsVersion` of the probed environment, e.g. `14.38.33130`
+    pub toolset: String,
+    /// Values of `/std:` for C++, e.g. `c++14`, `c++17`, `c++20` and `c++latest`
+    pub cpp_standards: Vec<String>,
+    /// Values of `/std:` for C, e.g. `c11` and `c17`; empty before VS 2019 16.8
+    pub c_standards: Vec<String>,
+    /// Whether `/fsanitize=address` is available
+    pub asan: bool,
+}
+
+impl ToolCapabilities {
+    /// Whether `/std:<std>` is accepted, e.g. `c++20`
+    pub fn supports_std(&self, std: &str) -> bool {
+        self.cpp_standards
+            .iter()
+            .chain(&self.c_standards)
+            .any(|x| x.eq_ignore_ascii_case(std))
+    }
+
+    /// Whether C11 can be requested with `/std:c11`
+    pub fn c11(&self) -> bool {
+        self.supports_std("c11")
+    }
+
+    /// Whether C17 can be requested with `/std:c17`
+    pub fn c17(&self) -> bool {
+        self.supports_std("c17")
+    }
+}
+
+impl MsvcEnv {
+    /// Resolves the environment for `selection` and probes its compiler, see
+    /// [`ToolCapabilities`]. The result is cached along with the environment,
+    /// so it is probed again after [`clear_cache`](Self::clear_cache) or when
+    /// the installation changed.
+    pub fn tool_capabilities(
+        &self,
+        selection: &Selection,
+    ) -> Result<ToolCapabilities, MsvcEnvError> {
+        let selection = &self.pins.apply(selection);
+        let env = self.resolve(selection)?;
+        let toolset = env.get("VCToolsVersion").ok_or_else(|| {
+            MsvcEnvError::ParseError("the environment has no VCToolsVersion".to_string())
+        })?;
+        let slot = self.cache.slot(selection);
+        let mut cached = slot
+            .lock()
+            .map_err(|_| std::io::Error::other("Mutex poisoned"))?;
+        if let Some(found) = cached.as_ref().and_then(|x| x.capabilities.as_ref()) {
+            return Ok(found.clone());
+        }
+
+        let found = probe(&env, toolset)?;
+        if let Some(cached) = cached.as_mut() {
+            cached.capabilities = Some(found.clone());
+        }
+        Ok(found)
+    }
+}
+
+/// Runs `cl /?` in `env`
+fn probe(env: &MsvcEnvironment, toolset: &str) -> Result<ToolCapabilities, MsvcEnvError> {
+    let mut command = Command::new("cl");
+    crate::native::hide_window(&mut command);
+    command.arg("/?").envs(&env.vars);
+    capture::log_command(&command);
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(capture::process_failed(Stage::Parse, &command, &output));
+    }
+    let found = parse_help(toolset, &String::from_utf8_lossy(&output.stdout));
+    tracing::trace!("Capabilities of toolset {}: {:?}", toolset, found);
+    Ok(found)
+}
+
+/// Reads the `/std:<...|...>` lists and `/fsanitize=address` from the output of
+/// `cl /?`. Some versions list the C and C++ standards together.
+fn parse_help(toolset: &str, help: &str) -> ToolCapabilities {
+    let mut found = ToolCapabilities {
+        toolset: toolset.to_string(),
+        ..Default::default()
+    };
+    for line in help.lines().map(str::trim) {
+        if line.starts_with("/fsanitize=address") {
+            found.asan = true;
+        }
+        let Some(list) = line
+            .strip_prefix("/std:<")
+            .and_then(|x| x.split_once('>'))
+            .map(|(list, _)| list)
+        else {
+            continue;
+        };
+        for std in list.split('|').map(str::trim) {
+            let standards = if std.starts_with("c++") {
+                &mut found.cpp_standards
+            } else {
+                &mut found.c_standards
+            };
+            if !standards.iter().any(|x| x == std) {
+                standards.push(std.to_string());
+            }
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MockProvider, MsvcArch};
+
+    #[test]
+    fn test_parse_help() {
+        let help = "\
+                         C/C++ COMPILER OPTIONS
+
+                              -LANGUAGE-
+
+/std:<c++14|c++17|c++20|c++latest> C++ standard version
+    c++14 - ISO/IEC 14882:2014 (default)
+/std:<c11|c17|clatest> C standard version
+/permissive[-] enable some nonconforming code to compile (feature set subject to change) (on by default)
+/fsanitize=address Enable address sanitizer codegen
+";
+        let found = parse_help("14.38.33130", help);
+        assert_eq!(
+            found.cpp_standards,
+            ["c++14", "c++17", "c++20", "c++latest"]
+        );
+        assert_eq!(found.c_standards, ["c11", "c17", "clatest"]);
+        assert!(found.asan);
+        assert!(found.c11() && found.c17());
+        assert!(found.supports_std("C++20"));
+
+        // Both kinds in one option, from a compiler without ASAN
+        let found = parse_help(
+            "14.28.29333",
+            "/std:<c++14|c++17|c++latest|c11|c17> standard",
+        );
+        assert_eq!(found.cpp_standards, ["c++14", "c++17", "c++latest"]);
+        assert!(found.c17());
+        assert!(!found.asan);
+
+        assert!(!parse_help("14.16.27023", "/std:<c++14|c++17|c++latest>").c11());
+    }
+
+    #[test]
+    fn test_tool_capabilities_cached() {
+        let env = MockProvider::fake_environment(MsvcArch::Arm64);
+        let cached = ToolCapabilities {
+            toolset: env.get("VCToolsVersion").unwrap().to_string(),
+            cpp_standards: vec!["c++17".to_string()],
+            ..Default::default()
+        };
+        let msvc_env = MsvcEnv::builder()
+            .provider(MockProvider::empty().with_environment(MsvcArch::Arm64, env))
+            .build();
+        let selection = Selection::new(MsvcArch::Arm64);
+        msvc_env.resolve(&selection).unwrap();
+        let slot = msvc_env.cache.slot(&selection);
+        slot.lock().unwrap().as_mut().unwrap().capabilities = Some(cached.clone());
+        assert_eq!(msvc_env.tool_capabilities(&selection).unwrap(), cached);
+
+        // Forgotten along with the environment
+        msvc_env.clear_cache();
+        assert!(msvc_env.cache.slot(&selection).lock().unwrap().is_none());
+    }
+}
@@ -0,0 +1,514 @@
+//! Runs environment setup scripts under cmd and reads back the resulting variables
+
+use crate::job::Job;
+use crate::{MsvcEnvError, Stage};
+use indexmap::IndexMap;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::io::{BufRead as _, BufReader, Read, Write as _};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+
+/// Stops VsDevCmd from starting vctip.exe, which would otherwise keep running in
+/// the background after the capture
+const SKIP_TELEMETRY: (&str, &str) = ("VSCMD_SKIP_SENDTELEMETRY", "1");
+
+/// Inherited variables that change what vswhere and the setup scripts do, logged
+/// with every command so differences to a working prompt show up
+const REPRO_VARS: &[&str] = &[
+    "VSINSTALLDIR",
+    "VCINSTALLDIR",
+    "VSCMD_VER",
+    "VSCMD_ARG_TGT_ARCH",
+    "VSCMD_ARG_HOST_ARCH",
+    "VSCMD_SKIP_SENDTELEMETRY",
+    "VSCMD_START_DIR",
+    "Platform",
+    "PreferredToolArchitecture",
+    "WindowsSdkDir",
+    "ComSpec",
+];
+
+/// Echoed after `set`, so the end of the variables is known while the shell and
+/// anything it started are still running
+const END_MARKER: &str = "__MSVC_ENV_END__";
+
+/// Runs `script` with `args` inside `cmd /k`, then asks that same shell for `set`
+/// so every variable the script exported is captured. The shell starts from
+/// `base_env` if given, or else inherits this process's environment, and `vars`
+/// are set on top. Once the variables are read, the shell is terminated together
+/// with every process the script left running; with the `windows-sys` feature
+/// the shell runs in a [`Job`], so that also happens on early returns.
+pub(crate) fn run_script<I, S>(
+    script: &Path,
+    args: I,
+    base_env: Option<&BTreeMap<String, String>>,
+    vars: &[(&str, &OsStr)],
+) -> Result<IndexMap<String, String>, MsvcEnvError>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let span = tracing::debug_span!(
+        "capture",
+        script = %script.display(),
+        vars = tracing::field::Empty
+    )
+    .entered();
+    let mut command = Command::new("cmd");
+    crate::native::hide_window(&mut command);
+    command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .arg("/k")
+        .arg(script)
+        .args(args);
+    set_base_env(&mut command, base_env);
+    set_safe_current_dir(&mut command, base_env);
+    command.env(SKIP_TELEMETRY.0, SKIP_TELEMETRY.1);
+    command.envs(vars.iter().copied());
+    log_command(&command);
+    let mut child = command
+        .spawn()
+        .map_err(|e| MsvcEnvError::VcvarsError(e.to_string()))?;
+    let _job = Job::assign(&child);
+
+    let mut stderr = child.stderr.take().unwrap();
+    let stderr = std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = stderr.read_to_end(&mut buf);
+        buf
+    });
+
+    let mut stdin = child.stdin.take().unwrap();
+    write!(stdin, "set\necho {}\n", END_MARKER)?;
+    stdin.flush()?;
+
+    let (stdout, finished) = read_until_marker(child.stdout.take().unwrap())?;
+    if finished {
+        terminate_tree(&mut child);
+    }
+    drop(stdin);
+    let status = child
+        .wait()
+        .map_err(|e| MsvcEnvError::VcvarsError(e.to_string()))?;
+    let output = Output {
+        status,
+        stdout,
+        stderr: stderr.join().unwrap_or_default(),
+    };
+
+    // A script that fails hard makes the shell exit before the marker. The
+    // errors scripts print otherwise, e.g. from VsDevCmd extensions, leave the
+    // environment set up, so they are only passed on.
+    if !finished {
+        return Err(process_failed(Stage::Capture, &command, &output));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    for line in stdout.lines().filter(|line| line.starts_with("[ERROR:")) {
+        tracing::warn!("{}", line.trim_end());
+    }
+
+    let vars = parse_set_output(&stdout);
+    span.record("vars", vars.len());
+    Ok(vars)
+}
+
+/// Reads the shell's output up to [`END_MARKER`], leaving out the marker and the
+/// command that printed it. `false` if the output ended before the marker.
+fn read_until_marker(stdout: impl Read) -> Result<(Vec<u8>, bool), MsvcEnvError> {
+    let echo = format!(">echo {}", END_MARKER);
+    let mut output = Vec::new();
+    for line in BufReader::new(stdout).split(b'\n') {
+        let line = line?;
+        let text = String::from_utf8_lossy(&line);
+        let text = text.trim_end();
+        if text == END_MARKER {
+            return Ok((output, true));
+        }
+        if !text.ends_with(&echo) {
+            output.extend_from_slice(&line);
+            output.push(b'\n');
+        }
+    }
+    Ok((output, false))
+}
+
+/// Kills the capture shell and all of its descendants, such as tools a setup
+/// script started in the background
+fn terminate_tree(child: &mut Child) {
+    #[cfg(windows)]
+    {
+        let mut taskkill = Command::new("taskkill");
+        crate::native::hide_window(&mut taskkill);
+        let status = taskkill
+            .args(["/F", "/T", "/PID", &child.id().to_string()])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        if status.is_ok_and(|x| x.success()) {
+            return;
+        }
+        tracing::trace!("taskkill failed for capture shell {}", child.id());
+    }
+    let _ = child.kill();
+}
+
+/// Printed by PowerShell capture scripts right before the environment dump, so
+/// anything the setup module writes first can be told apart from variables
+pub(crate) const ENV_MARKER: &str = "__MSVC_ENV_BEGIN__";
+
+/// Runs a PowerShell `script` that prints [`ENV_MARKER`] followed by `NAME=value`
+/// lines and returns the variables, starting from `base_env` like [`run_script`]
+pub(crate) fn run_powershell(
+    executable: &Path,
+    script: &str,
+    base_env: Option<&BTreeMap<String, String>>,
+) -> Result<IndexMap<String, String>, MsvcEnvError> {
+    let span = tracing::debug_span!(
+        "capture",
+        powershell = %executable.display(),
+        vars = tracing::field::Empty
+    )
+    .entered();
+    let mut command = Command::new(executable);
+    crate::native::hide_window(&mut command);
+    command.args([
+        "-NoLogo",
+        "-NoProfile",
+        "-NonInteractive",
+        "-ExecutionPolicy",
+        "Bypass",
+        "-Command",
+    ]);
+    set_base_env(&mut command, base_env);
+    set_safe_current_dir(&mut command, base_env);
+    command.env(SKIP_TELEMETRY.0, SKIP_TELEMETRY.1);
+    command.arg(script);
+    log_command(&command);
+    let child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| MsvcEnvError::VcvarsError(e.to_string()))?;
+    let _job = Job::assign(&child);
+    let output = child
+        .wait_with_output()
+        .map_err(|e| MsvcEnvError::VcvarsError(e.to_string()))?;
+
+    if !output.status.success() {
+        return Err(process_failed(Stage::Capture, &command, &output));
+    }
+
+    let vars = parse_marked_output(&String::from_utf8_lossy(&output.stdout))?;
+    span.record("vars", vars.len());
+    Ok(vars)
+}
+
+/// Replaces the inherited environment of `command` with `base_env`
+fn set_base_env(command: &mut Command, base_env: Option<&BTreeMap<String, String>>) {
+    if let Some(base_env) = base_env {
+        command.env_clear().envs(base_env);
+    }
+}
+
+/// Runs `command` in `%SystemRoot%` if this process's working directory is one
+/// cmd cannot use. cmd prints a warning for UNC paths and continues in the
+/// Windows directory, and fails to start in a directory that was removed.
+pub(crate) fn set_safe_current_dir(
+    command: &mut Command,
+    base_env: Option<&BTreeMap<String, String>>,
+) {
+    let current_dir = std::env::current_dir().ok();
+    if let Some(dir) = safe_current_dir(current_dir.as_deref(), base_env) {
+        tracing::trace!(
+            "Running {} in {} instead of {:?}",
+            command.get_program().display(),
+            dir.display(),
+            current_dir
+        );
+        command.current_dir(dir);
+    }
+}
+
+/// The directory to run cmd in instead of `current_dir`, or `None` if it can
+/// stay there
+fn safe_current_dir(
+    current_dir: Option<&Path>,
+    base_env: Option<&BTreeMap<String, String>>,
+) -> Option<PathBuf> {
+    let usable = current_dir.is_some_and(|dir| {
+        let text = dir.to_string_lossy();
+        // Also covers the `\\?\` prefix of verbatim paths, which cmd rejects too
+        !text.starts_with(r"\\") && !text.starts_with("//") && dir.is_dir()
+    });
+    if usable {
+        return None;
+    }
+    let system_root = match base_env {
+        Some(vars) => vars
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case("SystemRoot"))
+            .map(|(_, value)| value.clone()),
+        None => std::env::var("SystemRoot").ok(),
+    };
+    Some(PathBuf::from(
+        system_root.unwrap_or_else(|| r"C:\Windows".to_string()),
+    ))
+}
+
+/// Logs `command` at debug level: the program, its arguments, the variables set
+/// for it and the [`REPRO_VARS`] it inherits, followed by a line that runs the
+/// same in cmd. Only the values of [`REPRO_VARS`] are logged, since the others
+/// can be tokens or passwords, e.g. from a `base_env` copied from CI.
+pub(crate) fn log_command(command: &Command) {
+    if !tracing::enabled!(tracing::Level::DEBUG) {
+        return;
+    }
+    let inherited = REPRO_VARS
+        .iter()
+        .filter(|key| !command.get_envs().any(|(k, _)| k.eq_ignore_ascii_case(key)))
+        .filter_map(|key| Some(format!("{}={}", key, std::env::var(key).ok()?)))
+        .collect::<Vec<_>>();
+    tracing::debug!(
+        program = %command.get_program().display(),
+        args = ?command.get_args().collect::<Vec<_>>(),
+        env = ?logged_env(command),
+        inherited = ?inherited,
+        "Running {}",
+        repro_line(command)
+    );
+}
+
+/// Whether the value of `key` may be logged
+fn is_repro_var(key: &OsStr) -> bool {
+    REPRO_VARS.iter().any(|x| key.eq_ignore_ascii_case(x))
+}
+
+/// The variables set or removed for `command`, with the values of all but
+/// [`REPRO_VARS`] redacted
+fn logged_env(command: &Command) -> Vec<String> {
+    command
+        .get_envs()
+        .map(|(key, value)| match value {
+            Some(value) if is_repro_var(key) => {
+                format!("{}={}", key.to_string_lossy(), value.to_string_lossy())
+            }
+            Some(_) => format!("{}=<redacted>", key.to_string_lossy()),
+            None => format!("{} removed", key.to_string_lossy()),
+        })
+        .collect()
+}
+
+/// A cmd command line that sets the [`REPRO_VARS`] of `command`, clears what it
+/// removes and runs it
+fn repro_line(command: &Command) -> String {
+    let quote = |x: &OsStr| {
+        let x = x.to_string_lossy();
+        if x.is_empty() || x.contains([' ', '&', '(', ')', '^']) {
+            format!("\"{}\"", x)
+        } else {
+            x.into_owned()
+        }
+    };
+    let mut line = String::new();
+    for (key, value) in command.get_envs() {
+        match value {
+            Some(value) if is_repro_var(key) => {
+                line += &format!(
+                    "set \"{}={}\" && ",
+                    key.to_string_lossy(),
+                    value.to_string_lossy()
+                )
+            }
+            Some(_) => {}
+            None => line += &format!("set \"{}=\" && ", key.to_string_lossy()),
+        }
+    }
+    line += &quote(command.get_program());
+    for arg in command.get_args() {
+        line.push(' ');
+        line += &quote(arg);
+    }
+    line
+}
+
+/// Error for a child process that ran but failed, keeping everything it printed
+pub(crate) fn process_failed(stage: Stage, command: &Command, output: &Output) -> MsvcEnvError {
+    MsvcEnvError::ProcessFailed {
+        stage,
+        command: repro_line(command),
+        exit_code: output.status.code(),
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+    }
+}
+
+/// Parses `NAME=value` lines following [`ENV_MARKER`]
+pub(crate) fn parse_marked_output(output: &str) -> Result<IndexMap<String, String>, MsvcEnvError> {
+    let (_, vars) = output
+        .split_once(ENV_MARKER)
+        .ok_or_else(|| MsvcEnvError::ParseError("environment marker not found".to_string()))?;
+
+    Ok(vars
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect())
+}
+
+/// Parses the output of `set`, skipping the script's banner and the prompt line
+/// cmd echoes before running the command
+pub(crate) fn parse_set_output(output: &str) -> IndexMap<String, String> {
+    let lines = output.trim().lines().collect::<Vec<_>>();
+    let start = lines
+        .iter()
+        .position(|line| line.trim_end().ends_with(">set"))
+        .map_or(0, |i| i + 1);
+
+    lines[start..]
+        .iter()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_set_output() {
+        let output = "\
+**********************************************************************
+** Visual Studio 2022 Developer Command Prompt v17.10.3
+** Copyright (c) 2022 Microsoft Corporation
+**********************************************************************
+
+C:\\work>set
+INCLUDE=C:\\VC\\include;C:\\Kits\\include
+Path=C:\\VC\\bin;C:\\Windows
+VSCMD_ARG_TGT_ARCH=x64
+
+C:\\work>";
+
+        let vars = parse_set_output(output);
+        assert_eq!(
+            vars.keys().collect::<Vec<_>>(),
+            ["INCLUDE", "Path", "VSCMD_ARG_TGT_ARCH"]
+        );
+        assert_eq!(vars["Path"], "C:\\VC\\bin;C:\\Windows");
+    }
+
+    #[test]
+    fn test_read_until_marker() {
+        let output = "\
+C:\\work>set\r
+Path=C:\\VC\\bin\r
+\r
+C:\\work>echo __MSVC_ENV_END__\r
+__MSVC_ENV_END__\r
+C:\\work>";
+        let (stdout, finished) = read_until_marker(output.as_bytes()).unwrap();
+        assert!(finished);
+        assert_eq!(
+            String::from_utf8(stdout).unwrap(),
+            "C:\\work>set\r\nPath=C:\\VC\\bin\r\n\r\n"
+        );
+
+        let (_, finished) = read_until_marker("[ERROR: vcvars]\r\n".as_bytes()).unwrap();
+        assert!(!finished);
+    }
+
+    #[test]
+    fn test_repro_line() {
+        let mut command = Command::new("cmd");
+        command
+            .arg("/k")
+            .arg(r"C:\Program Files\Microsoft Visual Studio\2022\BuildTools\Common7\Tools\VsDevCmd.bat")
+            .arg("-arch=arm64")
+            .env("VSCMD_START_DIR", r"C:\src");
+        assert_eq!(
+            repro_line(&command),
+            r#"set "VSCMD_START_DIR=C:\src" && cmd /k "C:\Program Files\Microsoft Visual Studio\2022\BuildTools\Common7\Tools\VsDevCmd.bat" -arch=arm64"#
+        );
+    }
+
+    #[test]
+    fn test_logged_env_redacts_secrets() {
+        let mut command = Command::new("vswhere.exe");
+        command
+            .env("GITHUB_TOKEN", "ghp_s3cr3t")
+            .env("Platform", "x64")
+            .env_remove("VSINSTALLDIR");
+        let env = logged_env(&command);
+        assert!(
+            env.contains(&"GITHUB_TOKEN=<redacted>".to_string()),
+            "{:?}",
+            env
+        );
+        assert!(env.contains(&"Platform=x64".to_string()), "{:?}", env);
+        assert!(
+            env.contains(&"VSINSTALLDIR removed".to_string()),
+            "{:?}",
+            env
+        );
+
+        let line = repro_line(&command);
+        assert!(!line.contains("ghp_s3cr3t"), "{}", line);
+        assert!(line.contains(r#"set "Platform=x64" && "#), "{}", line);
+        let output = Output {
+            status: Default::default(),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        };
+        let error = process_failed(Stage::Discovery, &command, &output);
+        assert!(!format!("{:?}", error).contains("ghp_s3cr3t"));
+    }
+
+    #[test]
+    fn test_safe_current_dir() {
+        let base_env = BTreeMap::from([("SYSTEMROOT".to_string(), r"D:\Windows".to_string())]);
+        let base_env = Some(&base_env);
+        let temp = std::env::temp_dir();
+        assert_eq!(safe_current_dir(Some(&temp), base_env), None);
+
+        for dir in [
+            r"\\server\share\src",
+            r"\\?\UNC\server\share\src",
+            "//server/share/src",
+        ] {
+            assert_eq!(
+                safe_current_dir(Some(Path::new(dir)), base_env),
+                Some(PathBuf::from(r"D:\Windows")),
+                "{}",
+                dir
+            );
+        }
+        let removed = temp.join(format!("msvc-env-removed-{}", std::process::id()));
+        assert!(safe_current_dir(Some(&removed), base_env).is_some());
+        assert!(safe_current_dir(None, base_env).is_some());
+    }
+
+    #[test]
+    fn test_parse_marked_output() {
+        let output = "\
+**********************************************************************
+** Visual Studio 2022 Developer PowerShell v17.10.3
+**********************************************************************
+__MSVC_ENV_BEGIN__
+INCLUDE=C:\\VC\\include
+ProgramFiles(x86)=C:\\Program Files (x86)
+=C:=C:\\work
+";
+        let vars = parse_marked_output(output).unwrap();
+        assert_eq!(vars.len(), 3);
+        assert_eq!(vars["INCLUDE"], "C:\\VC\\include");
+        assert_eq!(vars["ProgramFiles(x86)"], "C:\\Program Files (x86)");
+
+        assert!(parse_marked_output("Enter-VsDevShell : failed").is_err());
+    }
+}
@@ -0,0 +1,128 @@
+//! The CMake and Ninja that the C++ CMake tools component installs into Visual
+//! Studio, for machines without standalone copies
+
+use crate::{MsvcArch, MsvcEnv, MsvcEnvError, MsvcEnvironment};
+use std::path::{Path, PathBuf};
+
+/// The component that installs them
+const CMAKE_COMPONENT: &str = "Microsoft.VisualStudio.Component.VC.CMake.Project";
+
+/// `Common7\IDE\CommonExtensions\Microsoft\CMake` of the installation at `vs_path`
+fn cmake_root(vs_path: &Path) -> PathBuf {
+    vs_path
+        .join("Common7")
+        .join("IDE")
+        .join("CommonExtensions")
+        .join("Microsoft")
+        .join("CMake")
+}
+
+fn cmake_exe(vs_path: &Path) -> PathBuf {
+    cmake_root(vs_path)
+        .join("CMake")
+        .join("bin")
+        .join("cmake.exe")
+}
+
+fn ninja_exe(vs_path: &Path) -> PathBuf {
+    cmake_root(vs_path).join("Ninja").join("ninja.exe")
+}
+
+/// `path` if it exists, else the error naming the component to add
+fn bundled(vs_path: &Path, path: PathBuf, what: &str) -> Result<PathBuf, MsvcEnvError> {
+    if path.exists() {
+        return Ok(path);
+    }
+    Err(MsvcEnvError::MissingComponent {
+        arch: MsvcArch::All,
+        installation: vs_path.to_path_buf(),
+        what: format!("the bundled {}", what),
+        components: vec![CMAKE_COMPONENT],
+    })
+}
+
+impl MsvcEnv {
+    /// `cmake.exe` shipped with the installation
+    pub fn cmake_path(&self) -> Result<PathBuf, MsvcEnvError> {
+        let vs_path = self.find_visual_studio()?;
+        bundled(&vs_path, cmake_exe(&vs_path), "CMake")
+    }
+
+    /// `ninja.exe` shipped with the installation
+    pub fn ninja_path(&self) -> Result<PathBuf, MsvcEnvError> {
+        let vs_path = self.find_visual_studio()?;
+        bundled(&vs_path, ninja_exe(&vs_path), "Ninja")
+    }
+}
+
+impl MsvcEnvironment {
+    /// Puts the directories of the CMake and Ninja shipped with the installation
+    /// in front of PATH, unless PATH has them already. Does nothing for the ones
+    /// that are not installed or if the installation is not known.
+    pub fn prepend_bundled_cmake(&mut self) {
+        let Some(installation) = self.installation() else {
+            return;
+        };
+        let dirs = [cmake_exe(&installation.path), ninja_exe(&installation.path)]
+            .into_iter()
+            .filter(|exe| exe.exists())
+            .filter_map(|exe| Some(exe.parent()?.display().to_string()))
+            .collect::<Vec<_>>();
+        let path = self.get("PATH").unwrap_or_default();
+        let mut entries = dirs
+            .into_iter()
+            .filter(|dir| !path.split(';').any(|x| x.eq_ignore_ascii_case(dir)))
+            .collect::<Vec<_>>();
+        if entries.is_empty() {
+            return;
+        }
+        entries.extend(path.split(';').filter(|x| !x.is_empty()).map(String::from));
+        let key = self
+            .vars
+            .keys()
+            .find(|k| k.eq_ignore_ascii_case("PATH"))
+            .cloned()
+            .unwrap_or_else(|| "PATH".to_string());
+        self.vars.insert(key, entries.join(";"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VsInstallation;
+    use indexmap::IndexMap;
+    use std::fs;
+
+    #[test]
+    fn test_prepend_bundled_cmake() {
+        let vs = std::env::temp_dir().join(format!("msvc-env-cmake-{}", std::process::id()));
+        let error = bundled(&vs, cmake_exe(&vs), "CMake").unwrap_err();
+        assert!(error.to_string().contains(CMAKE_COMPONENT), "{}", error);
+
+        let mut env = MsvcEnvironment::new(IndexMap::from([(
+            "Path".to_string(),
+            r"C:\Windows".to_string(),
+        )]));
+        env.meta.installation = Some(VsInstallation {
+            path: vs.clone(),
+            ..Default::default()
+        });
+        env.prepend_bundled_cmake();
+        assert_eq!(env.get("PATH"), Some(r"C:\Windows"));
+
+        for exe in [cmake_exe(&vs), ninja_exe(&vs)] {
+            fs::create_dir_all(exe.parent().unwrap()).unwrap();
+            fs::write(exe, "").unwrap();
+        }
+        env.prepend_bundled_cmake();
+        env.prepend_bundled_cmake();
+        let path = env.get("PATH").unwrap().split(';').collect::<Vec<_>>();
+        assert_eq!(path.len(), 3);
+        assert_eq!(Path::new(path[0]), cmake_exe(&vs).parent().unwrap());
+        assert_eq!(path[2], r"C:\Windows");
+        assert_eq!(env.vars.len(), 1);
+
+        fs::remove_dir_all(&vs).unwrap();
+    }
+}
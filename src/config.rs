@@ -0,0 +1,488 @@
+//! Project configuration read from `.msvc-env.toml`, so a team and its CI resolve
+//! the same toolchain the way `rust-toolchain.toml` pins a Rust toolchain, on top
+//! of a per-user configuration holding personal defaults. Rust projects can keep
+//! the same keys in `[package.metadata.msvc-env]` of their `Cargo.toml` instead.
+
+pub use crate::format::OutputFormat;
+use crate::{MsvcArch, MsvcEnvError, Selection, overrides};
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the project configuration file
+pub const PROJECT_CONFIG_FILE: &str = ".msvc-env.toml";
+
+/// Directory under the user's configuration directory holding [`USER_CONFIG_FILE`]
+const USER_CONFIG_DIR: &str = "msvc-env";
+
+/// Name of the user configuration file
+pub const USER_CONFIG_FILE: &str = "config.toml";
+
+/// Name of the Cargo manifest holding `[package.metadata.msvc-env]`
+pub const CARGO_MANIFEST_FILE: &str = "Cargo.toml";
+
+/// The parts of a Cargo manifest leading to `[package.metadata.msvc-env]`
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct CargoManifest {
+    package: Option<CargoPackage>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct CargoPackage {
+    metadata: Option<CargoMetadata>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct CargoMetadata {
+    #[serde(rename = "msvc-env")]
+    msvc_env: Option<Config>,
+}
+
+/// Toolchain pins from a configuration file. Every field is optional; unset
+/// fields keep the library defaults.
+///
+/// ```toml
+/// arch = "x64"
+/// host-arch = "x64"
+/// host-fallback = true
+/// toolset = "14.29"
+/// sdk = "10.0.22621.0"
+/// min-sdk = "10.0.19041.0"
+/// vs-version = "[17.0,18.0)"
+/// installation-name = "VS2022 ASAN"
+/// prerelease = true
+/// required-components = ["Microsoft.VisualStudio.Component.VC.ATL"]
+/// prerelease-order = "stable-first"
+/// start-dir = "none"
+/// format = "sh"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub arch: Option<MsvcArch>,
+    pub host_arch: Option<MsvcArch>,
+    /// MSVC toolset version or prefix
+    pub toolset: Option<String>,
+    /// Windows SDK version
+    pub sdk: Option<String>,
+    /// Oldest acceptable Windows SDK version
+    pub min_sdk: Option<String>,
+    /// vswhere version range of acceptable installations
+    pub vs_version: Option<String>,
+    pub channel_id: Option<String>,
+    pub product_id: Option<String>,
+    /// Display name or nickname of the installation to use
+    pub installation_name: Option<String>,
+    /// Also consider preview installations
+    pub prerelease: Option<bool>,
+    /// `stable-first` or `preview-first`, see
+    /// [`PrereleaseOrder`](crate::PrereleaseOrder)
+    pub prerelease_order: Option<String>,
+    /// Also consider installations from before Visual Studio 2017
+    pub legacy: Option<bool>,
+    /// Component or workload IDs acceptable installations must have
+    pub required_components: Option<Vec<String>>,
+    /// Accept installations with any of `required-components`
+    pub requires_any: Option<bool>,
+    /// `none`, `auto` or a directory, see [`StartDir`](crate::StartDir)
+    pub start_dir: Option<String>,
+    /// Fall back to x64-hosted compilers when `host-arch` has none
+    pub host_fallback: Option<bool>,
+    /// Where vswhere is downloaded to; only read from the user configuration
+    pub cache_dir: Option<PathBuf>,
+    /// Size budget of the cache directory, e.g. `500M`; only read from the
+    /// user configuration
+    pub cache_max_size: Option<String>,
+    pub format: Option<OutputFormat>,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Self, MsvcEnvError> {
+        toml::from_str(text).map_err(|e| MsvcEnvError::ConfigError(e.to_string()))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, MsvcEnvError> {
+        let text = fs::read_to_string(path)?;
+        Self::parse(&text)
+            .map_err(|e| MsvcEnvError::ConfigError(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Loads the project configuration in `dir` or the nearest parent that has
+    /// one. The search stops at the repository root, the first directory
+    /// containing `.git`, so a configuration outside the project is never used.
+    pub fn find(dir: &Path) -> Result<Option<Self>, MsvcEnvError> {
+        match find_project_file(dir) {
+            Some(path) => {
+                tracing::trace!("Loading project configuration from {}", path.display());
+                Self::load(&path).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Reads `[package.metadata.msvc-env]` of a Cargo manifest; `None` if it has
+    /// no such table, as for a workspace root without a package
+    pub fn parse_cargo_manifest(text: &str) -> Result<Option<Self>, MsvcEnvError> {
+        let manifest = toml::from_str::<CargoManifest>(text)
+            .map_err(|e| MsvcEnvError::ConfigError(e.to_string()))?;
+        Ok(manifest
+            .package
+            .and_then(|x| x.metadata)
+            .and_then(|x| x.msvc_env))
+    }
+
+    /// Loads `[package.metadata.msvc-env]` from the `Cargo.toml` in `dir` or the
+    /// nearest parent that has one, searching no further than [`find`](Self::find)
+    pub fn find_cargo(dir: &Path) -> Result<Option<Self>, MsvcEnvError> {
+        let Some(path) = find_file(dir, CARGO_MANIFEST_FILE) else {
+            return Ok(None);
+        };
+        tracing::trace!("Loading Cargo package metadata from {}", path.display());
+        let text = fs::read_to_string(&path)?;
+        Self::parse_cargo_manifest(&text)
+            .map_err(|e| MsvcEnvError::ConfigError(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Location of the user configuration: `%APPDATA%\msvc-env\config.toml` on
+    /// Windows, `$XDG_CONFIG_HOME/msvc-env/config.toml` or
+    /// `~/.config/msvc-env/config.toml` elsewhere
+    pub fn user_path() -> Option<PathBuf> {
+        let dir = if cfg!(windows) {
+            env::var_os("APPDATA").map(PathBuf::from)
+        } else {
+            env::var_os("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .or_else(|| env::var_os("HOME").map(|x| PathBuf::from(x).join(".config")))
+        };
+        dir.map(|x| x.join(USER_CONFIG_DIR).join(USER_CONFIG_FILE))
+    }
+
+    /// Loads the user configuration, if there is one
+    pub fn user() -> Result<Option<Self>, MsvcEnvError> {
+        match Self::user_path() {
+            Some(path) if path.is_file() => {
+                tracing::trace!("Loading user configuration from {}", path.display());
+                Self::load(&path).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Combines two configurations; fields set in `other` win
+    pub fn merge(self, other: Config) -> Config {
+        Config {
+            arch: other.arch.or(self.arch),
+            host_arch: other.host_arch.or(self.host_arch),
+            toolset: other.toolset.or(self.toolset),
+            sdk: other.sdk.or(self.sdk),
+            min_sdk: other.min_sdk.or(self.min_sdk),
+            vs_version: other.vs_version.or(self.vs_version),
+            channel_id: other.channel_id.or(self.channel_id),
+            product_id: other.product_id.or(self.product_id),
+            installation_name: other.installation_name.or(self.installation_name),
+            prerelease: other.prerelease.or(self.prerelease),
+            prerelease_order: other.prerelease_order.or(self.prerelease_order),
+            legacy: other.legacy.or(self.legacy),
+            required_components: other.required_components.or(self.required_components),
+            requires_any: other.requires_any.or(self.requires_any),
+            start_dir: other.start_dir.or(self.start_dir),
+            host_fallback: other.host_fallback.or(self.host_fallback),
+            cache_dir: other.cache_dir.or(self.cache_dir),
+            cache_max_size: other.cache_max_size.or(self.cache_max_size),
+            format: other.format.or(self.format),
+        }
+    }
+
+    /// The selection this configuration pins, targeting x64 unless `arch` is set
+    pub fn selection(&self) -> Result<Selection, MsvcEnvError> {
+        let vs_version = match &self.vs_version {
+            Some(range) => Some(
+                range
+                    .parse()
+                    .map_err(|e| MsvcEnvError::ConfigError(format!("vs-version: {}", e)))?,
+            ),
+            None => None,
+        };
+        let toolset = match &self.toolset {
+            Some(toolset) => Some(
+                toolset
+                    .parse()
+                    .map_err(|e| MsvcEnvError::ConfigError(format!("toolset: {}", e)))?,
+            ),
+            None => None,
+        };
+        let sdk = match &self.sdk {
+            Some(sdk) => Some(
+                sdk.parse()
+                    .map_err(|e| MsvcEnvError::ConfigError(format!("sdk: {}", e)))?,
+            ),
+            None => None,
+        };
+        let min_sdk = match &self.min_sdk {
+            Some(sdk) => Some(
+                sdk.parse()
+                    .map_err(|e| MsvcEnvError::ConfigError(format!("min-sdk: {}", e)))?,
+            ),
+            None => None,
+        };
+        let prerelease_order = match &self.prerelease_order {
+            Some(order) => Some(
+                order
+                    .parse()
+                    .map_err(|e| MsvcEnvError::ConfigError(format!("prerelease-order: {}", e)))?,
+            ),
+            None => None,
+        };
+        let start_dir = match &self.start_dir {
+            Some(dir) => dir
+                .parse()
+                .map_err(|e| MsvcEnvError::ConfigError(format!("start-dir: {}", e)))?,
+            None => Default::default(),
+        };
+        Ok(Selection {
+            arch: self.arch.unwrap_or(MsvcArch::X64),
+            host_arch: self.host_arch.unwrap_or(MsvcArch::X64),
+            toolset,
+            sdk,
+            min_sdk,
+            vs_version,
+            channel_id: self.channel_id.clone(),
+            product_id: self.product_id.clone(),
+            installation_name: self.installation_name.clone(),
+            instance_id: None,
+            installation_path: None,
+            prerelease: self.prerelease.unwrap_or(false),
+            prerelease_order,
+            legacy: self.legacy.unwrap_or(false),
+            required_components: self.required_components.clone().unwrap_or_default(),
+            requires_any: self.requires_any.unwrap_or(false),
+            start_dir,
+            host_fallback: self.host_fallback.unwrap_or(false),
+            base_env: None,
+        })
+    }
+}
+
+fn find_project_file(dir: &Path) -> Option<PathBuf> {
+    find_file(dir, PROJECT_CONFIG_FILE)
+}
+
+/// `name` in `dir` or the nearest parent, up to the repository root
+fn find_file(dir: &Path, name: &str) -> Option<PathBuf> {
+    for dir in dir.ancestors() {
+        let path = dir.join(name);
+        if path.is_file() {
+            return Some(path);
+        }
+        if dir.join(".git").exists() {
+            break;
+        }
+    }
+    None
+}
+
+/// The selection for a build script: the user configuration, overlaid with the
+/// package's `[package.metadata.msvc-env]` and a `.msvc-env.toml`, then with
+/// the `MSVC_ENV_*` variables. Without a pinned architecture it targets the
+/// architecture cargo builds for. Tells cargo to run the script again when any
+/// of these change.
+pub fn build_script_selection() -> Result<Selection, MsvcEnvError> {
+    let dir = env::var_os("CARGO_MANIFEST_DIR")
+        .map(PathBuf::from)
+        .ok_or_else(|| MsvcEnvError::ConfigError("CARGO_MANIFEST_DIR is not set".to_string()))?;
+    let config = Config::user()?
+        .unwrap_or_default()
+        .merge(Config::find_cargo(&dir)?.unwrap_or_default())
+        .merge(Config::find(&dir)?.unwrap_or_default());
+
+    let user = Config::user_path().filter(|path| path.is_file());
+    let files = [
+        user,
+        find_file(&dir, CARGO_MANIFEST_FILE),
+        find_project_file(&dir),
+    ];
+    for path in files.into_iter().flatten() {
+        println!("cargo:rerun-if-changed={}", path.display());
+    }
+    for name in [
+        overrides::ARCH,
+        overrides::HOST_ARCH,
+        overrides::TOOLSET,
+        overrides::SDK,
+        overrides::VS_PATH,
+        overrides::VSWHERE,
+        overrides::OFFLINE,
+    ] {
+        println!("cargo:rerun-if-env-changed={}", name);
+    }
+
+    let mut selection = config.selection()?;
+    if config.arch.is_none()
+        && let Some(arch) = env::var("CARGO_CFG_TARGET_ARCH")
+            .ok()
+            .and_then(|x| target_arch(&x))
+    {
+        selection.arch = arch;
+    }
+    selection.with_env_overrides()
+}
+
+/// The MSVC architecture of a Rust `target_arch`
+fn target_arch(arch: &str) -> Option<MsvcArch> {
+    match arch {
+        "x86_64" => Some(MsvcArch::X64),
+        "x86" => Some(MsvcArch::X86),
+        "aarch64" | "arm64ec" => Some(MsvcArch::Arm64),
+        "arm" => Some(MsvcArch::Arm),
+        _ => None,
+    }
+}
+
+/// Cache directory from the user configuration. The file is read on every call
+/// so long-running processes see edits; an unreadable configuration is ignored
+/// here since the CLI reports it.
+pub(crate) fn user_cache_dir() -> Option<PathBuf> {
+    Config::user()
+        .unwrap_or_else(|e| {
+            tracing::warn!("Ignoring user configuration: {}", e);
+            None
+        })
+        .and_then(|x| x.cache_dir)
+}
+
+/// Cache size budget from the user configuration, read like [`user_cache_dir`]
+pub(crate) fn user_cache_max_size() -> Option<String> {
+    Config::user()
+        .unwrap_or_else(|e| {
+            tracing::warn!("Ignoring user configuration: {}", e);
+            None
+        })
+        .and_then(|x| x.cache_max_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(
+            r#"
+arch = "arm64"
+toolset = "14.29"
+vs-version = "[17.0,18.0)"
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.arch, Some(MsvcArch::Arm64));
+
+        let selection = config.selection().unwrap();
+        assert_eq!(selection.arch, MsvcArch::Arm64);
+        assert_eq!(selection.host_arch, MsvcArch::X64);
+        assert_eq!(selection.toolset, Some("14.29".parse().unwrap()));
+        assert_eq!(selection.vs_version, Some(crate::VsVersionRange::major(17)));
+        assert_eq!(selection.sdk, None);
+
+        assert!(matches!(
+            Config::parse("compiler = \"clang\""),
+            Err(MsvcEnvError::ConfigError(_))
+        ));
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+
+        let config = Config::parse("vs-version = \"17.x\"").unwrap();
+        assert!(matches!(
+            config.selection(),
+            Err(MsvcEnvError::ConfigError(_))
+        ));
+    }
+
+    #[test]
+    fn test_cargo_metadata() {
+        let config = Config::parse_cargo_manifest(
+            r#"
+[package]
+name = "app"
+version = "0.1.0"
+
+[package.metadata.msvc-env]
+arch = "arm64"
+toolset = "14.38"
+
+[package.metadata.docs.rs]
+all-features = true
+
+[dependencies]
+msvc-env = "0.1"
+"#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(config.arch, Some(MsvcArch::Arm64));
+        assert_eq!(config.toolset.as_deref(), Some("14.38"));
+
+        assert_eq!(
+            Config::parse_cargo_manifest("[workspace]\nmembers = [\"app\"]\n").unwrap(),
+            None
+        );
+        assert!(
+            Config::parse_cargo_manifest("[package.metadata.msvc-env]\ncompiler = \"clang\"\n")
+                .is_err()
+        );
+        assert_eq!(target_arch("aarch64"), Some(MsvcArch::Arm64));
+        assert_eq!(target_arch("riscv64"), None);
+    }
+
+    #[test]
+    fn test_find_project_file() {
+        let root = std::env::temp_dir().join(format!("msvc-env-config-{}", std::process::id()));
+        let project = root.join("project");
+        let nested = project.join("crates").join("app");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(root.join(PROJECT_CONFIG_FILE), "").unwrap();
+
+        assert_eq!(
+            find_project_file(&nested),
+            Some(root.join(PROJECT_CONFIG_FILE))
+        );
+
+        fs::create_dir(project.join(".git")).unwrap();
+        assert_eq!(find_project_file(&nested), None);
+
+        fs::write(project.join(PROJECT_CONFIG_FILE), "").unwrap();
+        assert_eq!(
+            find_project_file(&nested),
+            Some(project.join(PROJECT_CONFIG_FILE))
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_merge_config() {
+        let user = Config::parse(
+            r#"
+product-id = "Microsoft.VisualStudio.Product.BuildTools"
+prerelease = true
+toolset = "14.38"
+format = "sh"
+"#,
+        )
+        .unwrap();
+        let project = Config::parse("toolset = \"14.29\"").unwrap();
+
+        let config = user.merge(project);
+        assert_eq!(config.toolset.as_deref(), Some("14.29"));
+        assert_eq!(config.format, Some(OutputFormat::Sh));
+
+        let selection = config.selection().unwrap();
+        assert!(selection.prerelease);
+        assert_eq!(
+            selection.product_id.as_deref(),
+            Some("Microsoft.VisualStudio.Product.BuildTools")
+        );
+    }
+}
@@ -0,0 +1,171 @@
+//! HTTP downloads of vswhere and toolchain packages
+
+use crate::{MsvcEnvError, overrides};
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+type ProgressCallback = Arc<dyn Fn(&DownloadProgress<'_>) + Send + Sync>;
+
+static PROGRESS: RwLock<Option<ProgressCallback>> = RwLock::new(None);
+
+/// How far a download has got, as passed to the callback of
+/// [`set_download_progress`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DownloadProgress<'a> {
+    pub url: &'a str,
+    /// Bytes received so far
+    pub downloaded: u64,
+    /// Size of the whole download, if the server told
+    pub total: Option<u64>,
+    /// Set on the last call for a download, once everything was received
+    pub finished: bool,
+}
+
+/// Calls `callback` as vswhere and toolchain packages are downloaded, at the
+/// start of each download and after every chunk received, so frontends can show
+/// progress on slow networks. Replaces any previous callback for the whole
+/// process.
+pub fn set_download_progress(callback: impl Fn(&DownloadProgress<'_>) + Send + Sync + 'static) {
+    *PROGRESS.write().unwrap() = Some(Arc::new(callback));
+}
+
+/// Removes the callback of [`set_download_progress`]
+pub fn clear_download_progress() {
+    *PROGRESS.write().unwrap() = None;
+}
+
+/// Reports the bytes read through it to the progress callback
+struct ProgressReader<'a, R> {
+    inner: R,
+    url: &'a str,
+    downloaded: u64,
+    total: Option<u64>,
+    callback: Option<ProgressCallback>,
+}
+
+impl<'a, R: Read> ProgressReader<'a, R> {
+    fn new(inner: R, url: &'a str, total: Option<u64>) -> Self {
+        let reader = Self {
+            inner,
+            url,
+            downloaded: 0,
+            total,
+            callback: PROGRESS.read().unwrap().clone(),
+        };
+        reader.report(false);
+        reader
+    }
+
+    fn report(&self, finished: bool) {
+        if let Some(callback) = &self.callback {
+            callback(&DownloadProgress {
+                url: self.url,
+                downloaded: self.downloaded,
+                total: self.total,
+                finished,
+            });
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.downloaded += n as u64;
+        self.report(n == 0 && !buf.is_empty());
+        Ok(n)
+    }
+}
+
+/// Downloads `url` to `dest`, writing to a temporary file first so an interrupted
+/// download never leaves a truncated file behind
+pub(crate) fn download_file(url: &str, dest: &Path) -> Result<(), MsvcEnvError> {
+    let span = tracing::debug_span!("download", url, bytes = tracing::field::Empty).entered();
+    check_online(url)?;
+    tracing::trace!("Downloading {} to {}", url, dest.display());
+    let (reader, total) = get(url)?;
+    let mut reader = ProgressReader::new(reader, url, total);
+
+    let mut partial = dest.as_os_str().to_owned();
+    partial.push(".part");
+    let mut file = fs::File::create(&partial)?;
+    let bytes = std::io::copy(&mut reader, &mut file)?;
+    span.record("bytes", bytes);
+    drop(file);
+
+    fs::rename(&partial, dest)?;
+    Ok(())
+}
+
+/// Downloads `url` into memory
+#[cfg_attr(not(feature = "portable"), allow(dead_code))]
+pub(crate) fn download_bytes(url: &str) -> Result<Vec<u8>, MsvcEnvError> {
+    let span = tracing::debug_span!("download", url, bytes = tracing::field::Empty).entered();
+    check_online(url)?;
+    tracing::trace!("Downloading {}", url);
+    let (reader, total) = get(url)?;
+    let mut bytes = Vec::new();
+    ProgressReader::new(reader, url, total).read_to_end(&mut bytes)?;
+    span.record("bytes", bytes.len());
+    Ok(bytes)
+}
+
+/// Sends a GET request and returns the response body and its length
+#[cfg(feature = "download")]
+fn get(url: &str) -> Result<(impl Read, Option<u64>), MsvcEnvError> {
+    let response = ureq::get(url)
+        .call()
+        .map_err(|e| MsvcEnvError::DownloadError(e.to_string()))?;
+    let (parts, body) = response.into_parts();
+    let total = parts
+        .headers
+        .get("content-length")
+        .and_then(|x| x.to_str().ok()?.parse().ok());
+    Ok((body.into_reader(), total))
+}
+
+#[cfg(not(feature = "download"))]
+fn get(url: &str) -> Result<(std::io::Empty, Option<u64>), MsvcEnvError> {
+    Err(MsvcEnvError::DownloadError(format!(
+        "not downloading {}: built without the `download` feature",
+        url
+    )))
+}
+
+fn check_online(url: &str) -> Result<(), MsvcEnvError> {
+    if overrides::offline() {
+        return Err(MsvcEnvError::DownloadError(format!(
+            "{} is set, not downloading {}",
+            overrides::OFFLINE,
+            url
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_progress_reader() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let seen = calls.clone();
+        let callback: ProgressCallback = Arc::new(move |x: &DownloadProgress<'_>| {
+            seen.lock().unwrap().push((x.downloaded, x.finished));
+        });
+        let mut reader = ProgressReader {
+            inner: &b"vswhere"[..],
+            url: "https://example.com/vswhere.exe",
+            downloaded: 0,
+            total: Some(7),
+            callback: Some(callback),
+        };
+        let mut buf = [0; 4];
+        while reader.read(&mut buf).unwrap() > 0 {}
+        assert_eq!(*calls.lock().unwrap(), [(4, false), (7, false), (7, true)]);
+    }
+}
@@ -0,0 +1,153 @@
+//! Running a command in a resolved environment the way a shell would, for
+//! `msvc-env exec`: the command gets the console to itself while it runs and
+//! its exit status becomes this process's
+
+use std::io;
+use std::process::{Command, ExitStatus};
+
+/// Runs `command` to completion. Ctrl+C and Ctrl+Break reach the command
+/// through the shared console and are ignored here meanwhile, so a build that
+/// handles them is waited for instead of outliving this process. Ignoring them
+/// needs the `windows-sys` feature on Windows.
+pub fn run(command: &mut Command) -> io::Result<ExitStatus> {
+    let mut child = command.spawn()?;
+    let _guard = InterruptGuard::new();
+    child.wait()
+}
+
+/// Exits with the status of a command [`run`] ran: its exit code, which on
+/// Windows includes NTSTATUS values such as `STATUS_CONTROL_C_EXIT`, or on Unix
+/// the signal that terminated it, raised again
+pub fn exit_with(status: ExitStatus) -> ! {
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        unix::raise_default(signal);
+    }
+    std::process::exit(exit_code(status))
+}
+
+/// The code a shell reports for `status`: the exit code, or 128 plus the
+/// signal that terminated it
+pub fn exit_code(status: ExitStatus) -> i32 {
+    if let Some(code) = status.code() {
+        return code;
+    }
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        return 128 + signal;
+    }
+    1
+}
+
+/// Ignores console interrupts in this process until dropped
+struct InterruptGuard;
+
+#[cfg(all(windows, feature = "windows-sys"))]
+impl InterruptGuard {
+    fn new() -> Self {
+        use windows_sys::Win32::System::Console::SetConsoleCtrlHandler;
+        // A null handler would be inherited by the command and keep Ctrl+C
+        // from reaching it, so interrupts are swallowed by a handler instead
+        // SAFETY: the handler is a plain function that lives for the program
+        unsafe { SetConsoleCtrlHandler(Some(ignore_interrupt), 1) };
+        Self
+    }
+}
+
+#[cfg(all(windows, feature = "windows-sys"))]
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        use windows_sys::Win32::System::Console::SetConsoleCtrlHandler;
+        // SAFETY: removes the handler added in `new`
+        unsafe { SetConsoleCtrlHandler(Some(ignore_interrupt), 0) };
+    }
+}
+
+#[cfg(all(windows, feature = "windows-sys"))]
+unsafe extern "system" fn ignore_interrupt(event: u32) -> windows_sys::Win32::Foundation::BOOL {
+    use windows_sys::Win32::System::Console::{CTRL_BREAK_EVENT, CTRL_C_EVENT};
+    // Closing the console or logging off still ends this process
+    (event == CTRL_C_EVENT || event == CTRL_BREAK_EVENT) as _
+}
+
+#[cfg(unix)]
+impl InterruptGuard {
+    fn new() -> Self {
+        unix::ignore_interrupts(true);
+        Self
+    }
+}
+
+#[cfg(unix)]
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        unix::ignore_interrupts(false);
+    }
+}
+
+#[cfg(not(any(unix, all(windows, feature = "windows-sys"))))]
+impl InterruptGuard {
+    fn new() -> Self {
+        Self
+    }
+}
+
+/// The two C library calls needed for signals, which std links anyway
+#[cfg(unix)]
+mod unix {
+    const SIGINT: i32 = 2;
+    const SIGQUIT: i32 = 3;
+    const SIG_DFL: usize = 0;
+    const SIG_IGN: usize = 1;
+
+    unsafe extern "C" {
+        fn signal(signum: i32, handler: usize) -> usize;
+        fn raise(signum: i32) -> i32;
+    }
+
+    pub(super) fn ignore_interrupts(ignore: bool) {
+        let handler = if ignore { SIG_IGN } else { SIG_DFL };
+        for signum in [SIGINT, SIGQUIT] {
+            // SAFETY: only switches between the default and ignoring
+            unsafe { signal(signum, handler) };
+        }
+    }
+
+    /// Terminates this process with `signum` if its default action does
+    pub(super) fn raise_default(signum: i32) {
+        // SAFETY: restores the default action before raising
+        unsafe {
+            signal(signum, SIG_DFL);
+            raise(signum);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_exit_code() {
+        use std::os::unix::process::ExitStatusExt;
+
+        assert_eq!(exit_code(ExitStatus::from_raw(0)), 0);
+        assert_eq!(exit_code(ExitStatus::from_raw(3 << 8)), 3);
+        // Terminated by SIGINT
+        assert_eq!(exit_code(ExitStatus::from_raw(2)), 130);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_exit_code() {
+        use std::os::windows::process::ExitStatusExt;
+
+        assert_eq!(exit_code(ExitStatus::from_raw(3)), 3);
+        // STATUS_CONTROL_C_EXIT survives the round trip through i32
+        assert_eq!(
+            exit_code(ExitStatus::from_raw(0xC000_013A)) as u32,
+            0xC000_013A
+        );
+    }
+}
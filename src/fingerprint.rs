@@ -0,0 +1,99 @@
+//! Cheap checks for whether the installation behind a cached environment changed
+
+use crate::MsvcEnvironment;
+use std::fs;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// Variables naming directories that the Visual Studio Installer replaces or
+/// touches when it updates, modifies or removes an installation
+const WATCHED_VARS: &[&str] = &[
+    "VSINSTALLDIR",
+    "VCINSTALLDIR",
+    "VCToolsInstallDir",
+    "WindowsSdkDir",
+];
+
+/// Rewritten by every toolset update, relative to `VCINSTALLDIR`
+const DEFAULT_TOOLSET_FILE: &[&str] =
+    &["Auxiliary", "Build", "Microsoft.VCToolsVersion.default.txt"];
+
+/// Modification times of the files and directories an environment points into,
+/// `None` for paths that no longer exist
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct InstallFingerprint(Vec<(PathBuf, Option<SystemTime>)>);
+
+impl InstallFingerprint {
+    pub(crate) fn of(env: &MsvcEnvironment) -> Self {
+        let mut paths = WATCHED_VARS
+            .iter()
+            .filter_map(|key| env.get(key))
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+        if let Some(vc) = env.get("VCINSTALLDIR") {
+            paths.push(
+                DEFAULT_TOOLSET_FILE
+                    .iter()
+                    .fold(PathBuf::from(vc), |x, y| x.join(y)),
+            );
+        }
+
+        Self(
+            paths
+                .into_iter()
+                .map(|path| {
+                    let modified = fs::metadata(&path).and_then(|x| x.modified()).ok();
+                    (path, modified)
+                })
+                .collect(),
+        )
+    }
+
+    /// Whether anything changed after `time`, or is gone, e.g. since a
+    /// persisted environment was written
+    pub(crate) fn changed_since(&self, time: SystemTime) -> bool {
+        self.0
+            .iter()
+            .any(|(_, modified)| modified.is_none_or(|x| x > time))
+    }
+
+    /// Whether the environment no longer matches what is on disk
+    pub(crate) fn is_stale(&self) -> bool {
+        self.0
+            .iter()
+            .any(|(path, modified)| fs::metadata(path).and_then(|x| x.modified()).ok() != *modified)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indexmap::IndexMap;
+
+    #[test]
+    fn test_install_fingerprint() {
+        let root =
+            std::env::temp_dir().join(format!("msvc-env-fingerprint-{}", std::process::id()));
+        let tools = root
+            .join("VC")
+            .join("Tools")
+            .join("MSVC")
+            .join("14.38.33130");
+        fs::create_dir_all(&tools).unwrap();
+
+        let env = MsvcEnvironment::new(IndexMap::from([
+            ("VSINSTALLDIR".to_string(), root.display().to_string()),
+            ("VCToolsInstallDir".to_string(), tools.display().to_string()),
+        ]));
+        let fingerprint = InstallFingerprint::of(&env);
+        assert_eq!(fingerprint.0.len(), 2);
+        assert!(!fingerprint.is_stale());
+        assert!(!fingerprint.changed_since(SystemTime::now()));
+        assert!(fingerprint.changed_since(SystemTime::UNIX_EPOCH));
+
+        fs::remove_dir(&tools).unwrap();
+        assert!(fingerprint.is_stale());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
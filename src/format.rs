@@ -0,0 +1,601 @@
+//! Renders an [`MsvcEnvironment`] in formats understood by other build tools.
+//! Variables are written in the order of [`MsvcEnvironment::sort`].
+
+use crate::paths::{self, PathStyle};
+use crate::provider::powershell_quote;
+use crate::{MsvcEnvError, MsvcEnvironment};
+use indexmap::IndexMap;
+use std::fmt::Write as _;
+use std::path::Path;
+
+/// How the CLI prints an environment, chosen with `--format` or the `format`
+/// of the configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum OutputFormat {
+    /// `${env:NAME}='value'` lines
+    Powershell,
+    /// `export NAME="value"` lines with Unix-style `PATH`; also accepted as `bash`
+    Sh,
+    /// TeamCity `setParameter` service messages, see [`teamcity`]
+    Teamcity,
+    /// A Java properties file, see [`properties`]
+    Properties,
+    /// A JSON object of the variables, see `json`; needs the `serde` feature
+    Json,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = MsvcEnvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "powershell" => Ok(OutputFormat::Powershell),
+            "sh" | "bash" => Ok(OutputFormat::Sh),
+            "teamcity" => Ok(OutputFormat::Teamcity),
+            "properties" => Ok(OutputFormat::Properties),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(MsvcEnvError::ConfigError(format!(
+                "unknown format {:?}, expected one of powershell, sh, teamcity, properties, json",
+                s
+            ))),
+        }
+    }
+}
+
+/// Variables forwarded to Bazel actions in addition to the `BAZEL_*` toolchain hints
+const BAZEL_ACTION_VARS: &[&str] = &["PATH", "INCLUDE", "LIB", "LIBPATH"];
+
+/// Renders a `.bazelrc` fragment that points Bazel's C++ toolchain configuration
+/// at the same Visual Studio installation and SDK that produced `env`
+pub fn bazelrc(env: &MsvcEnvironment) -> String {
+    let mut out = String::new();
+
+    let mut hints = Vec::new();
+    if let Some(vc) = env.get("VCINSTALLDIR") {
+        hints.push(("BAZEL_VC", vc.trim_end_matches('\\').to_string()));
+    }
+    if let Some(version) = env.get("VCToolsVersion") {
+        hints.push(("BAZEL_VC_FULL_VERSION", version.trim().to_string()));
+    }
+    if let Some(version) = env.get("WindowsSDKVersion") {
+        hints.push((
+            "BAZEL_WINSDK_FULL_VERSION",
+            version.trim_end_matches('\\').to_string(),
+        ));
+    }
+
+    for (key, value) in hints {
+        writeln!(out, "build --action_env={}={}", key, bazelrc_quote(&value)).unwrap();
+    }
+
+    for key in BAZEL_ACTION_VARS {
+        if let Some(value) = env.get(key) {
+            writeln!(out, "build --action_env={}={}", key, bazelrc_quote(value)).unwrap();
+        }
+    }
+
+    out
+}
+
+/// Renders a file for `docker run --env-file`, one `KEY=value` per line.
+/// Docker reads env-file values verbatim, so nothing is quoted.
+/// [`MsvcEnvironment::from_dotenv`] reads it back.
+pub fn docker_env_file(env: &MsvcEnvironment) -> String {
+    let mut out = String::new();
+    for (key, value) in settable_vars(env) {
+        writeln!(out, "{}={}", key, value).unwrap();
+    }
+    out
+}
+
+/// Renders `ENV` instructions for a Dockerfile using the default `\` escape
+/// character. `$` is escaped too, as Docker substitutes variables in the values.
+pub fn dockerfile_env(env: &MsvcEnvironment) -> String {
+    let mut out = String::new();
+    for (key, value) in settable_vars(env) {
+        writeln!(
+            out,
+            "ENV {}=\"{}\"",
+            key,
+            value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('$', "\\$")
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// Renders `##teamcity[setParameter ...]` service messages that make every
+/// variable an `env.` parameter of the following build steps
+pub fn teamcity(env: &MsvcEnvironment) -> String {
+    let mut out = String::new();
+    for (key, value) in settable_vars(env) {
+        writeln!(
+            out,
+            "##teamcity[setParameter name='env.{}' value='{}']",
+            teamcity_escape(key),
+            teamcity_escape(value)
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// Escapes a service message value: `|` is the escape character and quotes,
+/// brackets, line breaks and non-ASCII characters must be escaped with it
+fn teamcity_escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '|' | '\'' | '[' | ']' => {
+                out.push('|');
+                out.push(c);
+            }
+            '\n' => out.push_str("|n"),
+            '\r' => out.push_str("|r"),
+            c if c.is_ascii() => out.push(c),
+            c => {
+                let mut units = [0; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    write!(out, "|0x{:04X}", unit).unwrap();
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Renders a JSON object of the variables, which
+/// [`MsvcEnvironment::from_json`] reads back
+#[cfg(feature = "serde")]
+pub fn json(env: &MsvcEnvironment) -> String {
+    serde_json::to_string_pretty(env).unwrap() + "\n"
+}
+
+/// Renders one JSON object holding the environment of every architecture in
+/// `envs`, keyed by architecture, as [`MsvcEnv::resolve_all`](crate::MsvcEnv::resolve_all)
+/// returns them
+#[cfg(feature = "serde")]
+pub fn json_by_arch(envs: &IndexMap<crate::MsvcArch, MsvcEnvironment>) -> String {
+    let envs = envs
+        .iter()
+        .map(|(arch, env)| (arch.as_str().to_string(), env))
+        .collect::<IndexMap<_, _>>();
+    serde_json::to_string_pretty(&envs).unwrap() + "\n"
+}
+
+/// Renders a Java properties file, one `NAME=value` per line, for Jenkins'
+/// EnvInject plugin or `readProperties`
+pub fn properties(env: &MsvcEnvironment) -> String {
+    let mut out = String::new();
+    for (key, value) in settable_vars(env) {
+        writeln!(
+            out,
+            "{}={}",
+            properties_escape(key, true),
+            properties_escape(value, false)
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// Escapes for `java.util.Properties::load`, which reads `\` as an escape
+/// character, ends keys at `=`, `:` or whitespace and decodes ISO 8859-1
+fn properties_escape(text: &str, key: bool) -> String {
+    let mut out = String::with_capacity(text.len());
+    for (i, c) in text.chars().enumerate() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '=' | ':' | '#' | '!' => {
+                out.push('\\');
+                out.push(c);
+            }
+            ' ' if key || i == 0 => out.push_str("\\ "),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_ascii() && !c.is_ascii_control() => out.push(c),
+            c => {
+                let mut units = [0; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    write!(out, "\\u{:04X}", unit).unwrap();
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Variables that can be set from outside the process; names such as
+/// `ProgramFiles(x86)` are rejected by Docker and are provided by Windows anyway
+fn settable_vars(env: &MsvcEnvironment) -> impl Iterator<Item = (&String, &String)> {
+    env.vars
+        .iter()
+        .filter(|(key, _)| !(key.contains('(') || key.contains(')') || key.starts_with('=')))
+}
+
+/// Returns `env` extended with the variables node-gyp and gyp's msvs generator use
+/// to pick a Visual Studio installation, pinned to the one that produced `env`
+pub fn node_gyp_env(env: &MsvcEnvironment) -> MsvcEnvironment {
+    let mut env = env.clone();
+
+    let year = env.get("VisualStudioVersion").and_then(vs_year);
+    if let Some(year) = year {
+        env.vars
+            .insert("GYP_MSVS_VERSION".to_string(), year.to_string());
+    }
+    if let Some(vs_dir) = env
+        .get("VSINSTALLDIR")
+        .map(|x| x.trim_end_matches('\\').to_string())
+    {
+        env.vars
+            .insert("GYP_MSVS_OVERRIDE_PATH".to_string(), vs_dir);
+    }
+
+    env.sort();
+    env
+}
+
+/// Returns `env` extended with the variables that make setuptools/distutils use the
+/// already configured compiler environment instead of searching for Visual Studio
+pub fn python_env(env: &MsvcEnvironment) -> MsvcEnvironment {
+    let mut env = env.clone();
+    env.vars
+        .insert("DISTUTILS_USE_SDK".to_string(), "1".to_string());
+    env.vars.insert("MSSdk".to_string(), "1".to_string());
+    env.sort();
+    env
+}
+
+/// Undoes applying an environment to a shell: variables the environment
+/// overwrote get their previous values back and the ones it added are removed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RestoreScript {
+    /// Variables to reset, with the value they had before
+    pub restore: Vec<(String, String)>,
+    /// Variables that did not exist before
+    pub unset: Vec<String>,
+}
+
+impl RestoreScript {
+    /// Compares `env` with the `previous` variables, matching names without
+    /// regard to case like Windows does
+    pub fn new(env: &MsvcEnvironment, previous: &IndexMap<String, String>) -> Self {
+        let mut restore = Vec::new();
+        let mut unset = Vec::new();
+        for (key, value) in &env.vars {
+            match previous.iter().find(|(k, _)| k.eq_ignore_ascii_case(key)) {
+                Some((_, old)) if old == value => {}
+                Some((_, old)) => restore.push((key.clone(), old.clone())),
+                None => unset.push(key.clone()),
+            }
+        }
+        Self { restore, unset }
+    }
+
+    /// Compares `env` with the variables of the current process, which for the
+    /// CLI are those of the shell it was started from
+    pub fn from_process(env: &MsvcEnvironment) -> Self {
+        Self::new(env, &std::env::vars().collect())
+    }
+
+    /// Renders a `msvc_env_deactivate` shell function; `PATH` is converted to
+    /// `style` like the activation does
+    pub fn sh(&self, style: &PathStyle) -> String {
+        let mut out = String::from("msvc_env_deactivate() {\n");
+        for (key, value) in self.restore.iter().filter(|(key, _)| is_sh_name(key)) {
+            let value = if key.eq_ignore_ascii_case("PATH") {
+                paths::to_unix_list(value, style)
+            } else {
+                value.clone()
+            };
+            writeln!(out, "    export {}={}", key, paths::shell_quote(&value)).unwrap();
+        }
+        for key in self.unset.iter().filter(|key| is_sh_name(key)) {
+            writeln!(out, "    unset {}", key).unwrap();
+        }
+        out.push_str("    unset -f msvc_env_deactivate\n}\n");
+        out
+    }
+
+    /// Renders an `Exit-MsvcEnv` PowerShell function
+    pub fn powershell(&self) -> String {
+        let mut out = String::from("function global:Exit-MsvcEnv {\n");
+        for (key, value) in &self.restore {
+            writeln!(out, "    ${{env:{}}}={}", key, powershell_quote(value)).unwrap();
+        }
+        for key in &self.unset {
+            writeln!(
+                out,
+                "    Remove-Item -LiteralPath {} -ErrorAction SilentlyContinue",
+                powershell_quote(&format!("Env:{}", key))
+            )
+            .unwrap();
+        }
+        out.push_str("    Remove-Item Function:Exit-MsvcEnv\n}\n");
+        out
+    }
+}
+
+/// Renders a PowerShell module with `Enter-MsvcEnv` and `Exit-MsvcEnv`, which run
+/// the CLI at `exe` to apply an environment to the session and undo it again.
+/// The previous values are kept in the module's scope, so entering twice
+/// first leaves the current environment.
+pub fn powershell_module(exe: &Path) -> String {
+    let mut out = String::new();
+    writeln!(
+        out,
+        "# Generated by `msvc-env powershell-module`; load with Import-Module"
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "$script:MsvcEnvExe = {}",
+        powershell_quote(&exe.display().to_string())
+    )
+    .unwrap();
+    out.push_str(POWERSHELL_MODULE);
+    out
+}
+
+const POWERSHELL_MODULE: &str = r#"$script:SavedEnv = $null
+
+function Enter-MsvcEnv {
+    [CmdletBinding()]
+    param(
+        [ValidateSet('x64', 'x86', 'arm', 'arm64')]
+        [string]$Arch = 'x64',
+        [ValidateSet('x64', 'x86', 'arm64')]
+        [string]$HostArch,
+        [string]$Toolset,
+        [string]$Sdk,
+        [string]$VsVersion
+    )
+
+    if ($null -ne $script:SavedEnv) {
+        Exit-MsvcEnv
+    }
+
+    $cliArgs = @('print', '--powershell', '--no-restore', '--arch', $Arch)
+    if ($HostArch) { $cliArgs += @('--host-arch', $HostArch) }
+    if ($Toolset) { $cliArgs += @('--toolset', $Toolset) }
+    if ($Sdk) { $cliArgs += @('--sdk', $Sdk) }
+    if ($VsVersion) { $cliArgs += @('--vs-version', $VsVersion) }
+
+    $output = & $script:MsvcEnvExe @cliArgs
+    if ($LASTEXITCODE -ne 0) {
+        throw "msvc-env failed with exit code $LASTEXITCODE"
+    }
+
+    $saved = @{}
+    Get-ChildItem Env: | ForEach-Object { $saved[$_.Name] = $_.Value }
+    $script:SavedEnv = $saved
+    Invoke-Expression ($output -join "`n")
+}
+
+function Exit-MsvcEnv {
+    [CmdletBinding()]
+    param()
+
+    if ($null -eq $script:SavedEnv) {
+        Write-Warning 'No MSVC environment was entered'
+        return
+    }
+
+    foreach ($name in @(Get-ChildItem Env: | ForEach-Object Name)) {
+        if (-not $script:SavedEnv.ContainsKey($name)) {
+            Remove-Item -LiteralPath "Env:$name" -ErrorAction SilentlyContinue
+        }
+    }
+    foreach ($entry in $script:SavedEnv.GetEnumerator()) {
+        Set-Item -LiteralPath "Env:$($entry.Key)" -Value $entry.Value
+    }
+    $script:SavedEnv = $null
+}
+
+Export-ModuleMember -Function Enter-MsvcEnv, Exit-MsvcEnv
+"#;
+
+/// Whether `key` can be assigned in `sh`, which rules out names like
+/// `ProgramFiles(x86)`
+fn is_sh_name(key: &str) -> bool {
+    !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Maps `VisualStudioVersion` (e.g. `17.0`) to the product year gyp expects
+fn vs_year(version: &str) -> Option<&'static str> {
+    match version.split('.').next()? {
+        "14" => Some("2015"),
+        "15" => Some("2017"),
+        "16" => Some("2019"),
+        "17" => Some("2022"),
+        "18" => Some("2026"),
+        _ => None,
+    }
+}
+
+/// Quotes a value for the bazelrc tokenizer, which treats backslashes as escapes
+fn bazelrc_quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_env() -> MsvcEnvironment {
+        let mut vars = IndexMap::new();
+        vars.insert(
+            "VCINSTALLDIR".to_string(),
+            r"C:\Program Files\Microsoft Visual Studio\2022\BuildTools\VC\".to_string(),
+        );
+        vars.insert("VCToolsVersion".to_string(), "14.38.33130".to_string());
+        vars.insert(
+            "WindowsSDKVersion".to_string(),
+            r"10.0.22621.0\".to_string(),
+        );
+        vars.insert("Path".to_string(), r"C:\VC\bin;C:\Windows".to_string());
+        vars.insert("VisualStudioVersion".to_string(), "17.0".to_string());
+        vars.insert(
+            "VSINSTALLDIR".to_string(),
+            r"C:\Program Files\Microsoft Visual Studio\2022\BuildTools\".to_string(),
+        );
+        MsvcEnvironment::new(vars)
+    }
+
+    #[test]
+    fn test_bazelrc() {
+        let rc = bazelrc(&sample_env());
+        let lines = rc.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                r#"build --action_env=BAZEL_VC="C:\\Program Files\\Microsoft Visual Studio\\2022\\BuildTools\\VC""#,
+                r#"build --action_env=BAZEL_VC_FULL_VERSION="14.38.33130""#,
+                r#"build --action_env=BAZEL_WINSDK_FULL_VERSION="10.0.22621.0""#,
+                r#"build --action_env=PATH="C:\\VC\\bin;C:\\Windows""#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_docker_env() {
+        let mut env = sample_env();
+        env.vars.insert(
+            "ProgramFiles(x86)".to_string(),
+            r"C:\Program Files (x86)".to_string(),
+        );
+
+        let env_file = docker_env_file(&env);
+        assert!(env_file.contains("VCToolsVersion=14.38.33130\n"));
+        assert!(env_file.contains("Path=C:\\VC\\bin;C:\\Windows\n"));
+        assert!(!env_file.contains("ProgramFiles(x86)"));
+
+        env.vars
+            .insert("PROMPT".to_string(), "$P$G ${USER}".to_string());
+        let dockerfile = dockerfile_env(&env);
+        assert!(dockerfile.contains(r#"ENV Path="C:\\VC\\bin;C:\\Windows""#));
+        assert!(dockerfile.contains(r#"ENV PROMPT="\$P\$G \${USER}""#));
+        assert!(!dockerfile.contains("ProgramFiles(x86)"));
+    }
+
+    #[test]
+    fn test_teamcity() {
+        let mut env = sample_env();
+        env.vars
+            .insert("ProgramFiles(x86)".to_string(), r"C:\x86".to_string());
+        env.vars
+            .insert("Quoted".to_string(), "it's [x]|ü\n".to_string());
+
+        let messages = teamcity(&env);
+        assert!(
+            messages.contains(
+                "##teamcity[setParameter name='env.VCToolsVersion' value='14.38.33130']\n"
+            )
+        );
+        assert!(messages.contains(
+            "##teamcity[setParameter name='env.Quoted' value='it|'s |[x|]|||0x00FC|n']\n"
+        ));
+        assert!(!messages.contains("ProgramFiles(x86)"));
+        assert_eq!(
+            "teamcity".parse::<OutputFormat>().unwrap(),
+            OutputFormat::Teamcity
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json_by_arch() {
+        let envs = IndexMap::from([
+            (crate::MsvcArch::X64, sample_env()),
+            (crate::MsvcArch::Arm64, MsvcEnvironment::default()),
+        ]);
+        let json = json_by_arch(&envs);
+        assert!(json.starts_with("{\n  \"x64\": {\n"));
+        assert!(json.contains("\"VCToolsVersion\": \"14.38.33130\""));
+        assert!(json.contains("\"arm64\": {}"));
+    }
+
+    #[test]
+    fn test_properties() {
+        let mut env = sample_env();
+        env.vars
+            .insert("Odd Name".to_string(), " x=1 # ü".to_string());
+
+        let properties = properties(&env);
+        assert!(properties.contains(
+            r"VCINSTALLDIR=C\:\\Program Files\\Microsoft Visual Studio\\2022\\BuildTools\\VC\\"
+        ));
+        assert!(properties.contains(r"Path=C\:\\VC\\bin;C\:\\Windows"));
+        assert!(properties.contains(r"Odd\ Name=\ x\=1 \# \u00FC"));
+    }
+
+    #[test]
+    fn test_node_gyp_env() {
+        let env = node_gyp_env(&sample_env());
+        assert_eq!(env.get("GYP_MSVS_VERSION"), Some("2022"));
+        assert_eq!(
+            env.get("GYP_MSVS_OVERRIDE_PATH"),
+            Some(r"C:\Program Files\Microsoft Visual Studio\2022\BuildTools")
+        );
+        assert_eq!(env.get("VCINSTALLDIR"), sample_env().get("VCINSTALLDIR"));
+    }
+
+    #[test]
+    fn test_restore_script() {
+        let mut previous = IndexMap::new();
+        previous.insert("PATH".to_string(), r"C:\Windows;C:\Tools (x86)".to_string());
+        previous.insert("VisualStudioVersion".to_string(), "17.0".to_string());
+        previous.insert("HOME".to_string(), "/home/user".to_string());
+
+        let script = RestoreScript::new(&sample_env(), &previous);
+        assert_eq!(
+            script.restore,
+            [("Path".to_string(), r"C:\Windows;C:\Tools (x86)".to_string())]
+        );
+        assert_eq!(
+            script.unset,
+            [
+                "VCINSTALLDIR",
+                "VCToolsVersion",
+                "WindowsSDKVersion",
+                "VSINSTALLDIR"
+            ]
+        );
+
+        let sh = script.sh(&PathStyle::Msys);
+        assert!(sh.starts_with("msvc_env_deactivate() {\n"));
+        assert!(sh.contains("    export Path='/c/Windows:/c/Tools (x86)'\n"));
+        assert!(sh.contains("    unset VCToolsVersion\n"));
+
+        let powershell = script.powershell();
+        assert!(powershell.contains(r"    ${env:Path}='C:\Windows;C:\Tools (x86)'"));
+        assert!(powershell.contains(
+            "    Remove-Item -LiteralPath 'Env:VSINSTALLDIR' -ErrorAction SilentlyContinue\n"
+        ));
+    }
+
+    #[test]
+    fn test_powershell_module() {
+        let module = powershell_module(Path::new(r"C:\Users\O'Brien\.cargo\bin\msvc-env.exe"));
+        assert!(
+            module.contains(r"$script:MsvcEnvExe = 'C:\Users\O''Brien\.cargo\bin\msvc-env.exe'")
+        );
+        assert!(module.contains("function Enter-MsvcEnv {"));
+        assert!(module.contains("function Exit-MsvcEnv {"));
+        assert!(module.ends_with("Export-ModuleMember -Function Enter-MsvcEnv, Exit-MsvcEnv\n"));
+    }
+
+    #[test]
+    fn test_python_env() {
+        let env = python_env(&sample_env());
+        assert_eq!(env.get("DISTUTILS_USE_SDK"), Some("1"));
+        assert_eq!(env.get("MSSdk"), Some("1"));
+        assert_eq!(env.vars.len(), sample_env().vars.len() + 2);
+    }
+}
@@ -0,0 +1,486 @@
+//! What to install when no usable toolchain is found
+
+use crate::provider::{VswhereProvider, check_components};
+use crate::{MsvcArch, MsvcEnvError, Selection, VsInstallation};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// The Build Tools workload providing the MSVC compiler, linker and libraries
+pub const VC_TOOLS_WORKLOAD: &str = "Microsoft.VisualStudio.Workload.VCTools";
+
+/// The same for the IDE editions
+const NATIVE_DESKTOP_WORKLOAD: &str = "Microsoft.VisualStudio.Workload.NativeDesktop";
+
+/// Products guidance can be given for, by the last part of their product ID
+pub const PRODUCTS: &[&str] = &["BuildTools", "Community", "Professional", "Enterprise"];
+
+const PRODUCT_ID_PREFIX: &str = "Microsoft.VisualStudio.Product.";
+const WINGET_ID_PREFIX: &str = "Microsoft.VisualStudio.2022.";
+const RELEASE_CHANNEL_ID: &str = "VisualStudio.17.Release";
+const BOOTSTRAPPER_URL_PREFIX: &str = "https://aka.ms/vs/17/release/vs_";
+/// Exit code of the bootstrapper when it succeeded but a restart is needed
+#[cfg(feature = "download")]
+const REBOOT_REQUIRED: i32 = 3010;
+const VS_INSTALLER: &str =
+    r"C:\Program Files (x86)\Microsoft Visual Studio\Installer\vs_installer.exe";
+
+const X86_X64_TOOLS: &str = "Microsoft.VisualStudio.Component.VC.Tools.x86.x64";
+const ARM_TOOLS: &str = "Microsoft.VisualStudio.Component.VC.Tools.ARM";
+const ARM64_TOOLS: &str = "Microsoft.VisualStudio.Component.VC.Tools.ARM64";
+const WINDOWS_SDK: &str = "Microsoft.VisualStudio.Component.Windows11SDK.22621";
+
+/// Visual Studio component IDs an `arch` build needs on an x64 host
+pub fn required_components(arch: MsvcArch) -> Vec<&'static str> {
+    let mut components = vec![X86_X64_TOOLS, WINDOWS_SDK];
+    components.extend(
+        compiler_components(arch)
+            .into_iter()
+            .filter(|x| *x != X86_X64_TOOLS),
+    );
+    components
+}
+
+/// Component IDs providing the compilers that target `arch`
+pub fn compiler_components(arch: MsvcArch) -> Vec<&'static str> {
+    match arch {
+        MsvcArch::X86 | MsvcArch::X64 => vec![X86_X64_TOOLS],
+        MsvcArch::Arm => vec![ARM_TOOLS],
+        MsvcArch::Arm64 => vec![ARM64_TOOLS],
+        MsvcArch::All => vec![X86_X64_TOOLS, ARM_TOOLS, ARM64_TOOLS],
+    }
+}
+
+/// One of the [`PRODUCTS`], given as in that list or as a full product ID,
+/// ignoring case
+pub fn parse_product(name: &str) -> Result<&'static str, MsvcEnvError> {
+    let short = name.get(PRODUCT_ID_PREFIX.len()..).filter(|_| {
+        name.get(..PRODUCT_ID_PREFIX.len())
+            .is_some_and(|x| x.eq_ignore_ascii_case(PRODUCT_ID_PREFIX))
+    });
+    let short = short.unwrap_or(name);
+    PRODUCTS
+        .iter()
+        .find(|x| x.eq_ignore_ascii_case(short))
+        .copied()
+        .ok_or_else(|| {
+            MsvcEnvError::ConfigError(format!(
+                "unknown product {:?}, expected one of {}",
+                name,
+                PRODUCTS.join(", ")
+            ))
+        })
+}
+
+/// The product pinned in `selection`, or Build Tools
+fn selected_product(selection: &Selection) -> Result<&'static str, MsvcEnvError> {
+    match &selection.product_id {
+        Some(id) => parse_product(id),
+        None => Ok("BuildTools"),
+    }
+}
+
+/// The full product ID of one of the [`PRODUCTS`], as vswhere `-products` expects
+pub fn product_id(product: &str) -> String {
+    format!("{}{}", PRODUCT_ID_PREFIX, product)
+}
+
+/// How much of the installer UI a [bootstrap](InstallHint::bootstrap) shows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InstallMode {
+    /// No UI at all; needs an elevated process
+    #[default]
+    Quiet,
+    /// Progress only, asking for elevation if needed
+    Passive,
+}
+
+impl InstallMode {
+    fn arg(self) -> &'static str {
+        match self {
+            InstallMode::Quiet => "--quiet",
+            InstallMode::Passive => "--passive",
+        }
+    }
+}
+
+/// Command lines that install a product, Build Tools unless told otherwise, with
+/// everything an architecture needs
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct InstallGuidance {
+    pub arch: MsvcArch,
+    /// Components added on top of the C++ workload
+    pub components: Vec<&'static str>,
+    /// One of the [`PRODUCTS`]
+    pub product: &'static str,
+}
+
+impl InstallGuidance {
+    pub fn for_arch(arch: MsvcArch) -> Self {
+        Self {
+            arch,
+            components: required_components(arch),
+            product: "BuildTools",
+        }
+    }
+
+    /// Guidance for installing `product` instead, one of the [`PRODUCTS`]
+    pub fn product(mut self, product: &'static str) -> Self {
+        self.product = product;
+        self
+    }
+
+    /// The product's C++ workload, [`VC_TOOLS_WORKLOAD`] for Build Tools
+    pub fn workload(&self) -> &'static str {
+        if self.product == "BuildTools" {
+            VC_TOOLS_WORKLOAD
+        } else {
+            NATIVE_DESKTOP_WORKLOAD
+        }
+    }
+
+    /// Where the product's bootstrapper is downloaded from, e.g.
+    /// `https://aka.ms/vs/17/release/vs_buildtools.exe`
+    pub fn bootstrapper_url(&self) -> String {
+        format!(
+            "{}{}.exe",
+            BOOTSTRAPPER_URL_PREFIX,
+            self.product.to_lowercase()
+        )
+    }
+
+    fn add_args(&self) -> String {
+        std::iter::once(self.workload())
+            .chain(self.components.iter().copied())
+            .map(|id| format!("--add {}", id))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Installs the product through winget
+    pub fn winget_command(&self) -> String {
+        format!(
+            "winget install --id {}{} --override \"--quiet --wait {}\"",
+            WINGET_ID_PREFIX,
+            self.product,
+            self.add_args()
+        )
+    }
+
+    /// Adds the components to the installation at `installation`
+    pub fn modify_command(&self, installation: &Path) -> String {
+        format!(
+            "\"{}\" modify --installPath \"{}\" --quiet --wait {}",
+            VS_INSTALLER,
+            installation.display(),
+            self.add_args()
+        )
+    }
+
+    /// Installs the product through an existing Visual Studio Installer
+    pub fn vs_installer_command(&self) -> String {
+        format!(
+            "\"{}\" install --channelId {} --productId {} --quiet --wait {}",
+            VS_INSTALLER,
+            RELEASE_CHANNEL_ID,
+            product_id(self.product),
+            self.add_args()
+        )
+    }
+}
+
+impl fmt::Display for InstallGuidance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let product = match self.product {
+            "BuildTools" => "Build Tools",
+            product => product,
+        };
+        writeln!(
+            f,
+            "Install the Visual Studio {} with the C++ workload for {}:",
+            product,
+            self.arch.as_str()
+        )?;
+        writeln!(f, "  {}", self.winget_command())?;
+        writeln!(f, "or, if the Visual Studio Installer is present:")?;
+        write!(f, "  {}", self.vs_installer_command())
+    }
+}
+
+impl MsvcEnvError {
+    /// How to install what is missing, for errors an installation would fix
+    pub fn install_guidance(&self, arch: MsvcArch) -> Option<InstallGuidance> {
+        match self {
+            MsvcEnvError::NoVisualStudio => Some(InstallGuidance::for_arch(arch)),
+            MsvcEnvError::ArchNotSupported(arch, _) => Some(InstallGuidance::for_arch(*arch)),
+            MsvcEnvError::MissingComponent {
+                arch, components, ..
+            } => Some(InstallGuidance {
+                arch: *arch,
+                components: components.clone(),
+                product: "BuildTools",
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// What to run so a selection can be resolved on this machine
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct InstallHint {
+    pub guidance: InstallGuidance,
+    /// The installation to add the components to; `None` if a new one is needed
+    pub installation: Option<PathBuf>,
+}
+
+impl InstallHint {
+    /// Looks for the installation `selection` resolves to and works out what it
+    /// lacks, or what to install if there is none. `None` if nothing is missing.
+    /// A `product_id` in the selection picks the product of a new installation;
+    /// an existing one is modified with the workload and bootstrapper of its own
+    /// product.
+    pub fn for_selection(selection: &Selection) -> Result<Option<Self>, MsvcEnvError> {
+        let installation = match VswhereProvider::new().find_instance(selection) {
+            Ok(installation) => installation,
+            Err(MsvcEnvError::NoVisualStudio) => {
+                return Ok(Some(Self {
+                    guidance: InstallGuidance::for_arch(selection.arch)
+                        .product(selected_product(selection)?),
+                    installation: None,
+                }));
+            }
+            Err(e) => return Err(e),
+        };
+        match check_components(&installation.path, selection) {
+            Ok(()) => Ok(None),
+            Err(e) => match e.install_guidance(selection.arch) {
+                Some(guidance) => Ok(Some(Self::modifying(guidance, &installation, selection)?)),
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Adding what `guidance` lists to `installation`. Its product decides the
+    /// workload and bootstrapper; the selection's only counts if it is unknown,
+    /// e.g. with `MSVC_ENV_VS_PATH`.
+    fn modifying(
+        guidance: InstallGuidance,
+        installation: &VsInstallation,
+        selection: &Selection,
+    ) -> Result<Self, MsvcEnvError> {
+        let product = match installation.product().and_then(|x| parse_product(x).ok()) {
+            Some(product) => product,
+            None => selected_product(selection)?,
+        };
+        Ok(Self {
+            guidance: guidance.product(product),
+            installation: Some(installation.path.clone()),
+        })
+    }
+
+    /// Arguments for the product's bootstrapper that install it, or add the
+    /// missing components to the installation, waiting until it is done
+    pub fn bootstrapper_args(&self, mode: InstallMode) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(installation) = &self.installation {
+            args.push("modify".to_string());
+            args.push("--installPath".to_string());
+            args.push(installation.display().to_string());
+        }
+        args.extend(
+            [mode.arg(), "--wait", "--norestart", "--nocache"]
+                .into_iter()
+                .map(String::from),
+        );
+        for id in std::iter::once(self.guidance.workload())
+            .chain(self.guidance.components.iter().copied())
+        {
+            args.push("--add".to_string());
+            args.push(id.to_string());
+        }
+        args
+    }
+
+    /// Downloads the product's bootstrapper to the cache directory and runs it
+    /// with the [`bootstrapper_args`](Self::bootstrapper_args). Success that
+    /// needs a restart only logs a warning.
+    #[cfg(feature = "download")]
+    pub fn bootstrap(&self, mode: InstallMode) -> Result<(), MsvcEnvError> {
+        let url = self.guidance.bootstrapper_url();
+        let file_name = url.rsplit('/').next().unwrap_or("vs_buildtools.exe");
+        let cache_dir = crate::overrides::writable_cache_dir()?;
+        let path = crate::cache::root(&cache_dir)
+            .join("bootstrapper")
+            .join(file_name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        crate::download::download_file(&url, &path)?;
+        if let Err(e) = crate::cache::prune(&cache_dir, crate::overrides::cache_max_size()) {
+            tracing::debug!("Could not prune {}: {}", cache_dir.display(), e);
+        }
+
+        let mut command = std::process::Command::new(&path);
+        command.args(self.bootstrapper_args(mode));
+        crate::capture::log_command(&command);
+        let output = command.output()?;
+        match output.status.code() {
+            Some(0) => Ok(()),
+            Some(REBOOT_REQUIRED) => {
+                tracing::warn!("Visual Studio was installed, but Windows needs a restart");
+                Ok(())
+            }
+            _ => Err(crate::capture::process_failed(
+                crate::Stage::Download,
+                &command,
+                &output,
+            )),
+        }
+    }
+
+    /// The command lines to run, one per alternative
+    pub fn commands(&self) -> Vec<String> {
+        match &self.installation {
+            Some(installation) => vec![self.guidance.modify_command(installation)],
+            None => vec![
+                self.guidance.winget_command(),
+                self.guidance.vs_installer_command(),
+            ],
+        }
+    }
+}
+
+impl fmt::Display for InstallHint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.installation {
+            Some(installation) => {
+                writeln!(
+                    f,
+                    "Add the missing components to {}:",
+                    installation.display()
+                )?;
+                write!(f, "  {}", self.guidance.modify_command(installation))
+            }
+            None => self.guidance.fmt(f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_install_guidance() {
+        let guidance = MsvcEnvError::NoVisualStudio
+            .install_guidance(MsvcArch::Arm64)
+            .unwrap();
+        let winget = guidance.winget_command();
+        assert!(winget.starts_with("winget install --id Microsoft.VisualStudio.2022.BuildTools"));
+        assert!(winget.contains("--add Microsoft.VisualStudio.Workload.VCTools"));
+        assert!(winget.contains("--add Microsoft.VisualStudio.Component.VC.Tools.ARM64"));
+        assert!(!winget.contains("VC.Tools.ARM "));
+        assert!(
+            guidance
+                .vs_installer_command()
+                .contains("--productId Microsoft.VisualStudio.Product.BuildTools")
+        );
+
+        assert_eq!(
+            required_components(MsvcArch::All).len(),
+            required_components(MsvcArch::X64).len() + 2
+        );
+        assert!(
+            MsvcEnvError::ParseError(String::new())
+                .install_guidance(MsvcArch::X64)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_product_guidance() {
+        assert_eq!(parse_product("buildtools").unwrap(), "BuildTools");
+        assert_eq!(
+            parse_product("Microsoft.VisualStudio.Product.Enterprise").unwrap(),
+            "Enterprise"
+        );
+        assert!(parse_product("Express").is_err());
+        assert_eq!(
+            product_id("BuildTools"),
+            "Microsoft.VisualStudio.Product.BuildTools"
+        );
+
+        let guidance = InstallGuidance::for_arch(MsvcArch::X64).product("Community");
+        assert!(
+            guidance
+                .winget_command()
+                .starts_with("winget install --id Microsoft.VisualStudio.2022.Community ")
+        );
+        assert!(
+            guidance
+                .vs_installer_command()
+                .contains("--productId Microsoft.VisualStudio.Product.Community --quiet --wait --add Microsoft.VisualStudio.Workload.NativeDesktop")
+        );
+
+        let hint = InstallHint {
+            guidance,
+            installation: Some(PathBuf::from(r"C:\VS")),
+        };
+        assert_eq!(hint.commands().len(), 1);
+        assert_eq!(
+            hint.guidance.bootstrapper_url(),
+            "https://aka.ms/vs/17/release/vs_community.exe"
+        );
+        assert_eq!(
+            hint.bootstrapper_args(InstallMode::Passive)[..5],
+            ["modify", "--installPath", r"C:\VS", "--passive", "--wait"]
+        );
+        assert!(
+            hint.to_string()
+                .starts_with("Add the missing components to C:\\VS:\n")
+        );
+    }
+
+    #[test]
+    fn test_modify_community() {
+        let community = VsInstallation {
+            path: PathBuf::from(r"C:\Program Files\Microsoft Visual Studio\2022\Community"),
+            product_id: Some("Microsoft.VisualStudio.Product.Community".to_string()),
+            ..Default::default()
+        };
+        let missing = InstallGuidance {
+            arch: MsvcArch::Arm64,
+            components: vec![ARM64_TOOLS],
+            product: "BuildTools",
+        };
+        // Build Tools pinned, or not, yet Community is what is installed
+        let selection = Selection::new(MsvcArch::Arm64).product_id(product_id("BuildTools"));
+        for selection in [Selection::new(MsvcArch::Arm64), selection] {
+            let hint = InstallHint::modifying(missing.clone(), &community, &selection).unwrap();
+            assert_eq!(hint.guidance.product, "Community");
+            assert_eq!(
+                hint.guidance.bootstrapper_url(),
+                "https://aka.ms/vs/17/release/vs_community.exe"
+            );
+            let commands = hint.commands();
+            assert_eq!(commands.len(), 1);
+            assert!(commands[0].contains("--add Microsoft.VisualStudio.Workload.NativeDesktop"));
+            assert!(!commands[0].contains(VC_TOOLS_WORKLOAD));
+            assert!(hint.to_string().contains("Workload.NativeDesktop --add"));
+            assert!(
+                hint.bootstrapper_args(InstallMode::Quiet)
+                    .contains(&NATIVE_DESKTOP_WORKLOAD.to_string())
+            );
+        }
+
+        // Without a known product, the selection's is used
+        let unknown = VsInstallation {
+            path: PathBuf::from(r"C:\VS"),
+            ..Default::default()
+        };
+        let selection = Selection::new(MsvcArch::Arm64).product_id(product_id("Enterprise"));
+        let hint = InstallHint::modifying(missing, &unknown, &selection).unwrap();
+        assert_eq!(hint.guidance.product, "Enterprise");
+    }
+}
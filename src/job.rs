@@ -0,0 +1,78 @@
+//! Windows job objects tying everything a capture shell starts to the capture,
+//! so timeouts and early returns never orphan a process
+
+use std::process::Child;
+
+/// A kill-on-close job holding a capture shell and its descendants. Dropping it
+/// terminates whatever is still running in it.
+#[cfg_attr(not(all(windows, feature = "windows-sys")), allow(dead_code))]
+pub(crate) struct Job {
+    #[cfg(all(windows, feature = "windows-sys"))]
+    handle: windows_sys::Win32::Foundation::HANDLE,
+}
+
+impl Job {
+    /// Puts `child` into a new job. Processes it starts from then on are put
+    /// into the job as well. `None` if that is not possible, in which case the
+    /// capture goes on without one.
+    #[cfg(all(windows, feature = "windows-sys"))]
+    pub(crate) fn assign(child: &Child) -> Option<Self> {
+        use std::os::windows::io::AsRawHandle;
+        use std::ptr;
+        use windows_sys::Win32::System::JobObjects::{
+            AssignProcessToJobObject, CreateJobObjectW, JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+            JOBOBJECT_EXTENDED_LIMIT_INFORMATION, JobObjectExtendedLimitInformation,
+            SetInformationJobObject,
+        };
+
+        // SAFETY: a null name and security attributes create an anonymous job
+        let handle = unsafe { CreateJobObjectW(ptr::null(), ptr::null()) };
+        if handle.is_null() {
+            tracing::trace!(
+                "CreateJobObjectW failed: {}",
+                std::io::Error::last_os_error()
+            );
+            return None;
+        }
+        let job = Self { handle };
+
+        // SAFETY: the structure is plain data for which all zeroes is valid
+        let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = unsafe { std::mem::zeroed() };
+        info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+        // SAFETY: `info` outlives the call and its size is passed along
+        let ok = unsafe {
+            SetInformationJobObject(
+                job.handle,
+                JobObjectExtendedLimitInformation,
+                &info as *const _ as *const _,
+                size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32,
+            )
+        };
+        // SAFETY: the process handle stays valid while `child` is borrowed
+        let ok =
+            ok != 0 && unsafe { AssignProcessToJobObject(job.handle, child.as_raw_handle()) } != 0;
+        if !ok {
+            tracing::trace!(
+                "Could not put capture shell {} into a job: {}",
+                child.id(),
+                std::io::Error::last_os_error()
+            );
+            return None;
+        }
+        Some(job)
+    }
+
+    /// Job objects need the `windows-sys` feature on Windows
+    #[cfg(not(all(windows, feature = "windows-sys")))]
+    pub(crate) fn assign(_child: &Child) -> Option<Self> {
+        None
+    }
+}
+
+#[cfg(all(windows, feature = "windows-sys"))]
+impl Drop for Job {
+    fn drop(&mut self) {
+        // SAFETY: the handle is owned by this job and closed exactly once
+        unsafe { windows_sys::Win32::Foundation::CloseHandle(self.handle) };
+    }
+}
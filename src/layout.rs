@@ -0,0 +1,240 @@
+//! Builds environments directly from an extracted toolchain directory, without
+//! running any of Visual Studio's setup scripts
+
+use crate::{MsvcArch, MsvcEnvError, MsvcEnvironment, SdkVersion, Selection, ToolsetVersion};
+use indexmap::IndexMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Windows SDK headers that must be present for a kit version to be usable
+const SDK_INCLUDE_DIRS: &[&str] = &["ucrt", "shared", "um", "winrt", "cppwinrt"];
+const SDK_LIB_DIRS: &[&str] = &["ucrt", "um"];
+
+/// A toolset and SDK laid out the way Visual Studio installs them:
+/// `<root>\VC\Tools\MSVC\<version>` and `<root>\Windows Kits\10`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ToolchainLayout {
+    pub(crate) root: PathBuf,
+    pub(crate) toolset_version: ToolsetVersion,
+    pub(crate) sdk_version: SdkVersion,
+}
+
+impl ToolchainLayout {
+    /// Picks the newest toolset and SDK found under `root`
+    pub(crate) fn detect(root: &Path) -> Result<Self, MsvcEnvError> {
+        let toolset_version = installed_toolsets(&root.join("VC").join("Tools").join("MSVC"))
+            .pop()
+            .ok_or(MsvcEnvError::NoVisualStudio)?;
+        let sdk_version = installed_sdks(&root.join("Windows Kits").join("10").join("Include"))
+            .pop()
+            .ok_or(MsvcEnvError::NoVisualStudio)?;
+
+        let layout = Self {
+            root: root.to_path_buf(),
+            toolset_version,
+            sdk_version,
+        };
+        tracing::trace!("Found toolchain layout {:?}", layout);
+        Ok(layout)
+    }
+
+    /// Switches to the toolset and SDK versions pinned in `selection`, if any
+    pub(crate) fn pin(mut self, selection: &Selection) -> Result<Self, MsvcEnvError> {
+        if let Some(toolset) = &selection.toolset {
+            let msvc = self.root.join("VC").join("Tools").join("MSVC");
+            self.toolset_version = matching_toolset(&msvc, toolset).ok_or_else(|| {
+                MsvcEnvError::InvalidToolchain(format!(
+                    "no toolset {} in {}",
+                    toolset,
+                    msvc.display()
+                ))
+            })?;
+        }
+        if let Some(sdk) = &selection.sdk {
+            let include = self.sdk_dir().join("Include");
+            self.sdk_version = matching_sdk(&include, sdk).ok_or_else(|| {
+                MsvcEnvError::InvalidToolchain(format!("no SDK {} in {}", sdk, include.display()))
+            })?;
+        }
+        Ok(self)
+    }
+
+    pub(crate) fn msvc_dir(&self) -> PathBuf {
+        self.root
+            .join("VC")
+            .join("Tools")
+            .join("MSVC")
+            .join(self.toolset_version.to_string())
+    }
+
+    pub(crate) fn sdk_dir(&self) -> PathBuf {
+        self.root.join("Windows Kits").join("10")
+    }
+
+    /// Paths an `arch` build needs that are missing from this layout
+    pub(crate) fn missing_paths(&self, arch: MsvcArch) -> Vec<PathBuf> {
+        let target = arch.as_str();
+        let msvc = self.msvc_dir();
+        let sdk = self.sdk_dir();
+        let sdk_include = sdk.join("Include").join(self.sdk_version.to_string());
+        let sdk_lib = sdk.join("Lib").join(self.sdk_version.to_string());
+
+        let mut expected = vec![
+            msvc.join("bin").join("Hostx64").join(target).join("cl.exe"),
+            msvc.join("include"),
+            msvc.join("lib").join(target),
+        ];
+        expected.extend(SDK_INCLUDE_DIRS.iter().map(|dir| sdk_include.join(dir)));
+        expected.extend(
+            SDK_LIB_DIRS
+                .iter()
+                .map(|dir| sdk_lib.join(dir).join(target)),
+        );
+
+        expected.retain(|path| !path.exists());
+        expected
+    }
+
+    /// Synthesizes the variables VsDevCmd would set for an x64-hosted `arch` build
+    pub(crate) fn environment(&self, arch: MsvcArch) -> Result<MsvcEnvironment, MsvcEnvError> {
+        if arch == MsvcArch::All {
+            return Err(MsvcEnvError::ArchNotSupported(
+                arch,
+                "a single target architecture".to_string(),
+            ));
+        }
+        let target = arch.as_str();
+
+        let msvc = self.msvc_dir();
+        let sdk = self.sdk_dir();
+        let sdk_include = sdk.join("Include").join(self.sdk_version.to_string());
+        let sdk_lib = sdk.join("Lib").join(self.sdk_version.to_string());
+
+        let compiler_dir = msvc.join("bin").join("Hostx64").join(target);
+        if !compiler_dir.join("cl.exe").exists() {
+            return Err(MsvcEnvError::ArchNotSupported(
+                arch,
+                compiler_dir.join("cl.exe").display().to_string(),
+            ));
+        }
+
+        let mut path = vec![compiler_dir];
+        if arch != MsvcArch::X64 {
+            // Cross compilers load DLLs from the native host toolset
+            path.push(msvc.join("bin").join("Hostx64").join("x64"));
+        }
+        path.push(
+            sdk.join("bin")
+                .join(self.sdk_version.to_string())
+                .join("x64"),
+        );
+
+        let mut include = vec![msvc.join("include")];
+        include.extend(SDK_INCLUDE_DIRS.iter().map(|dir| sdk_include.join(dir)));
+
+        let mut lib = vec![msvc.join("lib").join(target)];
+        lib.extend(
+            SDK_LIB_DIRS
+                .iter()
+                .map(|dir| sdk_lib.join(dir).join(target)),
+        );
+
+        let mut vars = IndexMap::new();
+        let mut path = join_paths(&path);
+        if let Ok(existing) = std::env::var("PATH") {
+            path = format!("{};{}", path, existing);
+        }
+        vars.insert("PATH".to_string(), path);
+        vars.insert("INCLUDE".to_string(), join_paths(&include));
+        vars.insert("LIB".to_string(), join_paths(&lib));
+        vars.insert("LIBPATH".to_string(), join_paths(&lib[..1]));
+        vars.insert(
+            "VCINSTALLDIR".to_string(),
+            format!("{}\\", self.root.join("VC").display()),
+        );
+        vars.insert(
+            "VCToolsInstallDir".to_string(),
+            format!("{}\\", msvc.display()),
+        );
+        vars.insert(
+            "VCToolsVersion".to_string(),
+            self.toolset_version.to_string(),
+        );
+        vars.insert("WindowsSdkDir".to_string(), format!("{}\\", sdk.display()));
+        vars.insert(
+            "WindowsSDKVersion".to_string(),
+            format!("{}\\", self.sdk_version),
+        );
+        vars.insert("VSCMD_ARG_HOST_ARCH".to_string(), "x64".to_string());
+        vars.insert("VSCMD_ARG_TGT_ARCH".to_string(), target.to_string());
+
+        Ok(MsvcEnvironment::new(vars))
+    }
+}
+
+fn join_paths(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// Name of the subdirectory with the highest dotted version number, e.g. `1.33`
+pub(crate) fn latest_version_dir(dir: &Path) -> Option<String> {
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter_map(|name| Some((version_key(&name)?, name)))
+        .max()
+        .map(|(_, name)| name)
+}
+
+/// The toolsets installed in a `VC\Tools\MSVC` directory, oldest first
+pub(crate) fn installed_toolsets(msvc: &Path) -> Vec<ToolsetVersion> {
+    let Ok(entries) = fs::read_dir(msvc) else {
+        return Vec::new();
+    };
+    let mut toolsets = entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok()?.parse().ok())
+        .collect::<Vec<ToolsetVersion>>();
+    toolsets.sort();
+    toolsets
+}
+
+/// The newest installed toolset that [matches](ToolsetVersion::matches) `pin`
+pub(crate) fn matching_toolset(msvc: &Path, pin: &ToolsetVersion) -> Option<ToolsetVersion> {
+    installed_toolsets(msvc)
+        .into_iter()
+        .rev()
+        .find(|x| x.matches(pin))
+}
+
+/// The SDKs installed in a `Windows Kits\10\Include` directory, oldest first
+pub(crate) fn installed_sdks(include: &Path) -> Vec<SdkVersion> {
+    let Ok(entries) = fs::read_dir(include) else {
+        return Vec::new();
+    };
+    let mut sdks = entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok()?.parse().ok())
+        .collect::<Vec<SdkVersion>>();
+    sdks.sort();
+    sdks
+}
+
+/// The newest installed SDK that [matches](SdkVersion::matches) `pin`
+pub(crate) fn matching_sdk(include: &Path, pin: &SdkVersion) -> Option<SdkVersion> {
+    installed_sdks(include)
+        .into_iter()
+        .rev()
+        .find(|x| x.matches(pin))
+}
+
+/// Sort key for a dotted numeric version such as `10.0.22621.0`; `None` if any
+/// segment is not a number
+pub(crate) fn version_key(version: &str) -> Option<Vec<u32>> {
+    version
+        .split('.')
+        .map(|part| part.parse::<u32>().ok())
+        .collect()
+}
@@ -0,0 +1,182 @@
+//! Windows limits on environment size. An oversized PATH does not fail where it
+//! is set but later, when CreateProcess or a cmd script expanding `%PATH%`
+//! rejects it in the middle of a build.
+
+use crate::{MsvcEnvironment, PATH_LIST_VARS};
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+
+/// Longest value of a single variable, in UTF-16 units
+pub const MAX_VAR_LEN: usize = 32_767;
+
+/// Longest command line cmd accepts, which bounds `set PATH=%PATH%;...` in scripts
+pub const MAX_CMD_LINE: usize = 8_191;
+
+/// Longest environment block many programs and older Windows versions accept
+pub const MAX_BLOCK_LEN: usize = 32_767;
+
+/// Share of a limit from which a size is reported as approaching it
+const WARN_RATIO: f64 = 0.9;
+
+/// Sizes that matter for the limits above, in UTF-16 units
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct EnvironmentSize {
+    /// Length of the PATH value
+    pub path: usize,
+    /// Length of the whole block passed to CreateProcess: `NAME=value\0` per
+    /// variable and a final `\0`
+    pub block: usize,
+}
+
+impl EnvironmentSize {
+    /// Describes each limit this size exceeds or comes close to
+    pub fn warnings(&self) -> Vec<String> {
+        let checks = [
+            (self.path, MAX_VAR_LEN, "PATH", "the limit for a variable"),
+            (self.path, MAX_CMD_LINE, "PATH", "cmd's command line limit"),
+            (
+                self.block,
+                MAX_BLOCK_LEN,
+                "the environment",
+                "the environment block limit",
+            ),
+        ];
+        checks
+            .iter()
+            .filter(|(size, limit, ..)| *size as f64 >= *limit as f64 * WARN_RATIO)
+            .map(|(size, limit, what, name)| {
+                let verb = if size > limit {
+                    "exceeds"
+                } else {
+                    "approaches"
+                };
+                format!(
+                    "{} is {} characters, which {} {} of {}",
+                    what, size, verb, name, limit
+                )
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for EnvironmentSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "PATH {} of {} characters, environment block {} of {}",
+            self.path, MAX_VAR_LEN, self.block, MAX_BLOCK_LEN
+        )
+    }
+}
+
+impl MsvcEnvironment {
+    /// How large this environment is when passed to a process
+    pub fn size(&self) -> EnvironmentSize {
+        let len = |x: &str| x.encode_utf16().count();
+        EnvironmentSize {
+            path: self.get("PATH").map_or(0, len),
+            block: self
+                .vars
+                .iter()
+                .map(|(key, value)| len(key) + 1 + len(value) + 1)
+                .sum::<usize>()
+                + 1,
+        }
+    }
+
+    /// Logs a warning for every limit this environment exceeds or comes close to
+    pub(crate) fn warn_if_oversized(&self) {
+        for warning in self.size().warnings() {
+            tracing::warn!("{}", warning);
+        }
+    }
+
+    /// Shrinks PATH, INCLUDE, LIB and LIBPATH by dropping empty entries, repeated
+    /// entries (ignoring case and a trailing `\`) and entries that do not exist on
+    /// this machine. Returns how many entries were removed.
+    pub fn compact_paths(&mut self) -> usize {
+        let mut removed = 0;
+        for (key, value) in self.vars.iter_mut() {
+            if !PATH_LIST_VARS.iter().any(|x| x.eq_ignore_ascii_case(key)) {
+                continue;
+            }
+            let mut seen = HashSet::new();
+            let entries = value.split(';').collect::<Vec<_>>();
+            let kept = entries
+                .iter()
+                .filter(|entry| {
+                    !entry.is_empty()
+                        && seen.insert(entry.trim_end_matches('\\').to_lowercase())
+                        && Path::new(entry).exists()
+                })
+                .copied()
+                .collect::<Vec<_>>();
+            removed += entries.len() - kept.len();
+            *value = kept.join(";");
+        }
+        if removed > 0 {
+            tracing::debug!("Removed {} redundant search path entries", removed);
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indexmap::IndexMap;
+    use std::fs;
+
+    #[test]
+    fn test_environment_size() {
+        let env = MsvcEnvironment::new(IndexMap::from([
+            ("A".to_string(), "ü".to_string()),
+            ("Path".to_string(), "C:\\x".to_string()),
+        ]));
+        assert_eq!(
+            env.size(),
+            EnvironmentSize {
+                path: 4,
+                block: 4 + 10 + 1
+            }
+        );
+        assert!(env.size().warnings().is_empty());
+
+        let size = EnvironmentSize {
+            path: 7_500,
+            block: 40_000,
+        };
+        assert_eq!(
+            size.warnings(),
+            [
+                "PATH is 7500 characters, which approaches cmd's command line limit of 8191",
+                "the environment is 40000 characters, which exceeds the environment block limit of 32767",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compact_paths() {
+        let root = std::env::temp_dir().join(format!("msvc-env-limits-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let dir = root.display().to_string();
+        let path = format!(
+            "{dir};;{upper}\\;{dir}{sep}missing;{dir}",
+            upper = dir.to_uppercase(),
+            sep = std::path::MAIN_SEPARATOR
+        );
+        let mut env = MsvcEnvironment::new(IndexMap::from([
+            ("Path".to_string(), path.clone()),
+            ("OTHER".to_string(), path),
+        ]));
+
+        let removed = env.compact_paths();
+        assert_eq!(env.get("PATH"), Some(dir.as_str()));
+        assert_eq!(removed, 4);
+        assert!(env.get("OTHER").unwrap().contains("missing"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
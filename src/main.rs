@@ -1,98 +1,777 @@
-use msvc_env::MsvcEnv;
-use std::{
-    env,
-    path::{Path, Prefix},
+use indexmap::IndexMap;
+use msvc_env::format::{OutputFormat, RestoreScript};
+use msvc_env::paths::{self, PathStyle};
+use msvc_env::provider::powershell_quote;
+use msvc_env::{
+    DevShellProvider, EwdkProvider, MsvcArch, MsvcEnv, MsvcEnvError, MsvcEnvironment, MsvcProduct,
+    Selection, VcvarsallProvider, VswhereProvider,
 };
+use std::env;
+use std::io::IsTerminal as _;
+use std::path::Path;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::format::FmtSpan;
 
-fn unixify_path(path: &Path) -> String {
-    let p = path
-        .components()
-        .filter_map(|c| {
-            Some(match c {
-                std::path::Component::Prefix(prefix_component) => match prefix_component.kind() {
-                    Prefix::Disk(os_str) => format!("/{}", os_str as char).to_lowercase(),
-                    _ => format!("{}", prefix_component.as_os_str().to_str().unwrap()),
-                },
-                std::path::Component::RootDir => return None,
-                std::path::Component::CurDir => ".".to_string(),
-                std::path::Component::ParentDir => "..".to_string(),
-                std::path::Component::Normal(os_str) => os_str
-                    .to_str()
-                    .unwrap()
-                    .replace(" ", "\\ ")
-                    .replace('(', "\\(")
-                    .replace(')', "\\)"),
-            })
-        })
-        .collect::<Vec<_>>();
-    // eprintln!("P: {:?}", p);
-    p.join("/")
+/// Options that consume the following argument as their value
+const VALUE_OPTIONS: &[&str] = &[
+    "--arch",
+    "--host-arch",
+    "--toolset",
+    "--sdk",
+    "--min-sdk",
+    "--vs-version",
+    "--ewdk",
+    "--channel",
+    "--product",
+    "--prerelease-order",
+    "--vs",
+    "--instance",
+    "--requires",
+    "--start-dir",
+    "--products",
+    "--path-style",
+    "--wsl-mount-root",
+    "--format",
+    "--max-size",
+    "--cwd",
+    "-e",
+];
+
+/// Command line split into subcommand words, boolean flags and valued options,
+/// and everything after `--` left as it is
+struct Args {
+    positional: Vec<String>,
+    flags: Vec<String>,
+    options: Vec<(String, String)>,
+    trailing: Vec<String>,
+}
+
+impl Args {
+    fn parse(args: impl Iterator<Item = String>) -> Args {
+        let mut parsed = Args {
+            positional: Vec::new(),
+            flags: Vec::new(),
+            options: Vec::new(),
+            trailing: Vec::new(),
+        };
+
+        let mut args = args.peekable();
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                parsed.trailing.extend(args.by_ref());
+            } else if let Some((key, value)) = arg.split_once('=').filter(|_| arg.starts_with("--"))
+            {
+                parsed.options.push((key.to_string(), value.to_string()));
+            } else if VALUE_OPTIONS.contains(&arg.as_str()) {
+                match args.next() {
+                    Some(value) => parsed.options.push((arg, value)),
+                    None => {
+                        eprintln!("Missing value for {}", arg);
+                        std::process::exit(1);
+                    }
+                }
+            } else if arg.starts_with('-') {
+                parsed.flags.push(arg);
+            } else {
+                parsed.positional.push(arg);
+            }
+        }
+
+        parsed
+    }
+
+    fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|f| f == flag)
+    }
+
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .rev()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Every value of an option that may be repeated, in order
+    fn option_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.options
+            .iter()
+            .filter(move |(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
 }
 
-fn unixify_path_env(path: &str) -> String {
-    path.split(";")
-        .map(|p| Path::new(p))
-        .map(unixify_path)
-        .collect::<Vec<_>>()
-        .join(":")
+fn parse_arch(arg: &str) -> MsvcArch {
+    arg.parse().unwrap_or_else(|_| {
+        eprintln!(
+            "Invalid architecture: {}. Supported architectures: x64, x86, arm, arm64, all, native",
+            arg
+        );
+        std::process::exit(1);
+    })
 }
 
 fn main() {
-    tracing_subscriber::fmt::init();
+    // RUST_LOG=debug shows how long discovery, downloads and capture took
+    let level = env::var("RUST_LOG")
+        .ok()
+        .and_then(|x| x.parse::<LevelFilter>().ok())
+        .unwrap_or(LevelFilter::INFO);
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(std::io::stderr)
+        .init();
+
+    let args = Args::parse(env::args().skip(1));
+    if std::io::stderr().is_terminal() {
+        msvc_env::set_download_progress(show_progress);
+    }
+
+    match args.positional.first().map(|x| &**x) {
+        Some("bazel-rc") => bazel_rc(&args),
+        Some("bootstrap") => bootstrap(&args),
+        Some("cache") => cache(&args),
+        Some("docker-env") => docker_env(&args),
+        Some("doctor") => doctor(&args),
+        Some("exec") => exec(&args),
+        Some("install-hint") => install_hint(&args),
+        Some("list") => list(),
+        Some("powershell-module") => powershell_module(),
+        Some("prewarm") => prewarm(&args),
+        Some("snapshot") => snapshot(&args),
+        Some("status") => status(),
+        Some("print") => print(&args, args.positional.get(1)),
+        _ => print(&args, args.positional.first()),
+    }
+}
+
+/// Reports `e` and exits with its [exit code](MsvcEnvError::exit_code)
+fn fail(e: &MsvcEnvError) -> ! {
+    eprintln!("{}", e);
+    std::process::exit(e.exit_code().into());
+}
+
+/// Like [`fail`], adding what to install when nothing was found for `arch`
+fn fail_for(e: &MsvcEnvError, arch: MsvcArch) -> ! {
+    if let (MsvcEnvError::NoVisualStudio, Some(guidance)) = (e, e.install_guidance(arch)) {
+        eprintln!("{}. {}", e, guidance);
+        std::process::exit(e.exit_code().into());
+    }
+    fail(e)
+}
+
+/// Progress line for downloads on a terminal, overwritten in place
+fn show_progress(progress: &msvc_env::DownloadProgress<'_>) {
+    let name = progress.url.rsplit('/').next().unwrap_or(progress.url);
+    let kib = progress.downloaded / 1024;
+    match progress.total {
+        Some(total) => eprint!("\rDownloading {} {} / {} KiB", name, kib, total / 1024),
+        None => eprint!("\rDownloading {} {} KiB", name, kib),
+    }
+    if progress.finished {
+        eprintln!();
+    }
+}
+
+/// Selection from the user and project configuration, overridden by `MSVC_ENV_*`
+/// variables, then by `--arch` (or a positional architecture) and the other
+/// pinning options
+fn selection(args: &Args, positional: Option<&String>) -> Selection {
+    let mut selection = configured_selection()
+        .with_env_overrides()
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+    if let Some(arch) = args.option("--arch").or(positional.map(|x| &**x)) {
+        selection.arch = parse_arch(arch);
+    }
+    if let Some(host_arch) = args.option("--host-arch") {
+        selection.host_arch = parse_arch(host_arch);
+    }
+    if args.has_flag("--host-fallback") {
+        selection.host_fallback = true;
+    }
+    if args.has_flag("--prerelease") {
+        selection.prerelease = true;
+    }
+    if let Some(order) = args.option("--prerelease-order") {
+        selection = selection.prerelease_order(order.parse().unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }));
+    }
+    if args.has_flag("--legacy") {
+        selection.legacy = true;
+    }
+    let components = args.option_values("--requires").collect::<Vec<_>>();
+    if !components.is_empty() {
+        selection = selection.required_components(&components);
+    }
+    if args.has_flag("--requires-any") {
+        selection.requires_any = true;
+    }
+    if args.has_flag("--clean-env") {
+        selection = selection.clean_baseline();
+    }
+    if let Some(toolset) = args.option("--toolset") {
+        selection = selection.toolset(toolset.parse().unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }));
+    }
+    if let Some(sdk) = args.option("--sdk") {
+        selection = selection.sdk(sdk.parse().unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }));
+    }
+    if let Some(sdk) = args.option("--min-sdk") {
+        selection = selection.require_sdk_at_least(sdk.parse().unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }));
+    }
+    if let Some(range) = args.option("--vs-version") {
+        selection = selection.vs_version(range.parse().unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }));
+    }
+    if let Some(channel) = args.option("--channel") {
+        selection = selection.channel_id(channel);
+    }
+    if let Some(product) = args.option("--product") {
+        selection = match product.parse::<MsvcProduct>() {
+            Ok(product) => selection.product(product),
+            Err(_) => selection.product_id(product),
+        };
+    }
+    if let Some(name) = args.option("--vs") {
+        selection = selection.installation_name(name);
+    }
+    if let Some(id) = args.option("--instance") {
+        selection = selection.instance_id(id);
+    }
+    if let Some(dir) = args.option("--start-dir") {
+        selection = selection.start_dir(dir.parse().unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }));
+    }
+    selection
+}
+
+/// The user configuration overlaid with the Cargo package metadata and then
+/// the project configuration
+#[cfg(feature = "config")]
+fn config() -> msvc_env::config::Config {
+    use msvc_env::config::Config;
+
+    let dir = env::current_dir().unwrap_or_else(|e| {
+        eprintln!("Cannot read the working directory: {}", e);
+        std::process::exit(1);
+    });
+    let configs =
+        Config::user().and_then(|user| Ok((user, Config::find_cargo(&dir)?, Config::find(&dir)?)));
+    match configs {
+        Ok((user, cargo, project)) => user
+            .unwrap_or_default()
+            .merge(cargo.unwrap_or_default())
+            .merge(project.unwrap_or_default()),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(feature = "config")]
+fn configured_selection() -> Selection {
+    config().selection().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    })
+}
+
+#[cfg(not(feature = "config"))]
+fn configured_selection() -> Selection {
+    Selection::new(MsvcArch::X64)
+}
+
+/// What `print` emits unless told otherwise
+#[cfg(feature = "config")]
+fn default_format() -> OutputFormat {
+    config().format.unwrap_or(OutputFormat::Powershell)
+}
+
+#[cfg(not(feature = "config"))]
+fn default_format() -> OutputFormat {
+    OutputFormat::Powershell
+}
+
+/// `--format`, else `--sh` or `--powershell`, else the configured format
+fn output_format(args: &Args) -> OutputFormat {
+    if let Some(format) = args.option("--format") {
+        return format.parse().unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+    }
+    if args.has_flag("--sh") {
+        OutputFormat::Sh
+    } else if args.has_flag("--powershell") {
+        OutputFormat::Powershell
+    } else {
+        default_format()
+    }
+}
+
+/// Resolver configured from the command line
+fn msvc_env(args: &Args) -> MsvcEnv {
+    let ewdk = args.option("--ewdk");
+    let devshell = args.has_flag("--devshell");
+    let vcvarsall = args.has_flag("--vcvarsall");
+    let compact = args.has_flag("--compact-paths");
+    let short = args.has_flag("--short-paths");
+    let cmake = args.has_flag("--bundled-cmake");
+    if ewdk.is_none() && !devshell && !vcvarsall && !compact && !short && !cmake {
+        return MsvcEnv::new();
+    }
+
+    let mut builder = MsvcEnv::builder();
+    if compact {
+        builder = builder.compact_paths();
+    }
+    if short {
+        builder = builder.short_paths();
+    }
+    if cmake {
+        builder = builder.bundled_cmake();
+    }
+    if let Some(root) = ewdk {
+        builder = builder.provider(EwdkProvider::new(root));
+    }
+    if devshell {
+        builder.provider(DevShellProvider::new()).build()
+    } else if vcvarsall {
+        builder.provider(VcvarsallProvider::new()).build()
+    } else if ewdk.is_some() {
+        builder.provider(VswhereProvider::new()).build()
+    } else {
+        builder.build()
+    }
+}
+
+/// Resolves the selection the options describe; `--timings` reports how
+fn resolve(args: &Args, arch: Option<&String>) -> MsvcEnvironment {
+    let selection = selection(args, arch);
+    let env = msvc_env(args)
+        .resolve(&selection)
+        .unwrap_or_else(|e| fail_for(&e, selection.arch));
+    if args.has_flag("--timings") {
+        eprintln!("{}", env.meta);
+    }
+    env
+}
+
+fn bazel_rc(args: &Args) {
+    let env = resolve(args, None);
+    print!("{}", msvc_env::format::bazelrc(&env));
+}
+
+/// `bootstrap` downloads the Visual Studio bootstrapper and installs what the
+/// selection lacks, quietly or with `--passive` showing progress. Without
+/// `--yes` it asks first, or only prints what it would do if it cannot ask.
+#[cfg(feature = "download")]
+fn bootstrap(args: &Args) {
+    use msvc_env::install::{InstallHint, InstallMode};
+
+    let mut selection = selection(args, None);
+    if let Some(product) = args.option("--products") {
+        let product = msvc_env::install::parse_product(product).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+        selection = selection.product_id(msvc_env::install::product_id(product));
+    }
+    let hint = match InstallHint::for_selection(&selection) {
+        Ok(Some(hint)) => hint,
+        Ok(None) => {
+            eprintln!("Nothing to install for {}", selection.arch);
+            return;
+        }
+        Err(e) => fail(&e),
+    };
+    let mode = if args.has_flag("--passive") {
+        InstallMode::Passive
+    } else {
+        InstallMode::Quiet
+    };
 
-    let args = env::args().collect::<Vec<_>>();
-    let flags = args
-        .iter()
-        .filter(|arg| arg.starts_with("-"))
-        .map(|x| &**x)
+    eprintln!(
+        "Will run {} {}",
+        hint.guidance.bootstrapper_url(),
+        hint.bootstrapper_args(mode).join(" ")
+    );
+    if !args.has_flag("--yes") && !confirm() {
+        eprintln!("Not installing; pass --yes to install without asking");
+        std::process::exit(1);
+    }
+    if let Err(e) = hint.bootstrap(mode) {
+        fail(&e);
+    }
+}
+
+#[cfg(not(feature = "download"))]
+fn bootstrap(_args: &Args) {
+    eprintln!("bootstrap needs the download feature");
+    std::process::exit(1);
+}
+
+/// Asks on the terminal whether to go on; `false` without one
+#[cfg(feature = "download")]
+fn confirm() -> bool {
+    if !std::io::stdin().is_terminal() {
+        return false;
+    }
+    eprint!("Proceed? [y/N] ");
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).is_ok() && matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+/// `cache key` prints a key for CI caches of the cache directory, derived from
+/// the selection the other options describe. `cache prune` shrinks the cache to
+/// its size budget, or to `--max-size`.
+fn cache(args: &Args) {
+    match args.positional.get(1).map(|x| &**x) {
+        Some("key") => println!("{}", msvc_env::cache::key(&selection(args, None))),
+        Some("prune") => cache_prune(args),
+        _ => {
+            eprintln!("usage: msvc-env cache key|prune [options]");
+            std::process::exit(2);
+        }
+    }
+}
+
+fn cache_prune(args: &Args) {
+    let result = match args.option("--max-size") {
+        Some(size) => {
+            let size = msvc_env::cache::parse_size(size).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            });
+            msvc_env::cache::prune(&msvc_env::overrides::cache_dir(), size)
+        }
+        None => msvc_env::cache::prune_configured(),
+    };
+    match result {
+        Ok(pruned) => eprintln!(
+            "Removed {} entries, freeing {} KiB; {} KiB left",
+            pruned.removed.len(),
+            pruned.freed / 1024,
+            pruned.kept / 1024
+        ),
+        Err(e) => fail(&e.into()),
+    }
+}
+
+/// Emits the environment for baking into a Windows container image. Run it inside
+/// the image being built so the path check reflects the container's layout.
+fn docker_env(args: &Args) {
+    let env = resolve(args, None);
+
+    let missing = env.missing_paths();
+    for (key, path) in &missing {
+        eprintln!("warning: {} entry does not exist: {}", key, path.display());
+    }
+    if !missing.is_empty() && args.has_flag("--strict") {
+        std::process::exit(1);
+    }
+
+    if args.has_flag("--dockerfile") {
+        print!("{}", msvc_env::format::dockerfile_env(&env));
+    } else {
+        print!("{}", msvc_env::format::docker_env_file(&env));
+    }
+}
+
+/// Reports what each provider found and what is wrong with the result
+fn doctor(args: &Args) {
+    let report = msvc_env(args).diagnose(&selection(args, None));
+    print!("{}", report);
+    if !report.is_healthy() {
+        std::process::exit(1);
+    }
+}
+
+/// `exec [options] -- <command> [args]` runs a command in the environment and
+/// exits the way it did. `--cwd` sets its working directory, and each
+/// `-e KEY=VALUE` a variable on top of the environment.
+fn exec(args: &Args) {
+    const USAGE: &str =
+        "usage: msvc-env exec [--cwd <dir>] [-e KEY=VALUE]... [options] -- <command> [args]";
+    let Some((program, program_args)) = args.trailing.split_first() else {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    };
+    let extra = args
+        .option_values("-e")
+        .map(|x| {
+            x.split_once('=')
+                .filter(|(key, _)| !key.is_empty())
+                .unwrap_or_else(|| {
+                    eprintln!("-e expects KEY=VALUE, got {:?}", x);
+                    std::process::exit(2);
+                })
+        })
         .collect::<Vec<_>>();
-    let arch = args
-        .iter()
-        .skip(1)
-        .filter(|arg| !arg.starts_with("-"))
-        .next();
-
-    // Get architecture from command line args or default to X64
-    let arch = arch
-        .map(|arg| match arg.to_lowercase().as_str() {
-            "x64" => msvc_env::MsvcArch::X64,
-            "x86" => msvc_env::MsvcArch::X86,
-            "arm" => msvc_env::MsvcArch::Arm,
-            "arm64" => msvc_env::MsvcArch::Arm64,
-            "all" => msvc_env::MsvcArch::All,
-            _ => {
-                eprintln!(
-                    "Invalid architecture: {}. Supported architectures: x64, x86, arm, arm64, all",
-                    arg
-                );
-                std::process::exit(1);
+    let cwd = args.option("--cwd");
+    if let Some(cwd) = cwd.filter(|x| !Path::new(x).is_dir()) {
+        eprintln!("--cwd {} is not a directory", cwd);
+        std::process::exit(2);
+    }
+
+    let mut env = resolve(args, None);
+    env.mark_active();
+    let mut command = std::process::Command::new(program);
+    command.args(program_args).envs(&env.vars).envs(extra);
+    if let Some(cwd) = cwd {
+        command.current_dir(cwd);
+    }
+    match msvc_env::exec::run(&mut command) {
+        Ok(status) => msvc_env::exec::exit_with(status),
+        Err(e) => {
+            eprintln!("{}: {}", program, e);
+            // As shells do for commands that cannot be found or started
+            let code = if e.kind() == std::io::ErrorKind::NotFound {
+                127
+            } else {
+                126
+            };
+            std::process::exit(code);
+        }
+    }
+}
+
+/// `list` prints every installation, newest first
+fn list() {
+    let installations = MsvcEnv::new().installations().unwrap_or_else(|e| fail(&e));
+    for installation in installations {
+        let id = installation.instance_id.as_deref().unwrap_or("-");
+        let preview = if installation.prerelease {
+            " (preview)"
+        } else {
+            ""
+        };
+        let broken = if installation.is_usable() {
+            ""
+        } else {
+            " (incomplete)"
+        };
+        println!("{}  {}{}{}", id, installation, preview, broken);
+    }
+}
+
+/// Prints the commands that install what the selection needs on this machine;
+/// `--products` picks the product to install, Build Tools by default
+fn install_hint(args: &Args) {
+    let mut selection = selection(args, None);
+    if let Some(product) = args.option("--products") {
+        let product = msvc_env::install::parse_product(product).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+        selection = selection.product_id(msvc_env::install::product_id(product));
+    }
+    match msvc_env::install::InstallHint::for_selection(&selection) {
+        Ok(Some(hint)) => println!("{}", hint),
+        Ok(None) => eprintln!("Nothing to install for {}", selection.arch),
+        Err(e) => fail(&e),
+    }
+}
+
+/// Prints a PowerShell module with `Enter-MsvcEnv` and `Exit-MsvcEnv` that call
+/// this executable
+fn powershell_module() {
+    let exe = env::current_exe().unwrap_or_else(|e| {
+        eprintln!("Cannot locate this executable: {}", e);
+        std::process::exit(1);
+    });
+    print!("{}", msvc_env::format::powershell_module(&exe));
+}
+
+/// `prewarm [--all] [options]` captures the environment the options select, or
+/// with `--all` those of every installed target, into the cache directory so
+/// later runs start without running any setup script
+fn prewarm(args: &Args) {
+    let mut selection = selection(args, None);
+    if args.has_flag("--all") {
+        selection.arch = MsvcArch::All;
+    }
+    match msvc_env(args).prewarm(&selection) {
+        Ok(paths) => {
+            for path in paths {
+                println!("{}", path.display());
             }
-        })
-        .unwrap_or(msvc_env::MsvcArch::X64);
+        }
+        Err(e) => fail(&e),
+    }
+}
 
-    let env = MsvcEnv.environment(arch).unwrap();
-    let env_vars = env.vars;
+/// Prints the active environment as `<arch>/<toolset>` for prompts, failing if
+/// there is none
+fn status() {
+    match msvc_env::status::active() {
+        Some(active) => println!("{}", active),
+        None => {
+            eprintln!("no MSVC environment is active");
+            std::process::exit(1);
+        }
+    }
+}
 
-    if flags.contains(&"-v") {
-        eprintln!("Environment: {:#?}", env_vars);
+/// `snapshot save <file>` writes the resolved environment to a JSON file, or a
+/// dotenv file for any other extension; `snapshot apply <file>` prints a saved
+/// one the way `print` does
+fn snapshot(args: &Args) {
+    let (Some(command), Some(path)) = (args.positional.get(1), args.positional.get(2)) else {
+        eprintln!("usage: msvc-env snapshot save|apply <file> [options]");
+        std::process::exit(2);
+    };
+    let path = Path::new(path);
+    let result = match command.as_str() {
+        "save" => {
+            let env = resolve(args, None);
+            msvc_env::snapshot::save(&env, path)
+        }
+        "apply" => msvc_env::snapshot::load(path).map(|env| emit(args, env)),
+        _ => {
+            eprintln!("usage: msvc-env snapshot save|apply <file> [options]");
+            std::process::exit(2);
+        }
+    };
+    if let Err(e) = result {
+        fail(&e);
     }
+}
 
-    let is_shell = flags.contains(&"--sh");
+fn print(args: &Args, arch: Option<&String>) {
+    let selection = selection(args, arch);
+    if selection.arch == MsvcArch::All {
+        return print_all(args, &selection);
+    }
+    let env = resolve(args, arch);
+    emit(args, env);
+}
+
+/// `--arch all` prints one JSON document with every target's environment
+fn print_all(args: &Args, selection: &Selection) {
+    if output_format(args) != OutputFormat::Json {
+        eprintln!("--arch all needs --format json");
+        std::process::exit(1);
+    }
+    let envs = msvc_env(args)
+        .resolve_all(selection)
+        .unwrap_or_else(|e| fail_for(&e, selection.arch));
+    print!("{}", json_by_arch(&envs));
+}
+
+#[cfg(feature = "serde")]
+fn json_by_arch(envs: &IndexMap<MsvcArch, MsvcEnvironment>) -> String {
+    msvc_env::format::json_by_arch(envs)
+}
+
+#[cfg(not(feature = "serde"))]
+fn json_by_arch(_envs: &IndexMap<MsvcArch, MsvcEnvironment>) -> String {
+    eprintln!("JSON output needs the serde feature");
+    std::process::exit(1);
+}
+
+#[cfg(feature = "serde")]
+fn json(env: &MsvcEnvironment) -> String {
+    msvc_env::format::json(env)
+}
+
+#[cfg(not(feature = "serde"))]
+fn json(_env: &MsvcEnvironment) -> String {
+    eprintln!("JSON output needs the serde feature");
+    std::process::exit(1);
+}
+
+/// Prints the script or CI messages that apply `env`
+fn emit(args: &Args, mut env: MsvcEnvironment) {
+    if args.has_flag("--node-gyp") {
+        env = msvc_env::format::node_gyp_env(&env);
+    }
+    if args.has_flag("--python") {
+        env = msvc_env::format::python_env(&env);
+    }
+    if let Some(active) = msvc_env::status::active() {
+        eprintln!(
+            "warning: replacing the MSVC environment {} that is already active",
+            active
+        );
+    }
+    env.mark_active();
+    if args.has_flag("-v") {
+        eprintln!("Environment: {:#?}", env.vars);
+    }
+
+    let format = output_format(args);
+    // CI formats carry no restore script
+    let ci = match format {
+        OutputFormat::Teamcity => Some(msvc_env::format::teamcity(&env)),
+        OutputFormat::Properties => Some(msvc_env::format::properties(&env)),
+        OutputFormat::Json => Some(json(&env)),
+        OutputFormat::Powershell | OutputFormat::Sh => None,
+    };
+    if let Some(text) = ci {
+        print!("{}", text);
+        return;
+    }
+    let restore = RestoreScript::from_process(&env);
+    let env_vars = env.vars;
+
+    let is_shell = format == OutputFormat::Sh;
+    // How the shell running the output names drives, e.g. `cygwin` for /cygdrive/c
+    let mut path_style = args
+        .option("--path-style")
+        .map(|x| {
+            x.parse::<PathStyle>().unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            })
+        })
+        .unwrap_or_default();
+    if let (PathStyle::Wsl { mount_root }, Some(root)) =
+        (&mut path_style, args.option("--wsl-mount-root"))
+    {
+        *mount_root = root.to_string();
+    }
 
     for (key, value) in env_vars {
         if !is_shell {
-            println!(
-                "${{env:{}}}={}",
-                key,
-                format!("'{}'", value).replace(r"\\", r"\")
-            );
+            println!("${{env:{}}}={}", key, powershell_quote(&value));
         } else if !(key.contains("(") || key.contains(")")) {
             if key.to_uppercase() == "PATH" {
                 println!("export OLD_PATH=\"$PATH\"");
-                println!("export PATH={:?}", unixify_path_env(&value));
+                let path = paths::to_unix_list(&value, &path_style);
+                println!("export PATH={}", paths::shell_quote(&path));
             } else {
-                println!("export {}={:?}", key, value);
+                println!("export {}={}", key, paths::shell_quote(&value));
             }
         }
     }
+
+    // Lets the caller leave the environment again, unless it keeps track itself
+    if args.has_flag("--no-restore") {
+        return;
+    }
+    if is_shell {
+        print!("{}", restore.sh(&path_style));
+    } else {
+        print!("{}", restore.powershell());
+    }
 }
@@ -0,0 +1,200 @@
+//! How an environment was resolved, for explaining slow or surprising setups
+
+use crate::{MsvcEnvironment, VsVersion};
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// A Visual Studio installation an environment was set up from. What vswhere
+/// did not report is `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct VsInstallation {
+    pub path: PathBuf,
+    pub version: Option<VsVersion>,
+    /// e.g. `Microsoft.VisualStudio.Product.BuildTools`
+    pub product_id: Option<String>,
+    /// vswhere's `instanceId`, e.g. `2a4c8d6e`
+    pub instance_id: Option<String>,
+    /// e.g. `Visual Studio Build Tools 2022`
+    pub display_name: Option<String>,
+    /// e.g. `VisualStudio.17.Release`
+    pub channel_id: Option<String>,
+    /// A preview installation
+    pub prerelease: bool,
+    /// Only registered the way installations before Visual Studio 2017 were,
+    /// and found through [`Selection::legacy`](crate::Selection::legacy) or in
+    /// the registry
+    pub legacy: bool,
+    /// The release year in the product name, e.g. `2022`
+    pub product_line_version: Option<String>,
+    /// When it was installed
+    pub install_date: Option<SystemTime>,
+    /// When it was last updated
+    pub update_date: Option<SystemTime>,
+    /// `false` while an install or update is in progress or after one failed
+    pub complete: Option<bool>,
+    /// `false` if the installer found it broken, e.g. with files missing
+    pub launchable: Option<bool>,
+}
+
+impl VsInstallation {
+    /// The installation `env` names in `VSINSTALLDIR`, with the version of its
+    /// developer prompt scripts
+    pub(crate) fn from_environment(env: &MsvcEnvironment) -> Option<Self> {
+        Some(Self {
+            path: PathBuf::from(env.get("VSINSTALLDIR")?.trim_end_matches('\\')),
+            version: env.vscmd().and_then(|x| x.version),
+            ..Default::default()
+        })
+    }
+
+    /// Whether setup scripts can be expected to work, which they may not while
+    /// the installer reports it incomplete or not launchable. Unknown counts as
+    /// usable.
+    pub fn is_usable(&self) -> bool {
+        self.complete != Some(false) && self.launchable != Some(false)
+    }
+
+    /// The product ID without its `Microsoft.VisualStudio.Product.` prefix, e.g.
+    /// `BuildTools`
+    pub fn product(&self) -> Option<&str> {
+        self.product_id.as_deref().map(|x| {
+            x.strip_prefix("Microsoft.VisualStudio.Product.")
+                .unwrap_or(x)
+        })
+    }
+}
+
+impl MsvcEnvironment {
+    /// Records the installation this environment was set up from, for providers
+    /// that know more about it than `VSINSTALLDIR` tells
+    pub fn installed_from(mut self, installation: VsInstallation) -> Self {
+        self.meta.installation = Some(installation);
+        self
+    }
+}
+
+/// e.g. `Visual Studio 17.10.3 BuildTools at C:\...`
+impl fmt::Display for VsInstallation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Visual Studio")?;
+        if let Some(version) = &self.version {
+            write!(f, " {}", version)?;
+        }
+        if let Some(product) = self.product() {
+            write!(f, " {}", product)?;
+        }
+        write!(f, " at {}", self.path.display())?;
+        if self.legacy {
+            f.write_str(" (legacy)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether [`MsvcEnv::resolve`](crate::MsvcEnv::resolve) could use a cached
+/// environment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+    /// An entry existed but its installation had changed, see
+    /// [`MsvcEnvBuilder::revalidate_cache`](crate::MsvcEnvBuilder::revalidate_cache)
+    Stale,
+    /// Read from the cache directory, see [`MsvcEnv::prewarm`](crate::MsvcEnv::prewarm)
+    Disk,
+}
+
+impl fmt::Display for CacheStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CacheStatus::Hit => "hit",
+            CacheStatus::Miss => "miss",
+            CacheStatus::Stale => "stale",
+            CacheStatus::Disk => "disk",
+        })
+    }
+}
+
+/// How an environment was resolved. Environments that did not come from
+/// [`MsvcEnv::resolve`](crate::MsvcEnv::resolve), such as loaded snapshots,
+/// have the default with every field unset.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct EnvironmentMeta {
+    /// Name of the provider that produced the environment
+    pub provider: Option<String>,
+    pub cache: Option<CacheStatus>,
+    /// Time spent in `resolve`, including discovery and capture on a cache miss
+    pub duration: Option<Duration>,
+    /// The Visual Studio installation the environment was set up from
+    pub installation: Option<VsInstallation>,
+    /// The x64-hosted compilers are used because the requested host had none,
+    /// see [`Selection::host_fallback`](crate::Selection::host_fallback)
+    pub host_fallback: bool,
+}
+
+impl fmt::Display for EnvironmentMeta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "resolved by {}",
+            self.provider.as_deref().unwrap_or("unknown provider")
+        )?;
+        if let Some(duration) = self.duration {
+            write!(f, " in {:.2?}", duration)?;
+        }
+        if let Some(cache) = self.cache {
+            write!(f, " (cache {})", cache)?;
+        }
+        if let Some(installation) = &self.installation {
+            write!(f, " from {}", installation)?;
+        }
+        if self.host_fallback {
+            f.write_str(", using x64-hosted compilers")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_meta() {
+        assert_eq!(
+            EnvironmentMeta::default().to_string(),
+            "resolved by unknown provider"
+        );
+        let meta = EnvironmentMeta {
+            provider: Some("vswhere".to_string()),
+            cache: Some(CacheStatus::Miss),
+            duration: Some(Duration::from_millis(1250)),
+            installation: Some(VsInstallation {
+                path: PathBuf::from(r"C:\VS"),
+                version: Some("17.10.3".parse().unwrap()),
+                product_id: Some("Microsoft.VisualStudio.Product.BuildTools".to_string()),
+                ..Default::default()
+            }),
+            host_fallback: false,
+        };
+        assert_eq!(
+            meta.to_string(),
+            r"resolved by vswhere in 1.25s (cache miss) from Visual Studio 17.10.3 BuildTools at C:\VS"
+        );
+
+        let installation = VsInstallation::from_environment(
+            &crate::MockProvider::fake_environment(crate::MsvcArch::X64),
+        )
+        .unwrap();
+        assert_eq!(installation.product(), None);
+        assert_eq!(
+            installation.to_string(),
+            r"Visual Studio 17.10.3 at C:\Program Files\Microsoft Visual Studio\2022\BuildTools"
+        );
+    }
+}
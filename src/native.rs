@@ -0,0 +1,130 @@
+//! Native Windows APIs used with the `windows-sys` feature, next to the shell
+//! and environment based fallbacks used without it
+
+use crate::MsvcArch;
+use std::process::Command;
+
+impl MsvcArch {
+    /// The architecture of this machine, which compilers running natively on it
+    /// are built for. With the `windows-sys` feature this is asked of Windows,
+    /// so an x64 build emulated on ARM64 gets `Arm64`; otherwise it is read from
+    /// `PROCESSOR_ARCHITEW6432` and `PROCESSOR_ARCHITECTURE`, which report the
+    /// emulated architecture. Outside Windows this is the one this crate was
+    /// built for.
+    pub fn host() -> MsvcArch {
+        native_host()
+            .or_else(|| env_host(|key| std::env::var(key).ok()))
+            .unwrap_or(match std::env::consts::ARCH {
+                "x86" => MsvcArch::X86,
+                "arm" => MsvcArch::Arm,
+                "aarch64" => MsvcArch::Arm64,
+                _ => MsvcArch::X64,
+            })
+    }
+}
+
+#[cfg(all(windows, feature = "windows-sys"))]
+fn native_host() -> Option<MsvcArch> {
+    use windows_sys::Win32::System::SystemInformation::{
+        IMAGE_FILE_MACHINE, IMAGE_FILE_MACHINE_AMD64, IMAGE_FILE_MACHINE_ARM64,
+        IMAGE_FILE_MACHINE_ARMNT, IMAGE_FILE_MACHINE_I386,
+    };
+    use windows_sys::Win32::System::Threading::{GetCurrentProcess, IsWow64Process2};
+
+    let mut process: IMAGE_FILE_MACHINE = 0;
+    let mut machine: IMAGE_FILE_MACHINE = 0;
+    // SAFETY: both pointers are valid for writes for the duration of the call
+    if unsafe { IsWow64Process2(GetCurrentProcess(), &mut process, &mut machine) } == 0 {
+        tracing::trace!(
+            "IsWow64Process2 failed: {}",
+            std::io::Error::last_os_error()
+        );
+        return None;
+    }
+    match machine {
+        IMAGE_FILE_MACHINE_AMD64 => Some(MsvcArch::X64),
+        IMAGE_FILE_MACHINE_ARM64 => Some(MsvcArch::Arm64),
+        IMAGE_FILE_MACHINE_I386 => Some(MsvcArch::X86),
+        IMAGE_FILE_MACHINE_ARMNT => Some(MsvcArch::Arm),
+        _ => None,
+    }
+}
+
+#[cfg(not(all(windows, feature = "windows-sys")))]
+fn native_host() -> Option<MsvcArch> {
+    None
+}
+
+/// The architecture Windows reports through the environment, preferring the
+/// native one a 32-bit process sees in `PROCESSOR_ARCHITEW6432`
+fn env_host(var: impl Fn(&str) -> Option<String>) -> Option<MsvcArch> {
+    let arch = var("PROCESSOR_ARCHITEW6432").or_else(|| var("PROCESSOR_ARCHITECTURE"))?;
+    match arch.to_uppercase().as_str() {
+        "AMD64" => Some(MsvcArch::X64),
+        "ARM64" => Some(MsvcArch::Arm64),
+        "X86" => Some(MsvcArch::X86),
+        "ARM" => Some(MsvcArch::Arm),
+        _ => None,
+    }
+}
+
+/// Keeps a console child from opening a window when this process has no
+/// console, e.g. in an IDE or build server. Needs the `windows-sys` feature.
+pub(crate) fn hide_window(command: &mut Command) {
+    #[cfg(all(windows, feature = "windows-sys"))]
+    {
+        use std::os::windows::process::CommandExt;
+        command.creation_flags(windows_sys::Win32::System::Threading::CREATE_NO_WINDOW);
+    }
+    #[cfg(not(all(windows, feature = "windows-sys")))]
+    let _ = command;
+}
+
+/// The 8.3 short name of `path` from `GetShortPathNameW`; `None` if it does not
+/// exist or has no short name
+#[cfg(all(windows, feature = "windows-sys"))]
+pub(crate) fn short_path(path: &str) -> Option<String> {
+    use windows_sys::Win32::Storage::FileSystem::GetShortPathNameW;
+
+    let wide = path.encode_utf16().chain(Some(0)).collect::<Vec<u16>>();
+    let mut buf = vec![0u16; wide.len().max(260)];
+    loop {
+        // SAFETY: `wide` is NUL-terminated and `buf` holds `buf.len()` elements
+        let len = unsafe { GetShortPathNameW(wide.as_ptr(), buf.as_mut_ptr(), buf.len() as u32) };
+        match len as usize {
+            0 => return None,
+            // Too small; `len` includes the terminating NUL
+            len if len > buf.len() => buf.resize(len, 0),
+            len => return String::from_utf16(&buf[..len]).ok(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_env_host() {
+        let vars = |pairs: &'static [(&str, &str)]| {
+            move |key: &str| {
+                pairs
+                    .iter()
+                    .find(|(k, _)| *k == key)
+                    .map(|(_, v)| v.to_string())
+            }
+        };
+        assert_eq!(
+            env_host(vars(&[("PROCESSOR_ARCHITECTURE", "AMD64")])),
+            Some(MsvcArch::X64)
+        );
+        assert_eq!(
+            env_host(vars(&[
+                ("PROCESSOR_ARCHITECTURE", "x86"),
+                ("PROCESSOR_ARCHITEW6432", "ARM64")
+            ])),
+            Some(MsvcArch::Arm64)
+        );
+        assert_eq!(env_host(vars(&[])), None);
+    }
+}
@@ -0,0 +1,209 @@
+//! `MSVC_ENV_*` variables overriding the defaults of both the library and the CLI,
+//! so CI can configure toolchain selection and caching without code changes
+//!
+//! | Variable                  | Overrides                                              |
+//! |---------------------------|--------------------------------------------------------|
+//! | `MSVC_ENV_ARCH`           | target architecture when none is given                 |
+//! | `MSVC_ENV_HOST_ARCH`      | architecture of the compilers                          |
+//! | `MSVC_ENV_TOOLSET`        | MSVC toolset version or prefix                         |
+//! | `MSVC_ENV_SDK`            | Windows SDK version                                    |
+//! | `MSVC_ENV_VS_PATH`        | Visual Studio installation, skipping vswhere discovery |
+//! | `MSVC_ENV_CACHE_DIR`      | where vswhere is downloaded to                         |
+//! | `MSVC_ENV_CACHE_MAX_SIZE` | size budget of the cache directory, e.g. `500M`        |
+//! | `MSVC_ENV_OFFLINE`        | set to anything but `0` or `false` to never download   |
+//! | `MSVC_ENV_VSWHERE`        | vswhere executable to run instead of downloading one   |
+//!
+//! Empty variables are treated as unset.
+
+use crate::{MsvcArch, MsvcEnvError, Selection};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const ARCH: &str = "MSVC_ENV_ARCH";
+pub const HOST_ARCH: &str = "MSVC_ENV_HOST_ARCH";
+pub const TOOLSET: &str = "MSVC_ENV_TOOLSET";
+pub const SDK: &str = "MSVC_ENV_SDK";
+pub const VS_PATH: &str = "MSVC_ENV_VS_PATH";
+pub const CACHE_DIR: &str = "MSVC_ENV_CACHE_DIR";
+pub const CACHE_MAX_SIZE: &str = "MSVC_ENV_CACHE_MAX_SIZE";
+pub const OFFLINE: &str = "MSVC_ENV_OFFLINE";
+pub const VSWHERE: &str = "MSVC_ENV_VSWHERE";
+
+/// Used unless [`CACHE_DIR`] is set
+const DEFAULT_CACHE_DIR: &str = "target/msvc-env-cache";
+
+pub(crate) fn var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|x| !x.is_empty())
+}
+
+pub(crate) fn offline() -> bool {
+    var(OFFLINE).is_some_and(|x| x != "0" && !x.eq_ignore_ascii_case("false"))
+}
+
+/// [`CACHE_DIR`], else the `cache-dir` of the user configuration, else the
+/// default. Its contents are laid out as described in [`crate::cache`].
+pub fn cache_dir() -> PathBuf {
+    if let Some(dir) = var(CACHE_DIR) {
+        return PathBuf::from(dir);
+    }
+    #[cfg(feature = "config")]
+    if let Some(dir) = crate::config::user_cache_dir() {
+        return dir;
+    }
+    PathBuf::from(DEFAULT_CACHE_DIR)
+}
+
+/// [`CACHE_MAX_SIZE`], else the `cache-max-size` of the user configuration,
+/// else [`DEFAULT_MAX_SIZE`](crate::cache::DEFAULT_MAX_SIZE). A budget that
+/// cannot be parsed is ignored with a warning.
+pub(crate) fn cache_max_size() -> u64 {
+    let configured = var(CACHE_MAX_SIZE);
+    #[cfg(feature = "config")]
+    let configured = configured.or_else(crate::config::user_cache_max_size);
+    configured
+        .and_then(|x| {
+            crate::cache::parse_size(&x)
+                .map_err(|e| tracing::warn!("Ignoring cache size budget: {}", e))
+                .ok()
+        })
+        .unwrap_or(crate::cache::DEFAULT_MAX_SIZE)
+}
+
+/// [`cache_dir`] if it can be created and written to, else a per-user cache
+/// directory, so a read-only checkout or a binary run from Program Files still
+/// has somewhere to put vswhere
+pub(crate) fn writable_cache_dir() -> io::Result<PathBuf> {
+    first_writable(cache_dir(), fallback_cache_dir)
+}
+
+fn first_writable(dir: PathBuf, fallback: impl FnOnce() -> PathBuf) -> io::Result<PathBuf> {
+    match ensure_writable(&dir) {
+        Ok(()) => Ok(dir),
+        Err(e) => {
+            let fallback = fallback();
+            tracing::debug!(
+                "Cache directory {} is not writable ({}), using {}",
+                dir.display(),
+                e,
+                fallback.display()
+            );
+            ensure_writable(&fallback)?;
+            Ok(fallback)
+        }
+    }
+}
+
+/// `%LOCALAPPDATA%\msvc-env` on Windows, `$XDG_CACHE_HOME/msvc-env` or
+/// `~/.cache/msvc-env` elsewhere, and the temporary directory as a last resort
+fn fallback_cache_dir() -> PathBuf {
+    let dir = if cfg!(windows) {
+        env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else {
+        env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|x| PathBuf::from(x).join(".cache")))
+    };
+    dir.unwrap_or_else(env::temp_dir).join("msvc-env")
+}
+
+/// Creates `dir` and checks that files can be created in it
+fn ensure_writable(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let probe = dir.join(format!(".msvc-env-probe-{}", std::process::id()));
+    fs::File::create(&probe)?;
+    fs::remove_file(&probe)
+}
+
+/// Applies the selection variables found through `lookup` to `selection`. The
+/// target architecture is only replaced if `arch` is set, since most callers
+/// name it explicitly.
+pub(crate) fn apply(
+    mut selection: Selection,
+    lookup: impl Fn(&str) -> Option<String>,
+    arch: bool,
+) -> Result<Selection, MsvcEnvError> {
+    let parse_arch = |name: &str, value: String| {
+        value.parse::<MsvcArch>().map_err(|_| {
+            MsvcEnvError::ConfigError(format!(
+                "{} is {:?}, expected one of x64, x86, arm, arm64, all",
+                name, value
+            ))
+        })
+    };
+
+    if arch && let Some(value) = lookup(ARCH) {
+        selection.arch = parse_arch(ARCH, value)?;
+    }
+    if let Some(value) = lookup(HOST_ARCH) {
+        selection.host_arch = parse_arch(HOST_ARCH, value)?;
+    }
+    if let Some(value) = lookup(TOOLSET) {
+        let toolset = value
+            .parse()
+            .map_err(|e| MsvcEnvError::ConfigError(format!("{}: {}", TOOLSET, e)))?;
+        selection.toolset = Some(toolset);
+    }
+    if let Some(value) = lookup(SDK) {
+        let sdk = value
+            .parse()
+            .map_err(|e| MsvcEnvError::ConfigError(format!("{}: {}", SDK, e)))?;
+        selection.sdk = Some(sdk);
+    }
+    Ok(selection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let lookup = |name: &str| match name {
+            ARCH => Some("arm64".to_string()),
+            TOOLSET => Some("14.29".to_string()),
+            _ => None,
+        };
+
+        let selection = apply(
+            Selection::new(MsvcArch::X86).sdk("10.0".parse().unwrap()),
+            lookup,
+            false,
+        )
+        .unwrap();
+        assert_eq!(selection.arch, MsvcArch::X86);
+        assert_eq!(selection.toolset, Some("14.29".parse().unwrap()));
+        assert_eq!(selection.sdk, Some("10.0".parse().unwrap()));
+
+        let selection = apply(Selection::new(MsvcArch::X86), lookup, true).unwrap();
+        assert_eq!(selection.arch, MsvcArch::Arm64);
+
+        let invalid = |name: &str| (name == HOST_ARCH).then(|| "amd64".to_string());
+        assert!(matches!(
+            apply(Selection::new(MsvcArch::X64), invalid, true),
+            Err(MsvcEnvError::ConfigError(message)) if message.starts_with(HOST_ARCH)
+        ));
+    }
+
+    #[test]
+    fn test_first_writable() {
+        let root = env::temp_dir().join(format!("msvc-env-cache-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let fallback = || root.join("fallback");
+
+        let dir = root.join("cache");
+        assert_eq!(first_writable(dir.clone(), fallback).unwrap(), dir);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        // A file where the directory should be can't be created over
+        let blocked = root.join("blocked");
+        fs::write(&blocked, "").unwrap();
+        assert_eq!(
+            first_writable(blocked.join("cache"), fallback).unwrap(),
+            fallback()
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,344 @@
+//! Conversion of Windows paths into the forms POSIX shells on Windows understand,
+//! for emitting environments to `sh` scripts. Each shell has its own way of
+//! naming drives, picked with [`PathStyle`]; [`shell_quote`] makes the result
+//! safe to paste into a script. [`to_windows`] goes the other way, for paths
+//! handed over from a POSIX layer.
+//!
+//! Paths are parsed as text rather than through [`std::path`], so conversion
+//! behaves the same on every host and never fails on non-UTF-8 components.
+
+use std::path::{Path, PathBuf};
+
+/// How a POSIX layer on Windows names drive `C:`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum PathStyle {
+    /// `/c/...`, used by MSYS2 and Git Bash
+    #[default]
+    Msys,
+    /// `/cygdrive/c/...`
+    Cygwin,
+    /// `/mnt/c/...` as seen from inside WSL, with the drives mounted under
+    /// `mount_root` (the `automount.root` of `wsl.conf`)
+    Wsl { mount_root: String },
+}
+
+/// Where WSL mounts drives unless `wsl.conf` says otherwise
+pub const DEFAULT_WSL_MOUNT_ROOT: &str = "/mnt";
+
+impl std::str::FromStr for PathStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "msys" | "msys2" => Ok(PathStyle::Msys),
+            "cygwin" => Ok(PathStyle::Cygwin),
+            "wsl" => Ok(PathStyle::Wsl {
+                mount_root: DEFAULT_WSL_MOUNT_ROOT.to_string(),
+            }),
+            _ => Err(format!(
+                "unknown path style {:?}, expected msys, cygwin or wsl",
+                s
+            )),
+        }
+    }
+}
+
+/// Where a Windows path is anchored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Root<'a> {
+    /// `C:\...`, `\\?\C:\...` and the drive-relative `C:...`
+    Drive(char),
+    /// `\\server\share\...` and `\\?\UNC\server\share\...`
+    Unc {
+        server: &'a str,
+        share: &'a str,
+    },
+    /// Device and volume paths such as `\\.\pipe\...` or `\\?\Volume{...}\...`,
+    /// holding the prefix up to the first separator
+    Device(&'a str),
+    /// `\...`, rooted on the current drive
+    Absolute,
+    Relative,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct WindowsPath<'a> {
+    root: Root<'a>,
+    components: Vec<&'a str>,
+}
+
+fn is_separator(c: char) -> bool {
+    c == '\\' || c == '/'
+}
+
+/// Splits `path` at the first separator
+fn next_component(path: &str) -> (&str, &str) {
+    path.split_once(is_separator).unwrap_or((path, ""))
+}
+
+fn drive_letter(path: &str) -> Option<char> {
+    let mut chars = path.chars();
+    match (chars.next(), chars.next()) {
+        (Some(letter), Some(':')) if letter.is_ascii_alphabetic() => {
+            Some(letter.to_ascii_lowercase())
+        }
+        _ => None,
+    }
+}
+
+fn parse(path: &str) -> WindowsPath<'_> {
+    let (root, rest) = if let Some(verbatim) = path
+        .strip_prefix(r"\\?\")
+        .or_else(|| path.strip_prefix("//?/"))
+    {
+        if let Some(letter) = drive_letter(verbatim) {
+            (Root::Drive(letter), &verbatim[2..])
+        } else if verbatim
+            .get(..4)
+            .is_some_and(|x| x.eq_ignore_ascii_case(r"UNC\"))
+        {
+            let (server, rest) = next_component(&verbatim[4..]);
+            let (share, rest) = next_component(rest);
+            (Root::Unc { server, share }, rest)
+        } else {
+            let end = 4 + verbatim.find(is_separator).unwrap_or(verbatim.len());
+            (Root::Device(&path[..end]), &path[end..])
+        }
+    } else if path.starts_with(r"\\.\") || path.starts_with("//./") {
+        let end = 4 + path[4..].find(is_separator).unwrap_or(path.len() - 4);
+        (Root::Device(&path[..end]), &path[end..])
+    } else if path.len() > 2 && path.chars().take(2).all(is_separator) {
+        let (server, rest) = next_component(&path[2..]);
+        let (share, rest) = next_component(rest);
+        (Root::Unc { server, share }, rest)
+    } else if let Some(letter) = drive_letter(path) {
+        (Root::Drive(letter), &path[2..])
+    } else if path.starts_with(is_separator) {
+        (Root::Absolute, path)
+    } else {
+        (Root::Relative, path)
+    };
+
+    WindowsPath {
+        root,
+        components: rest.split(is_separator).filter(|x| !x.is_empty()).collect(),
+    }
+}
+
+/// Converts a Windows path to the form `style` uses. UNC paths become
+/// `//server/share/...` and verbatim `\\?\` prefixes are removed where they
+/// name a drive or share. Drive-relative paths like `C:foo` are taken relative
+/// to the drive root, which is what a process without a remembered directory on
+/// that drive sees.
+pub fn to_unix(path: &Path, style: &PathStyle) -> String {
+    let path = path.to_string_lossy();
+    let parsed = parse(&path);
+
+    let mut unix = match parsed.root {
+        Root::Drive(letter) => match style {
+            PathStyle::Msys => format!("/{}", letter),
+            PathStyle::Cygwin => format!("/cygdrive/{}", letter),
+            PathStyle::Wsl { mount_root } => {
+                format!("{}/{}", mount_root.trim_end_matches('/'), letter)
+            }
+        },
+        Root::Unc { server, share } => format!("//{}/{}", server, share),
+        Root::Device(device) => device.replace('\\', "/"),
+        Root::Absolute => String::new(),
+        Root::Relative => {
+            return parsed.components.join("/");
+        }
+    };
+    for component in parsed.components {
+        unix.push('/');
+        unix.push_str(component);
+    }
+    if unix.is_empty() {
+        unix.push('/');
+    }
+    unix
+}
+
+/// Converts a `;`-separated list such as `PATH` to a `:`-separated list of
+/// [`to_unix`] paths
+pub fn to_unix_list(list: &str, style: &PathStyle) -> String {
+    list.split(';')
+        .filter(|x| !x.is_empty())
+        .map(|x| to_unix(Path::new(x), style))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Converts a POSIX path in the form `style` uses back to a Windows path, the
+/// inverse of [`to_unix`]: `/c/foo` becomes `C:\foo` and `//server/share` a UNC
+/// path. Paths outside the drive mounts, such as `/usr/bin`, only exist inside
+/// the POSIX layer and keep their shape with backslashes.
+pub fn to_windows(path: &str, style: &PathStyle) -> PathBuf {
+    let mount_root = match style {
+        PathStyle::Msys => "",
+        PathStyle::Cygwin => "/cygdrive",
+        PathStyle::Wsl { mount_root } => mount_root.trim_end_matches('/'),
+    };
+    let join = |path: &str| {
+        path.split('/')
+            .filter(|x| !x.is_empty())
+            .collect::<Vec<_>>()
+            .join("\\")
+    };
+
+    let drive = path
+        .strip_prefix(mount_root)
+        .and_then(|x| x.strip_prefix('/'))
+        .map(next_component)
+        .filter(|(letter, _)| letter.len() == 1 && letter.chars().all(|c| c.is_ascii_alphabetic()));
+    let windows = if let Some((letter, rest)) = drive {
+        format!("{}:\\{}", letter.to_ascii_uppercase(), join(rest))
+    } else if let Some(unc) = path.strip_prefix("//") {
+        format!("\\\\{}", join(unc))
+    } else if path.starts_with('/') {
+        format!("\\{}", join(path))
+    } else {
+        join(path)
+    };
+    PathBuf::from(windows)
+}
+
+/// Converts a `:`-separated list of POSIX paths to a `;`-separated list of
+/// [`to_windows`] paths
+pub fn to_windows_list(list: &str, style: &PathStyle) -> String {
+    list.split(':')
+        .filter(|x| !x.is_empty())
+        .map(|x| to_windows(x, style).to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// Quotes `value` as a single POSIX shell word. Anything beyond plain path
+/// characters is wrapped in single quotes, inside which only `'` itself needs
+/// escaping, so spaces, parentheses, `&`, `$`, quotes and non-ASCII text all
+/// survive.
+pub fn shell_quote(value: &str) -> String {
+    let plain = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "/._-+:,=@%".contains(c));
+    if plain {
+        return value.to_string();
+    }
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unix(path: &str) -> String {
+        to_unix(Path::new(path), &PathStyle::Msys)
+    }
+
+    #[test]
+    fn test_to_unix() {
+        assert_eq!(
+            unix(r"C:\Program Files (x86)\Windows Kits"),
+            "/c/Program Files (x86)/Windows Kits"
+        );
+        assert_eq!(unix(r"D:"), "/d");
+        assert_eq!(unix(r"C:foo\bar"), "/c/foo/bar");
+        assert_eq!(unix(r"\\?\C:\VC\bin"), "/c/VC/bin");
+        assert_eq!(unix(r"\\buildsrv\tools\VC"), "//buildsrv/tools/VC");
+        assert_eq!(unix(r"\\?\UNC\buildsrv\tools\VC"), "//buildsrv/tools/VC");
+        assert_eq!(unix(r"\\?\Volume{0e8c}\VC"), "//?/Volume{0e8c}/VC");
+        assert_eq!(unix(r"\\?\ééé\VC"), "//?/ééé/VC");
+        assert_eq!(unix(r"\\.\pipe\build"), "//./pipe/build");
+        assert_eq!(unix(r"\Windows\System32"), "/Windows/System32");
+        assert_eq!(unix(r"..\include"), "../include");
+
+        assert_eq!(
+            to_unix_list(r"C:\VC\bin;;\\srv\share\bin;", &PathStyle::Msys),
+            "/c/VC/bin://srv/share/bin"
+        );
+    }
+
+    #[test]
+    fn test_to_unix_cygwin() {
+        let cygwin = |path| to_unix(Path::new(path), &PathStyle::Cygwin);
+        assert_eq!(cygwin(r"C:\VC\bin"), "/cygdrive/c/VC/bin");
+        assert_eq!(cygwin(r"\\?\D:\"), "/cygdrive/d");
+        assert_eq!(cygwin(r"\\srv\share\bin"), "//srv/share/bin");
+        assert_eq!("Cygwin".parse::<PathStyle>(), Ok(PathStyle::Cygwin));
+        assert!("wsl1".parse::<PathStyle>().is_err());
+    }
+
+    #[test]
+    fn test_to_unix_wsl() {
+        let wsl = "wsl".parse::<PathStyle>().unwrap();
+        assert_eq!(
+            to_unix(Path::new(r"C:\Program Files\VC"), &wsl),
+            "/mnt/c/Program Files/VC"
+        );
+        assert_eq!(to_unix_list(r"C:\VC;D:\", &wsl), "/mnt/c/VC:/mnt/d");
+
+        let root = PathStyle::Wsl {
+            mount_root: "/".to_string(),
+        };
+        assert_eq!(to_unix(Path::new(r"E:\src"), &root), "/e/src");
+    }
+
+    #[test]
+    fn test_to_windows() {
+        let windows = |path, style| to_windows(path, style).to_string_lossy().into_owned();
+        let wsl = "wsl".parse::<PathStyle>().unwrap();
+
+        assert_eq!(windows("/c/VC/bin", &PathStyle::Msys), r"C:\VC\bin");
+        assert_eq!(windows("/d", &PathStyle::Msys), r"D:\");
+        assert_eq!(windows("/cygdrive/c/VC/", &PathStyle::Cygwin), r"C:\VC");
+        assert_eq!(windows("/mnt/c/Program Files", &wsl), r"C:\Program Files");
+        assert_eq!(windows("/mnt/c/VC", &PathStyle::Msys), r"\mnt\c\VC");
+        assert_eq!(windows("/usr/bin", &PathStyle::Cygwin), r"\usr\bin");
+        assert_eq!(windows("//srv/share/bin", &wsl), r"\\srv\share\bin");
+        assert_eq!(windows("../include", &wsl), r"..\include");
+
+        let list = r"C:\Program Files (x86)\Kits;\\srv\share\bin";
+        for style in [PathStyle::Msys, PathStyle::Cygwin, wsl] {
+            assert_eq!(to_windows_list(&to_unix_list(list, &style), &style), list);
+        }
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("/c/VC/bin:/c/Windows"), "/c/VC/bin:/c/Windows");
+        assert_eq!(
+            shell_quote(&to_unix_list(
+                r"C:\Program Files (x86)\Kits;D:\R&D\$HOME",
+                &PathStyle::Msys
+            )),
+            "'/c/Program Files (x86)/Kits:/d/R&D/$HOME'"
+        );
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote("C:\\Users\\Zoë"), "'C:\\Users\\Zoë'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn test_powershell_quote() {
+        use crate::provider::powershell_quote;
+
+        assert_eq!(powershell_quote("it's"), "'it''s'");
+        // Backslashes are literal inside single quotes, doubled ones included
+        assert_eq!(
+            powershell_quote(r"\\srv\share\O'Brien"),
+            r"'\\srv\share\O''Brien'"
+        );
+        assert_eq!(powershell_quote("$env:PATH"), "'$env:PATH'");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_to_unix_non_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new(OsStr::from_bytes(b"C:\\VC\\\xff"));
+        assert_eq!(to_unix(path, &PathStyle::Msys), "/c/VC/\u{fffd}");
+    }
+}
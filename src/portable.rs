@@ -0,0 +1,462 @@
+//! Downloads the MSVC toolset and Windows SDK straight from the Visual Studio
+//! release manifests into a local directory, for machines without Visual Studio.
+//!
+//! Toolset packages are VSIX archives that are unpacked directly. SDK packages are
+//! MSIs, which are unpacked with `msiexec /a` after fetching the cabinets they reference.
+//! Every download is checked against the SHA-256 the manifest lists for it.
+
+use crate::layout::{ToolchainLayout, version_key};
+use crate::version::SdkVersion;
+use crate::{
+    MsvcArch, MsvcEnvError, MsvcEnvProvider, MsvcEnvironment, Selection, Stage, capture, download,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const CHANNEL_URL: &str = "https://aka.ms/vs/17/release/channel";
+const VS_MANIFEST_ID: &str = "Microsoft.VisualStudio.Manifests.VisualStudio";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Channel {
+    channel_items: Vec<ChannelItem>,
+}
+
+#[derive(Deserialize)]
+struct ChannelItem {
+    id: String,
+    #[serde(default)]
+    payloads: Vec<Payload>,
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    packages: Vec<Package>,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    id: String,
+    language: Option<String>,
+    #[serde(default)]
+    payloads: Vec<Payload>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Payload {
+    file_name: String,
+    url: String,
+    sha256: String,
+}
+
+impl Payload {
+    /// Downloads the payload into memory, checking its hash
+    fn download_bytes(&self) -> Result<Vec<u8>, MsvcEnvError> {
+        let bytes = download::download_bytes(&self.url)?;
+        self.check(&format!("{:x}", Sha256::digest(&bytes)))?;
+        Ok(bytes)
+    }
+
+    /// Downloads the payload to `dest`, which is removed again if its hash does
+    /// not match
+    fn download_file(&self, dest: &Path) -> Result<(), MsvcEnvError> {
+        download::download_file(&self.url, dest)?;
+        self.check_file(dest)
+    }
+
+    /// Checks the hash of the downloaded `path`, removing it on a mismatch
+    fn check_file(&self, path: &Path) -> Result<(), MsvcEnvError> {
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+        let checked = self.check(&format!("{:x}", hasher.finalize()));
+        if checked.is_err() {
+            fs::remove_file(path)?;
+        }
+        checked
+    }
+
+    fn check(&self, digest: &str) -> Result<(), MsvcEnvError> {
+        if digest.eq_ignore_ascii_case(&self.sha256) {
+            return Ok(());
+        }
+        Err(MsvcEnvError::DownloadError(format!(
+            "{} has SHA-256 {}, but the manifest lists {}",
+            self.file_name, digest, self.sha256
+        )))
+    }
+}
+
+/// A toolset and SDK downloaded into `root`. Usable as a provider, in which case
+/// missing packages are fetched on first use.
+#[derive(Debug, Clone)]
+pub struct PortableToolchain {
+    root: PathBuf,
+    license_accepted: bool,
+}
+
+impl PortableToolchain {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            license_accepted: false,
+        }
+    }
+
+    /// Confirms acceptance of the Visual Studio and Windows SDK license terms,
+    /// which is required before anything is downloaded
+    pub fn accept_license(mut self, accepted: bool) -> Self {
+        self.license_accepted = accepted;
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Downloads the toolset and SDK packages for an x64-hosted `arch` build,
+    /// unless they have been fetched before
+    pub fn fetch(&self, arch: MsvcArch) -> Result<(), MsvcEnvError> {
+        let marker = self
+            .root
+            .join(format!(".msvc-env-portable-{}", arch.as_str()));
+        if marker.exists() {
+            tracing::trace!("Portable toolchain for {} already present", arch);
+            return Ok(());
+        }
+
+        if !self.license_accepted {
+            return Err(MsvcEnvError::DownloadError(
+                "the Visual Studio license must be accepted before downloading a portable toolchain"
+                    .to_string(),
+            ));
+        }
+
+        let targets = manifest_targets(arch)?;
+        let manifest = fetch_manifest()?;
+        let downloads = self.root.join(".downloads");
+        fs::create_dir_all(&downloads)?;
+
+        let toolset = latest_toolset(&manifest)?;
+        tracing::trace!("Fetching MSVC toolset {}", toolset);
+        let mut ids = vec![
+            format!("Microsoft.VC.{}.CRT.Headers.base", toolset),
+            format!("Microsoft.VC.{}.Tools.HostX64.TargetX64.base", toolset),
+            format!("Microsoft.VC.{}.Tools.HostX64.TargetX64.Res.base", toolset),
+        ];
+        if arch != MsvcArch::X64 {
+            ids.push(format!(
+                "Microsoft.VC.{}.Tools.HostX64.Target{}.base",
+                toolset, targets.tools
+            ));
+            ids.push(format!(
+                "Microsoft.VC.{}.Tools.HostX64.Target{}.Res.base",
+                toolset, targets.tools
+            ));
+        }
+        ids.push(format!(
+            "Microsoft.VC.{}.CRT.{}.Desktop.base",
+            toolset, targets.crt
+        ));
+
+        for id in &ids {
+            let package = find_package(&manifest, id)?;
+            for payload in &package.payloads {
+                self.extract_vsix(&payload.download_bytes()?)?;
+            }
+        }
+
+        let sdk = latest_sdk(&manifest)?;
+        tracing::trace!("Fetching Windows SDK {}", sdk.id);
+        let msis = [
+            "Windows SDK for Windows Store Apps Headers-x86_en-us.msi".to_string(),
+            "Windows SDK for Windows Store Apps Libs-x86_en-us.msi".to_string(),
+            "Universal CRT Headers Libraries and Sources-x86_en-us.msi".to_string(),
+            "Windows SDK Desktop Tools x64-x86_en-us.msi".to_string(),
+            format!("Windows SDK Desktop Headers {}-x86_en-us.msi", targets.sdk),
+            format!("Windows SDK Desktop Libs {}-x86_en-us.msi", targets.sdk),
+        ];
+        for msi in &msis {
+            self.install_msi(sdk, msi, &downloads)?;
+        }
+
+        fs::write(&marker, toolset)?;
+        Ok(())
+    }
+
+    /// Unpacks the `Contents` directory of a VSIX package into the root
+    fn extract_vsix(&self, bytes: &[u8]) -> Result<(), MsvcEnvError> {
+        let mut archive = zip::ZipArchive::new(Cursor::new(bytes))
+            .map_err(|e| MsvcEnvError::DownloadError(e.to_string()))?;
+
+        for i in 0..archive.len() {
+            let mut file = archive
+                .by_index(i)
+                .map_err(|e| MsvcEnvError::DownloadError(e.to_string()))?;
+            let Some(name) = file.enclosed_name() else {
+                continue;
+            };
+            let Ok(relative) = name.strip_prefix("Contents") else {
+                continue;
+            };
+            if file.is_dir() {
+                continue;
+            }
+
+            let dest = self.root.join(relative);
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            std::io::copy(&mut file, &mut fs::File::create(&dest)?)?;
+        }
+
+        Ok(())
+    }
+
+    /// Downloads an SDK MSI and the cabinets it references, then performs an
+    /// administrative install into the root
+    fn install_msi(
+        &self,
+        sdk: &Package,
+        file_name: &str,
+        downloads: &Path,
+    ) -> Result<(), MsvcEnvError> {
+        let payload = sdk
+            .payloads
+            .iter()
+            .find(|p| p.file_name.rsplit('\\').next() == Some(file_name))
+            .ok_or_else(|| {
+                MsvcEnvError::ParseError(format!("{} not found in {}", file_name, sdk.id))
+            })?;
+
+        let msi_path = downloads.join(file_name);
+        payload.download_file(&msi_path)?;
+
+        let mut package = msi::open(&msi_path)?;
+        let cabinets = package
+            .select_rows(msi::Select::table("Media"))?
+            .filter_map(|row| row["Cabinet"].as_str().map(str::to_string))
+            .filter(|cab| !cab.starts_with('#'))
+            .collect::<Vec<_>>();
+
+        for cab in cabinets {
+            let payload = sdk
+                .payloads
+                .iter()
+                .find(|p| p.file_name.rsplit('\\').next() == Some(cab.as_str()))
+                .ok_or_else(|| {
+                    MsvcEnvError::ParseError(format!("{} not found in {}", cab, sdk.id))
+                })?;
+            payload.download_file(&downloads.join(&cab))?;
+        }
+
+        let mut command = Command::new("msiexec");
+        let output = command
+            .arg("/a")
+            .arg(&msi_path)
+            .arg("/qn")
+            .arg(format!("TARGETDIR={}", self.root.display()))
+            .output()?;
+        if !output.status.success() {
+            return Err(capture::process_failed(Stage::Download, &command, &output));
+        }
+
+        Ok(())
+    }
+
+    /// Builds the environment from the downloaded files without fetching anything
+    pub fn installed_environment(&self, arch: MsvcArch) -> Result<MsvcEnvironment, MsvcEnvError> {
+        ToolchainLayout::detect(&self.root)?.environment(arch)
+    }
+}
+
+impl MsvcEnvProvider for PortableToolchain {
+    fn name(&self) -> &str {
+        "portable"
+    }
+
+    fn environment(&self, selection: &Selection) -> Result<MsvcEnvironment, MsvcEnvError> {
+        self.fetch(selection.arch)?;
+        ToolchainLayout::detect(&self.root)?
+            .pin(selection)?
+            .environment(selection.arch)
+    }
+}
+
+/// How each part of the manifest spells a target architecture
+struct ManifestTargets {
+    tools: &'static str,
+    crt: &'static str,
+    sdk: &'static str,
+}
+
+fn manifest_targets(arch: MsvcArch) -> Result<ManifestTargets, MsvcEnvError> {
+    let (tools, crt, sdk) = match arch {
+        MsvcArch::X64 => ("X64", "x64", "x64"),
+        MsvcArch::X86 => ("X86", "x86", "x86"),
+        MsvcArch::Arm => ("ARM", "ARM", "arm"),
+        MsvcArch::Arm64 => ("ARM64", "ARM64", "arm64"),
+        MsvcArch::All => {
+            return Err(MsvcEnvError::ArchNotSupported(
+                arch,
+                "a single target architecture".to_string(),
+            ));
+        }
+    };
+    Ok(ManifestTargets { tools, crt, sdk })
+}
+
+fn fetch_manifest() -> Result<Manifest, MsvcEnvError> {
+    let channel: Channel = serde_json::from_slice(&download::download_bytes(CHANNEL_URL)?)
+        .map_err(|e| MsvcEnvError::ParseError(e.to_string()))?;
+
+    let payload = channel
+        .channel_items
+        .iter()
+        .find(|item| item.id == VS_MANIFEST_ID)
+        .and_then(|item| item.payloads.first())
+        .ok_or_else(|| MsvcEnvError::ParseError(format!("{} not in channel", VS_MANIFEST_ID)))?;
+
+    serde_json::from_slice(&payload.download_bytes()?)
+        .map_err(|e| MsvcEnvError::ParseError(e.to_string()))
+}
+
+fn find_package<'a>(manifest: &'a Manifest, id: &str) -> Result<&'a Package, MsvcEnvError> {
+    let mut candidates = manifest
+        .packages
+        .iter()
+        .filter(|p| p.id.eq_ignore_ascii_case(id));
+    let first = candidates
+        .next()
+        .ok_or_else(|| MsvcEnvError::ParseError(format!("package {} not found", id)))?;
+
+    // Resource packages exist once per language
+    Ok(std::iter::once(first)
+        .chain(candidates)
+        .find(|p| {
+            p.language
+                .as_deref()
+                .is_none_or(|l| l.eq_ignore_ascii_case("en-US"))
+        })
+        .unwrap_or(first))
+}
+
+/// Version segment of the newest `Microsoft.VC.<version>.Tools.HostX64.TargetX64.base`
+fn latest_toolset(manifest: &Manifest) -> Result<String, MsvcEnvError> {
+    manifest
+        .packages
+        .iter()
+        .filter_map(|p| {
+            p.id.strip_prefix("Microsoft.VC.")?
+                .strip_suffix(".Tools.HostX64.TargetX64.base")
+        })
+        .filter_map(|v| Some((version_key(v)?, v)))
+        .max()
+        .map(|(_, v)| v.to_string())
+        .ok_or_else(|| MsvcEnvError::ParseError("no MSVC toolset in manifest".to_string()))
+}
+
+/// The newest `Win11SDK_<version>` or `Win10SDK_<version>` package
+fn latest_sdk(manifest: &Manifest) -> Result<&Package, MsvcEnvError> {
+    manifest
+        .packages
+        .iter()
+        .filter_map(|p| {
+            let version =
+                p.id.strip_prefix("Win11SDK_")
+                    .or_else(|| p.id.strip_prefix("Win10SDK_"))?;
+            Some((version.parse::<SdkVersion>().ok()?, p))
+        })
+        .max_by(|a, b| a.0.cmp(&b.0))
+        .map(|(_, p)| p)
+        .ok_or_else(|| MsvcEnvError::ParseError("no Windows SDK in manifest".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latest_toolset() {
+        let manifest: Manifest = serde_json::from_str(
+            r#"{"packages": [
+                {"id": "Microsoft.VC.14.38.17.8.Tools.HostX64.TargetX64.base", "payloads": []},
+                {"id": "Microsoft.VC.14.40.17.10.Tools.HostX64.TargetX64.base", "payloads": []},
+                {"id": "Microsoft.VC.14.40.17.10.Premium.Tools.HostX64.TargetX64.base"},
+                {"id": "Win10SDK_10.0.19041", "payloads": []},
+                {"id": "Win11SDK_10.0.22621", "payloads": []}
+            ]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(latest_toolset(&manifest).unwrap(), "14.40.17.10");
+        assert_eq!(latest_sdk(&manifest).unwrap().id, "Win11SDK_10.0.22621");
+    }
+
+    #[test]
+    fn test_payload_hash() {
+        let dest = std::env::temp_dir().join(format!("msvc-env-payload-{}", std::process::id()));
+        let payload = Payload {
+            file_name: "a.cab".to_string(),
+            url: String::new(),
+            sha256: "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD".to_string(),
+        };
+        payload
+            .check(&format!("{:x}", Sha256::digest(b"abc")))
+            .unwrap();
+        let error = payload
+            .check(&format!("{:x}", Sha256::digest(b"abd")))
+            .unwrap_err();
+        assert!(error.to_string().contains("a.cab"), "{}", error);
+
+        fs::write(&dest, "abc").unwrap();
+        payload.check_file(&dest).unwrap();
+        // A download failing the check is not left behind
+        fs::write(&dest, "abd").unwrap();
+        assert!(payload.check_file(&dest).is_err());
+        assert!(!dest.exists());
+    }
+
+    #[test]
+    fn test_environment_pins() {
+        let root = std::env::temp_dir().join(format!("msvc-env-portable-{}", std::process::id()));
+        for toolset in ["14.38.33130", "14.40.33807"] {
+            let bin = root
+                .join("VC")
+                .join("Tools")
+                .join("MSVC")
+                .join(toolset)
+                .join("bin")
+                .join("Hostx64")
+                .join("x64");
+            fs::create_dir_all(&bin).unwrap();
+            fs::write(bin.join("cl.exe"), "").unwrap();
+        }
+        for sdk in ["10.0.19041.0", "10.0.22621.0"] {
+            let include = root.join("Windows Kits").join("10").join("Include");
+            fs::create_dir_all(include.join(sdk).join("um")).unwrap();
+        }
+        // Already fetched, so nothing is downloaded
+        fs::write(root.join(".msvc-env-portable-x64"), "").unwrap();
+
+        let toolchain = PortableToolchain::new(&root);
+        let selection = Selection::new(MsvcArch::X64)
+            .toolset("14.38".parse().unwrap())
+            .sdk("10.0.19041.0".parse().unwrap());
+        let env = toolchain.environment(&selection).unwrap();
+        let include = env.vars.get("INCLUDE").unwrap();
+        assert!(include.contains("14.38.33130"), "{}", include);
+        assert!(include.contains("10.0.19041.0"), "{}", include);
+
+        let missing = Selection::new(MsvcArch::X64).toolset("14.29".parse().unwrap());
+        assert!(matches!(
+            toolchain.environment(&missing),
+            Err(MsvcEnvError::InvalidToolchain(_))
+        ));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,13 @@
+//! The types most programs need, for a single glob import:
+//!
+//! ```no_run
+//! use msvc_env::prelude::*;
+//! use std::process::Command;
+//!
+//! Command::new("cl").msvc_env(MsvcArch::X64)?.arg("main.c").status()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+pub use crate::{
+    CommandExt, MsvcArch, MsvcEnv, MsvcEnvError, MsvcEnvironment, Selection, command, environment,
+};
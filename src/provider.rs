@@ -0,0 +1,1806 @@
+//! Sources of MSVC environments. [`MsvcEnv`](crate::MsvcEnv) asks each provider in
+//! its chain in turn and uses the first environment one of them produces.
+
+use crate::install;
+use crate::layout::{ToolchainLayout, installed_toolsets, matching_toolset, version_key};
+use crate::overrides;
+use crate::validate::{self, SelfTestReport};
+use crate::{
+    MsvcArch, MsvcEnvError, MsvcEnvironment, PrereleaseOrder, Selection, Stage, StartDir,
+    VsInstallation, VsVersion, cache, capture, download,
+};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+fn vswhere_url() -> String {
+    format!(
+        "https://github.com/microsoft/vswhere/releases/download/{}/vswhere.exe",
+        cache::VSWHERE_VERSION
+    )
+}
+
+static VSWHERE_LOCK: OnceLock<Mutex<()>> = OnceLock::new();
+
+/// What [`system_vswhere`] found, `None` until it looks
+static SYSTEM_VSWHERE: Mutex<Option<Option<PathBuf>>> = Mutex::new(None);
+
+/// A way of locating a toolchain and producing its environment
+pub trait MsvcEnvProvider: Send + Sync {
+    /// Short name used in logs
+    fn name(&self) -> &str;
+
+    /// Produces the environment for `selection`. Returning
+    /// [`MsvcEnvError::NoVisualStudio`] means this provider has nothing to offer
+    /// and the next provider in the chain is tried.
+    fn environment(&self, selection: &Selection) -> Result<MsvcEnvironment, MsvcEnvError>;
+}
+
+/// Finds the latest installation with vswhere and captures the environment
+/// from its `VsDevCmd.bat`
+#[derive(Debug, Default, Clone)]
+pub struct VswhereProvider;
+
+impl VswhereProvider {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Returns the vswhere to run: the one named by `MSVC_ENV_VSWHERE`, the one
+    /// already on the machine (see [`system_vswhere`]), or else a copy in the
+    /// cache directory that is downloaded if missing or older than the pinned
+    /// release. If the cache directory is not writable, a per-user one is used
+    /// instead. With the `authenticode` feature the copy must be signed by
+    /// Microsoft; a download that is not is deleted again.
+    pub(crate) fn download_vswhere(&self) -> Result<PathBuf, MsvcEnvError> {
+        if let Some(path) = overrides::var(overrides::VSWHERE) {
+            return Ok(PathBuf::from(path));
+        }
+        if let Some(path) = system_vswhere() {
+            return Ok(path);
+        }
+
+        let lock = VSWHERE_LOCK.get_or_init(|| Mutex::new(()));
+        let _lock = lock
+            .lock()
+            .map_err(|_| std::io::Error::other("Mutex poisoned"))?;
+
+        let cache_dir = if cache::vswhere_path(&overrides::cache_dir()).exists() {
+            overrides::cache_dir()
+        } else {
+            overrides::writable_cache_dir()?
+        };
+        let vswhere_path = cache::vswhere_path(&cache_dir);
+
+        if vswhere_path.exists() && !vswhere_is_current(&vswhere_path) {
+            tracing::debug!(
+                "Replacing {}, which is older than vswhere {}",
+                vswhere_path.display(),
+                cache::VSWHERE_VERSION
+            );
+            std::fs::remove_file(&vswhere_path)?;
+        }
+
+        // Download vswhere if it doesn't exist
+        if !vswhere_path.exists() {
+            if let Some(dir) = vswhere_path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            download::download_file(&vswhere_url(), &vswhere_path)?;
+            std::fs::write(version_record(&vswhere_path), cache::VSWHERE_VERSION)?;
+            if let Err(e) = cache::prune(&cache_dir, overrides::cache_max_size()) {
+                tracing::debug!("Could not prune {}: {}", cache_dir.display(), e);
+            }
+        }
+        #[cfg(feature = "authenticode")]
+        if let Err(e) = crate::authenticode::verify(&vswhere_path) {
+            std::fs::remove_file(&vswhere_path)?;
+            return Err(e);
+        }
+
+        Ok(vswhere_path)
+    }
+
+    pub fn find_visual_studio(&self) -> Result<PathBuf, MsvcEnvError> {
+        self.find_installation(&Selection::new(MsvcArch::X64))
+    }
+
+    /// Path of the installation [`find_instance`](Self::find_instance) selects
+    pub fn find_installation(&self, selection: &Selection) -> Result<PathBuf, MsvcEnvError> {
+        self.find_instance(selection).map(|x| x.path)
+    }
+
+    /// Finds the newest installation matching the channel, product, name and
+    /// version range pinned in `selection`, or the one with its instance ID or
+    /// path. Preview installations are only considered when a channel, name,
+    /// instance ID or path is given or `prerelease` is set, and
+    /// `prerelease_order` can make stable or preview ones win over newer ones.
+    /// `MSVC_ENV_VS_PATH` skips the search, leaving version and product unknown.
+    /// With the `setup-api` feature the Setup Configuration API is asked first,
+    /// and vswhere only if that is unavailable or finds no match. If vswhere
+    /// fails too, Visual Studio 2015 and older are looked up in the registry.
+    pub fn find_instance(&self, selection: &Selection) -> Result<VsInstallation, MsvcEnvError> {
+        let channel_id = selection.channel_id.as_deref();
+        let product_id = selection.product_id.as_deref();
+        let name = selection.installation_name.as_deref();
+        let span = tracing::debug_span!(
+            "discover",
+            channel = channel_id,
+            product = product_id,
+            name,
+            vs_version = tracing::field::Empty
+        )
+        .entered();
+        if let Some(path) = overrides::var(overrides::VS_PATH) {
+            tracing::trace!(
+                "Using Visual Studio at {} from {}",
+                path,
+                overrides::VS_PATH
+            );
+            return Ok(VsInstallation {
+                path: PathBuf::from(path),
+                ..Default::default()
+            });
+        }
+
+        #[cfg(feature = "setup-api")]
+        let found = crate::setup_api::find_instance(selection);
+        #[cfg(not(feature = "setup-api"))]
+        let found = None;
+        let installation = match found {
+            Some(installation) => installation,
+            None => match self.vswhere_instance(selection) {
+                Ok(installation) => installation,
+                Err(e) => crate::registry::find_instance(selection)
+                    .or_else(|| unregistered_installation(selection))
+                    .ok_or(e)?,
+            },
+        };
+        if let Some(version) = &installation.version {
+            span.record("vs_version", tracing::field::display(version));
+        }
+        tracing::trace!("Found {}", installation);
+        Ok(installation)
+    }
+
+    /// Runs vswhere for [`find_instance`](Self::find_instance)
+    fn vswhere_instance(&self, selection: &Selection) -> Result<VsInstallation, MsvcEnvError> {
+        let args = vswhere_args(selection);
+        let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+        select_instance(&self.run_vswhere(&args)?, selection)
+    }
+
+    /// Every installation vswhere knows, newest first, including previews and
+    /// ones that are not completely installed
+    pub fn installations(&self) -> Result<Vec<VsInstallation>, MsvcEnvError> {
+        let instances = self.run_vswhere(&["-all", "-prerelease", "-products", "*"])?;
+        Ok(instances.iter().filter_map(installation_from).collect())
+    }
+
+    /// Runs vswhere with `args` and parses the instances it lists, newest first
+    fn run_vswhere(&self, args: &[&str]) -> Result<Vec<HashMap<String, String>>, MsvcEnvError> {
+        let vswhere_path = self.download_vswhere()?;
+        let mut command = Command::new(&vswhere_path);
+        // Without -utf8 vswhere writes in the console code page, which can't
+        // represent every character of a display name or path. JSON gives dates
+        // in ISO 8601 rather than the user's locale.
+        command
+            .args(["-nologo", "-utf8", "-sort", "-format", "json"])
+            .args(args);
+        capture::log_command(&command);
+        let output = command
+            .output()
+            .map_err(|e| MsvcEnvError::VswhereError(e.to_string()))?;
+
+        if !output.status.success() {
+            return Err(capture::process_failed(Stage::Discovery, &command, &output));
+        }
+        parse_vswhere_json(&decode_utf8(output.stdout)?)
+    }
+
+    pub fn vsdevcmd_path(&self) -> Result<PathBuf, MsvcEnvError> {
+        vsdevcmd_in(&self.find_visual_studio()?)
+    }
+
+    /// Runs `VsDevCmd.bat -test` for `selection`, which sets up the environment
+    /// and then has each of its extension scripts verify what it configured
+    pub fn self_test(&self, selection: &Selection) -> Result<SelfTestReport, MsvcEnvError> {
+        single_arch(selection.arch)?;
+        let vs_path = self.find_installation(selection)?;
+        if let Some((_, true)) = vcvarsall_in(&vs_path) {
+            return Err(MsvcEnvError::VcvarsError(
+                "VsDevCmd.bat of Visual Studio 2015 has no -test switch".to_string(),
+            ));
+        }
+        let vsdevcmd_path = vsdevcmd_in(&vs_path)?;
+
+        let (startdir, start_vars) = startdir_args(selection);
+        let mut command = Command::new("cmd");
+        command
+            .envs(start_vars)
+            .arg("/c")
+            .arg(&vsdevcmd_path)
+            .arg("-test")
+            .arg(startdir)
+            .arg(format!("-arch={}", selection.arch.as_str()))
+            .arg(format!("-host_arch={}", selection.host_arch.as_str()))
+            .args(version_args(selection));
+        capture::set_safe_current_dir(&mut command, None);
+        capture::log_command(&command);
+        let output = command
+            .output()
+            .map_err(|e| MsvcEnvError::VcvarsError(e.to_string()))?;
+
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        Ok(SelfTestReport {
+            selection: selection.clone(),
+            script: vsdevcmd_path,
+            exit_code: output.status.code(),
+            errors: validate::parse_self_test_output(&stdout),
+            output: stdout,
+        })
+    }
+}
+
+pub(crate) fn vsdevcmd_in(vs_path: &Path) -> Result<PathBuf, MsvcEnvError> {
+    let vsdevcmd_path = vs_path.join("Common7").join("Tools").join("VsDevCmd.bat");
+
+    if !vsdevcmd_path.exists() {
+        return Err(MsvcEnvError::NoVisualStudio);
+    }
+
+    tracing::trace!("Found VsDevCmd at {}", vsdevcmd_path.display());
+    Ok(vsdevcmd_path)
+}
+
+/// Finds `vcvarsall.bat`: in `VC\Auxiliary\Build` since VS 2017, directly in `VC`
+/// for VS 2015 and the 2015 Build Tools. The flag is set for the VS 2015 layout.
+pub(crate) fn vcvarsall_in(vs_path: &Path) -> Option<(PathBuf, bool)> {
+    let vc = vs_path.join("VC");
+    let current = vc.join("Auxiliary").join("Build").join("vcvarsall.bat");
+    let legacy = vc.join("vcvarsall.bat");
+
+    let found = if current.exists() {
+        (current, false)
+    } else if legacy.exists() {
+        (legacy, true)
+    } else {
+        return None;
+    };
+
+    tracing::trace!("Found vcvarsall at {}", found.0.display());
+    Some(found)
+}
+
+/// Rejects [`MsvcArch::All`], which no setup script accepts as a target
+fn single_arch(arch: MsvcArch) -> Result<(), MsvcEnvError> {
+    if arch == MsvcArch::All {
+        return Err(MsvcEnvError::ArchNotSupported(
+            arch,
+            "a single target architecture".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that the C++ tools targeting `arch` are installed, so a missing workload
+/// or component is reported by its installer ID instead of as a failed capture
+pub(crate) fn check_components(vs_path: &Path, selection: &Selection) -> Result<(), MsvcEnvError> {
+    let arch = selection.arch;
+    let msvc = vs_path.join("VC").join("Tools").join("MSVC");
+    let version = match &selection.toolset {
+        Some(toolset) => matching_toolset(&msvc, toolset),
+        None => installed_toolsets(&msvc).pop(),
+    };
+    let Some(version) = version else {
+        if selection.toolset.is_some() && !installed_toolsets(&msvc).is_empty() {
+            // Leave reporting an unknown toolset version to the setup script
+            return Ok(());
+        }
+
+        return Err(MsvcEnvError::MissingComponent {
+            arch,
+            installation: vs_path.to_path_buf(),
+            what: "the MSVC C++ build tools".to_string(),
+            components: install::required_components(arch),
+        });
+    };
+
+    let compiler_dir = msvc
+        .join(version.to_string())
+        .join("bin")
+        .join(format!("Host{}", selection.host_arch.as_str()))
+        .join(arch.as_str());
+    if !compiler_dir.exists() {
+        return Err(MsvcEnvError::MissingComponent {
+            arch,
+            installation: vs_path.to_path_buf(),
+            what: format!("the {} compiler", arch.as_str()),
+            components: install::compiler_components(arch),
+        });
+    }
+
+    Ok(())
+}
+
+/// The single targets the installation at `vs_path` has compilers for on the
+/// host `selection` names, with its toolset
+pub(crate) fn installed_targets(vs_path: &Path, selection: &Selection) -> Vec<MsvcArch> {
+    MsvcArch::SINGLE
+        .into_iter()
+        .filter(|&arch| {
+            let selection = Selection {
+                arch,
+                ..selection.clone()
+            };
+            check_components(vs_path, &selection).is_ok()
+        })
+        .collect()
+}
+
+/// `selection` with x64 as the host, if it allows falling back and the
+/// installation has x64-hosted compilers for the target but none running on the
+/// requested host
+fn with_host_fallback(vs_path: &Path, selection: &Selection) -> Option<Selection> {
+    if !selection.host_fallback || selection.host_arch == MsvcArch::X64 {
+        return None;
+    }
+    let fallback = selection.clone().host_arch(MsvcArch::X64);
+    if check_components(vs_path, selection).is_ok() || check_components(vs_path, &fallback).is_err()
+    {
+        return None;
+    }
+    tracing::warn!(
+        "No {}-hosted {} compiler in {}, using the x64-hosted one",
+        selection.host_arch.as_str(),
+        selection.arch.as_str(),
+        vs_path.display()
+    );
+    Some(fallback)
+}
+
+/// Where the version of a cached vswhere is recorded, next to the executable
+fn version_record(vswhere: &Path) -> PathBuf {
+    vswhere.with_extension("version")
+}
+
+/// Whether the vswhere at `path` is at least the pinned release. Its version is
+/// taken from the record next to it, or else asked of vswhere with `-?` and
+/// recorded, so that only happens once.
+fn vswhere_is_current(path: &Path) -> bool {
+    let record = version_record(path);
+    let version = match std::fs::read_to_string(&record) {
+        Ok(version) => version.trim().to_string(),
+        Err(_) => {
+            let Some(version) = vswhere_version(path) else {
+                return false;
+            };
+            let _ = std::fs::write(&record, &version);
+            version
+        }
+    };
+    version_key(&version) >= version_key(cache::VSWHERE_VERSION)
+}
+
+/// Asks the vswhere at `path` for its version with `-?`
+fn vswhere_version(path: &Path) -> Option<String> {
+    let output = Command::new(path).arg("-?").output().ok()?;
+    parse_vswhere_version(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// The vswhere.exe the Visual Studio Installer puts in its own directory, or else
+/// one on PATH, if it is at least the pinned release; with the `authenticode`
+/// feature also only if signed by Microsoft. Looked up once, until
+/// [`forget_system_vswhere`].
+fn system_vswhere() -> Option<PathBuf> {
+    let mut found = SYSTEM_VSWHERE
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    found
+        .get_or_insert_with(|| {
+            let found = system_vswhere_candidates(|key| std::env::var_os(key))
+                .into_iter()
+                .filter(|path| path.is_file())
+                .find(|path| {
+                    #[cfg(feature = "authenticode")]
+                    if crate::authenticode::verify(path).is_err() {
+                        return false;
+                    }
+                    vswhere_version(path).is_some_and(|version| {
+                        version_key(&version) >= version_key(cache::VSWHERE_VERSION)
+                    })
+                });
+            match &found {
+                Some(path) => tracing::trace!("Using vswhere at {}", path.display()),
+                None => tracing::trace!("No vswhere {} installed", cache::VSWHERE_VERSION),
+            }
+            found
+        })
+        .clone()
+}
+
+/// Makes the next [`system_vswhere`] look again, for a vswhere installed or
+/// removed since
+pub(crate) fn forget_system_vswhere() {
+    *SYSTEM_VSWHERE
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = None;
+}
+
+/// Where vswhere.exe may be installed, in order of preference, with variables
+/// looked up through `var`
+fn system_vswhere_candidates(var: impl Fn(&str) -> Option<OsString>) -> Vec<PathBuf> {
+    let installer = ["ProgramFiles(x86)", "ProgramFiles"]
+        .into_iter()
+        .filter_map(&var)
+        .map(|dir| {
+            PathBuf::from(dir)
+                .join("Microsoft Visual Studio")
+                .join("Installer")
+                .join("vswhere.exe")
+        });
+    let on_path = var("PATH")
+        .map(|path| std::env::split_paths(&path).collect::<Vec<_>>())
+        .unwrap_or_default()
+        .into_iter()
+        .map(|dir| dir.join("vswhere.exe"));
+    installer.chain(on_path).collect()
+}
+
+/// The version in the banner vswhere prints, e.g. `3.1.7` from
+/// `Visual Studio Locator version 3.1.7+f39851e70f [query version 3.6.2112.28831]`
+fn parse_vswhere_version(output: &str) -> Option<String> {
+    let (_, rest) = output.lines().next()?.split_once("version ")?;
+    let version = rest.split(['+', ' ']).next()?;
+    version_key(version).map(|_| version.to_string())
+}
+
+/// Decodes the output of `vswhere -utf8`, which may start with a byte order mark
+fn decode_utf8(mut output: Vec<u8>) -> Result<String, MsvcEnvError> {
+    if output.starts_with(b"\xEF\xBB\xBF") {
+        output.drain(..3);
+    }
+    String::from_utf8(output)
+        .map_err(|e| MsvcEnvError::ParseError(format!("vswhere output is not UTF-8: {}", e)))
+}
+
+/// The vswhere filters for `selection`, leaving the rest to [`select_instance`]
+fn vswhere_args(selection: &Selection) -> Vec<String> {
+    let product_id = selection.product_id.as_deref();
+    let mut args = Vec::new();
+    // -legacy finds installations of any product but rejects -products and
+    // -requires
+    if selection.legacy && product_id.is_none() && selection.required_components.is_empty() {
+        args.push("-legacy".to_string());
+    } else {
+        args.extend([
+            "-products".to_string(),
+            product_id.unwrap_or("*").to_string(),
+        ]);
+    }
+    if selection.channel_id.is_some()
+        || selection.installation_name.is_some()
+        || selection.instance_id.is_some()
+        || selection.installation_path.is_some()
+        || selection.prerelease
+    {
+        args.push("-prerelease".to_string());
+    }
+    if let Some(range) = &selection.vs_version {
+        args.extend(["-version".to_string(), range.to_string()]);
+    }
+    if !selection.required_components.is_empty() {
+        args.push("-requires".to_string());
+        args.extend(selection.required_components.iter().cloned());
+        if selection.requires_any {
+            args.push("-requiresAny".to_string());
+        }
+    }
+    args
+}
+
+/// Picks the newest of the vswhere `instances` that `selection` accepts, or
+/// of those its [`PrereleaseOrder`] prefers if there are any. The
+/// product, version range, prerelease and completeness filters vswhere already
+/// applied are checked again, so output listing every instance can be used as well. Required
+/// components are not listed in it, so only vswhere can check them.
+pub(crate) fn select_instance(
+    instances: &[HashMap<String, String>],
+    selection: &Selection,
+) -> Result<VsInstallation, MsvcEnvError> {
+    let channel_id = selection.channel_id.as_deref();
+    let product_id = selection.product_id.as_deref().filter(|x| *x != "*");
+    let name = selection.installation_name.as_deref();
+    let instance_id = selection.instance_id.as_deref();
+    let path = selection.installation_path.as_deref();
+    // Pinning one installation also allows it to be a preview or legacy one
+    let pinned = instance_id.is_some() || path.is_some();
+    let prerelease = channel_id.is_some() || name.is_some() || pinned || selection.prerelease;
+    let matches = |key: &str, value: Option<&str>, instance: &HashMap<String, String>| {
+        value.is_none_or(|value| {
+            instance
+                .get(key)
+                .is_some_and(|x| x.eq_ignore_ascii_case(value))
+        })
+    };
+    // The preferred kind first, then newest first; `min_by_key` keeps
+    // vswhere's order between equal versions
+    let rank = |instance: &HashMap<String, String>| match selection.prerelease_order {
+        None => false,
+        Some(PrereleaseOrder::StableFirst) => is_prerelease(instance),
+        Some(PrereleaseOrder::PreviewFirst) => !is_prerelease(instance),
+    };
+    let instance = instances
+        .iter()
+        .filter(|instance| matches("channelId", channel_id, instance))
+        .filter(|instance| matches("productId", product_id, instance))
+        .filter(|instance| matches("instanceId", instance_id, instance))
+        .filter(|instance| {
+            path.is_none_or(|path| {
+                instance
+                    .get("installationPath")
+                    .is_some_and(|x| same_installation_path(x, path))
+            })
+        })
+        .filter(|instance| selection.legacy || pinned || !is_legacy(instance))
+        .filter(|instance| selection.required_components.is_empty() || !is_legacy(instance))
+        .filter(|instance| name.is_none_or(|name| has_name(instance, name)))
+        .filter(|instance| prerelease || !is_prerelease(instance))
+        .filter(|instance| flag(instance, "isComplete") != Some(false))
+        .filter(|instance| {
+            selection.vs_version.as_ref().is_none_or(|range| {
+                instance_version(instance).is_some_and(|version| range.contains(&version))
+            })
+        })
+        .min_by_key(|instance| {
+            (
+                rank(instance),
+                std::cmp::Reverse(instance_version(instance)),
+            )
+        })
+        .ok_or(MsvcEnvError::NoVisualStudio)?;
+    installation_from(instance).ok_or(MsvcEnvError::NoVisualStudio)
+}
+
+/// Whether vswhere's `installationPath` is `path`, which Windows compares
+/// ignoring case; one may end in a separator and the other not
+fn same_installation_path(installation_path: &str, path: &Path) -> bool {
+    let normalize = |x: &str| x.trim_end_matches(['\\', '/']).to_lowercase();
+    normalize(installation_path) == normalize(&path.to_string_lossy())
+}
+
+/// The installation pinned by [`Selection::installation_path`] when no
+/// discovery lists it, e.g. a copied one, if it has the setup scripts
+fn unregistered_installation(selection: &Selection) -> Option<VsInstallation> {
+    let path = selection.installation_path.as_ref()?;
+    if vsdevcmd_in(path).is_err() && vcvarsall_in(path).is_none() {
+        return None;
+    }
+    tracing::trace!("Using unregistered installation at {}", path.display());
+    Some(VsInstallation {
+        path: path.clone(),
+        ..Default::default()
+    })
+}
+
+/// The installation a vswhere instance describes; `None` without a path
+fn installation_from(instance: &HashMap<String, String>) -> Option<VsInstallation> {
+    Some(VsInstallation {
+        path: PathBuf::from(instance.get("installationPath")?),
+        version: instance_version(instance),
+        product_id: instance.get("productId").cloned(),
+        instance_id: instance.get("instanceId").cloned(),
+        display_name: instance.get("displayName").cloned(),
+        channel_id: instance.get("channelId").cloned(),
+        prerelease: is_prerelease(instance),
+        legacy: is_legacy(instance),
+        product_line_version: instance.get("catalog_productLineVersion").cloned(),
+        install_date: timestamp(instance, "installDate"),
+        update_date: timestamp(instance, "updateDate"),
+        complete: flag(instance, "isComplete"),
+        launchable: flag(instance, "isLaunchable"),
+    })
+}
+
+fn instance_version(instance: &HashMap<String, String>) -> Option<VsVersion> {
+    instance
+        .get("installationVersion")
+        .and_then(|x| x.parse().ok())
+}
+
+fn is_prerelease(instance: &HashMap<String, String>) -> bool {
+    flag(instance, "isPrerelease").unwrap_or(false)
+}
+
+/// A boolean property, which vswhere prints as `1` or `0`
+fn flag(instance: &HashMap<String, String>, key: &str) -> Option<bool> {
+    instance
+        .get(key)
+        .map(|x| x == "1" || x.eq_ignore_ascii_case("true"))
+}
+
+/// A date property, which vswhere gives in UTC, e.g. `2024-05-10T09:12:45Z`
+fn timestamp(instance: &HashMap<String, String>, key: &str) -> Option<SystemTime> {
+    let (date, time) = instance.get(key)?.trim_end_matches('Z').split_once('T')?;
+    let mut date = date.splitn(3, '-').map(|x| x.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    // Fractions of a second are dropped
+    let time = time.split('.').next()?;
+    let mut time = time.splitn(3, ':').map(|x| x.parse::<u64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // Days since 1970-01-01, counting years from March so that leap days come last
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = u64::try_from(era * 146_097 + day_of_era - 719_468).ok()?;
+    let seconds = days * 86_400 + hour * 3_600 + minute * 60 + second;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+/// Whether vswhere found the instance through `-legacy`: those are named after
+/// their version, e.g. `VisualStudio.14.0`, and report little besides the path
+fn is_legacy(instance: &HashMap<String, String>) -> bool {
+    instance
+        .get("instanceId")
+        .is_some_and(|x| x.starts_with("VisualStudio."))
+}
+
+/// Whether `name` is the display name or installer nickname of a vswhere instance,
+/// ignoring case
+fn has_name(instance: &HashMap<String, String>, name: &str) -> bool {
+    ["displayName", "properties_nickname"]
+        .iter()
+        .filter_map(|key| instance.get(*key))
+        .any(|x| x.trim().eq_ignore_ascii_case(name.trim()))
+}
+
+/// Parses vswhere's `-format json` output, an array with an object per instance.
+/// Nested properties are flattened to keys like `catalog_productLineVersion`,
+/// the way the setup API and registry lookups name them, and booleans become
+/// `1` or `0`.
+pub(crate) fn parse_vswhere_json(
+    output: &str,
+) -> Result<Vec<HashMap<String, String>>, MsvcEnvError> {
+    let instances: Vec<serde_json::Value> = serde_json::from_str(output)
+        .map_err(|e| MsvcEnvError::ParseError(format!("unexpected vswhere output: {}", e)))?;
+    Ok(instances
+        .iter()
+        .map(|instance| {
+            let mut properties = HashMap::new();
+            flatten_properties("", instance, &mut properties);
+            properties
+        })
+        .collect())
+}
+
+fn flatten_properties(key: &str, value: &serde_json::Value, out: &mut HashMap<String, String>) {
+    use serde_json::Value;
+
+    let text = match value {
+        Value::Object(object) => {
+            for (name, value) in object {
+                let name = if key.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{}_{}", key, name)
+                };
+                flatten_properties(&name, value, out);
+            }
+            return;
+        }
+        Value::String(x) => x.clone(),
+        Value::Bool(x) => (if *x { "1" } else { "0" }).to_string(),
+        Value::Number(x) => x.to_string(),
+        Value::Null | Value::Array(_) => return,
+    };
+    out.insert(key.to_string(), text);
+}
+
+impl MsvcEnvProvider for VswhereProvider {
+    fn name(&self) -> &str {
+        "vswhere"
+    }
+
+    fn environment(&self, selection: &Selection) -> Result<MsvcEnvironment, MsvcEnvError> {
+        single_arch(selection.arch)?;
+        let installation = self.find_instance(selection)?;
+        let vs_path = installation.path.clone();
+
+        // VS 2015 ships a VsDevCmd.bat that ignores -arch, so its layout is set up
+        // through vcvarsall.bat instead
+        if let Some((vcvarsall, true)) = vcvarsall_in(&vs_path) {
+            let mut args = vec![vcvarsall_arch(selection, true)?.to_string()];
+            args.extend(selection.sdk.iter().map(|x| x.to_string()));
+            let vars = capture::run_script(&vcvarsall, args, selection.base_env.as_ref(), &[])?;
+            return Ok(MsvcEnvironment::new(vars).installed_from(installation));
+        }
+
+        let vsdevcmd_path = vsdevcmd_in(&vs_path)?;
+        let fallback = with_host_fallback(&vs_path, selection);
+        let selection = fallback.as_ref().unwrap_or(selection);
+        check_components(&vs_path, selection)?;
+
+        let (startdir, start_vars) = startdir_args(selection);
+        let mut args = vec![
+            startdir,
+            format!("-arch={}", selection.arch.as_str()),
+            format!("-host_arch={}", selection.host_arch.as_str()),
+        ];
+        args.extend(version_args(selection));
+        let vars = capture::run_script(
+            &vsdevcmd_path,
+            args,
+            selection.base_env.as_ref(),
+            &start_vars,
+        )?;
+
+        let mut env = MsvcEnvironment::new(vars).installed_from(installation);
+        env.meta.host_fallback = fallback.is_some();
+        Ok(env)
+    }
+}
+
+/// Finds the latest installation with vswhere like [`VswhereProvider`], but runs
+/// `vcvarsall.bat` directly. Extra arguments are passed through, so vcvarsall-only
+/// options such as `-vcvars_ver=14.29`, an SDK version or `uwp` can be used.
+#[derive(Debug, Default, Clone)]
+pub struct VcvarsallProvider {
+    args: Vec<String>,
+}
+
+impl VcvarsallProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends an argument passed to vcvarsall.bat after the architecture
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Path of `vcvarsall.bat` inside the installation `selection` resolves to
+    pub fn vcvarsall_path(&self, selection: &Selection) -> Result<PathBuf, MsvcEnvError> {
+        let vs_path = VswhereProvider::new().find_installation(selection)?;
+        vcvarsall_in(&vs_path)
+            .map(|(path, _)| path)
+            .ok_or(MsvcEnvError::NoVisualStudio)
+    }
+}
+
+impl MsvcEnvProvider for VcvarsallProvider {
+    fn name(&self) -> &str {
+        "vcvarsall"
+    }
+
+    fn environment(&self, selection: &Selection) -> Result<MsvcEnvironment, MsvcEnvError> {
+        let installation = VswhereProvider::new().find_instance(selection)?;
+        let vs_path = installation.path.clone();
+        let (vcvarsall, legacy) = vcvarsall_in(&vs_path).ok_or(MsvcEnvError::NoVisualStudio)?;
+        let fallback = (!legacy)
+            .then(|| with_host_fallback(&vs_path, selection))
+            .flatten();
+        let selection = fallback.as_ref().unwrap_or(selection);
+        if !legacy {
+            check_components(&vs_path, selection)?;
+        }
+        let mut args = vec![vcvarsall_arch(selection, legacy)?];
+        args.extend(selection.sdk.iter().map(|x| x.to_string()));
+        if !legacy {
+            args.extend(
+                selection
+                    .toolset
+                    .iter()
+                    .map(|x| format!("-vcvars_ver={}", x)),
+            );
+        }
+        args.extend(self.args.iter().cloned());
+
+        let vars = capture::run_script(&vcvarsall, args, selection.base_env.as_ref(), &[])?;
+        let mut env = MsvcEnvironment::new(vars).installed_from(installation);
+        env.meta.host_fallback = fallback.is_some();
+        Ok(env)
+    }
+}
+
+/// vcvarsall's `host_target` argument, e.g. `amd64_arm64`, or just the host when
+/// it is also the target. The VS 2015 vcvarsall has no ARM64 toolset.
+fn vcvarsall_arch(selection: &Selection, legacy: bool) -> Result<String, MsvcEnvError> {
+    let (host, arch) = (selection.host_arch, selection.arch);
+    if legacy && (arch == MsvcArch::Arm64 || host == MsvcArch::Arm64) {
+        return Err(MsvcEnvError::ArchNotSupported(
+            MsvcArch::Arm64,
+            "an ARM64 toolset in VS 2015".to_string(),
+        ));
+    }
+
+    let host_name = match host {
+        MsvcArch::X64 | MsvcArch::X86 | MsvcArch::Arm64 => vs_arch_name(host)?,
+        MsvcArch::Arm | MsvcArch::All => {
+            return Err(MsvcEnvError::ArchNotSupported(
+                host,
+                "compilers running on this host".to_string(),
+            ));
+        }
+    };
+    let target_name = vs_arch_name(arch)?;
+
+    if host == arch {
+        Ok(host_name.to_string())
+    } else {
+        Ok(format!("{}_{}", host_name, target_name))
+    }
+}
+
+/// Architecture name used by vcvarsall and the DevShell module
+fn vs_arch_name(arch: MsvcArch) -> Result<&'static str, MsvcEnvError> {
+    match arch {
+        MsvcArch::X64 => Ok("amd64"),
+        MsvcArch::X86 => Ok("x86"),
+        MsvcArch::Arm => Ok("arm"),
+        MsvcArch::Arm64 => Ok("arm64"),
+        MsvcArch::All => Err(MsvcEnvError::ArchNotSupported(
+            arch,
+            "a single target architecture".to_string(),
+        )),
+    }
+}
+
+/// VsDevCmd's `-startdir` argument for `selection`, with the variables it
+/// needs set to honor it
+fn startdir_args(selection: &Selection) -> (String, Vec<(&str, &OsStr)>) {
+    match &selection.start_dir {
+        StartDir::None => ("-startdir=none".to_string(), Vec::new()),
+        StartDir::Auto => ("-startdir=auto".to_string(), Vec::new()),
+        StartDir::Dir(dir) => (
+            "-startdir=auto".to_string(),
+            vec![("VSCMD_START_DIR", dir.as_os_str())],
+        ),
+    }
+}
+
+/// VsDevCmd arguments pinning the toolset and SDK versions in `selection`
+fn version_args(selection: &Selection) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(toolset) = &selection.toolset {
+        args.push(format!("-vcvars_ver={}", toolset));
+    }
+    if let Some(sdk) = &selection.sdk {
+        args.push(format!("-winsdk={}", sdk));
+    }
+    args
+}
+
+/// Finds the latest installation with vswhere like [`VswhereProvider`], but enters
+/// the environment through the DevShell PowerShell module instead of running
+/// `VsDevCmd.bat` in cmd. Useful where cmd.exe is restricted by policy.
+#[derive(Debug, Clone)]
+pub struct DevShellProvider {
+    powershell: PathBuf,
+}
+
+impl Default for DevShellProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DevShellProvider {
+    /// Uses Windows PowerShell (`powershell.exe`)
+    pub fn new() -> Self {
+        Self {
+            powershell: PathBuf::from("powershell"),
+        }
+    }
+
+    /// Uses another PowerShell executable, e.g. `pwsh` for PowerShell 7
+    pub fn powershell(mut self, executable: impl Into<PathBuf>) -> Self {
+        self.powershell = executable.into();
+        self
+    }
+
+    /// Path of the DevShell module inside the installation `selection` resolves to
+    pub fn module_path(&self, selection: &Selection) -> Result<PathBuf, MsvcEnvError> {
+        let vs_path = VswhereProvider::new().find_installation(selection)?;
+        let module = vs_path
+            .join("Common7")
+            .join("Tools")
+            .join("Microsoft.VisualStudio.DevShell.dll");
+
+        if !module.exists() {
+            return Err(MsvcEnvError::NoVisualStudio);
+        }
+
+        tracing::trace!("Found DevShell module at {}", module.display());
+        Ok(module)
+    }
+}
+
+impl MsvcEnvProvider for DevShellProvider {
+    fn name(&self) -> &str {
+        "devshell"
+    }
+
+    fn environment(&self, selection: &Selection) -> Result<MsvcEnvironment, MsvcEnvError> {
+        let module = self.module_path(selection)?;
+        let vs_path = module
+            .ancestors()
+            .nth(3)
+            .ok_or(MsvcEnvError::NoVisualStudio)?;
+
+        let fallback = with_host_fallback(vs_path, selection);
+        let selection = fallback.as_ref().unwrap_or(selection);
+        let script = devshell_script(&module, vs_path, selection)?;
+        check_components(vs_path, selection)?;
+
+        let vars = capture::run_powershell(&self.powershell, &script, selection.base_env.as_ref())?;
+        let mut env = MsvcEnvironment::new(vars);
+        env.meta.host_fallback = fallback.is_some();
+        Ok(env)
+    }
+}
+
+fn devshell_script(
+    module: &Path,
+    vs_path: &Path,
+    selection: &Selection,
+) -> Result<String, MsvcEnvError> {
+    let mut dev_cmd_args = vec!["-no_logo".to_string()];
+    dev_cmd_args.extend(version_args(selection));
+
+    // Enter-VsDevShell changes to the start directory unless told not to
+    let location = match &selection.start_dir {
+        StartDir::None => " -SkipAutomaticLocation".to_string(),
+        StartDir::Auto => String::new(),
+        StartDir::Dir(dir) => format!(
+            " -StartInPath {}",
+            powershell_quote(&dir.display().to_string())
+        ),
+    };
+
+    Ok(format!(
+        "$ErrorActionPreference = 'Stop'\n\
+         Import-Module {}\n\
+         Enter-VsDevShell -VsInstallPath {}{} -Arch {} -HostArch {} -DevCmdArguments {} | Out-Null\n\
+         Write-Output '{}'\n\
+         Get-ChildItem env: | ForEach-Object {{ \"$($_.Name)=$($_.Value)\" }}\n",
+        powershell_quote(&module.display().to_string()),
+        powershell_quote(&vs_path.display().to_string()),
+        location,
+        vs_arch_name(selection.arch)?,
+        vs_arch_name(selection.host_arch)?,
+        powershell_quote(&dev_cmd_args.join(" ")),
+        capture::ENV_MARKER,
+    ))
+}
+
+/// Single-quoted PowerShell string literal; only `'` needs escaping, by doubling it
+pub fn powershell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Environment variable naming an EWDK mount; when set, [`EwdkProvider`] is put
+/// in front of the default chain
+pub const EWDK_ENV_VAR: &str = "MSVC_ENV_EWDK";
+
+/// Captures the environment from an Enterprise WDK mount, a self-contained
+/// toolchain image that needs no Visual Studio installation
+#[derive(Debug, Clone)]
+pub struct EwdkProvider {
+    root: PathBuf,
+}
+
+impl EwdkProvider {
+    /// Uses the EWDK mounted at `root` (the directory containing `LaunchBuildEnv.cmd`)
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Uses the EWDK named by the `MSVC_ENV_EWDK` environment variable, if set
+    pub fn from_env() -> Option<Self> {
+        std::env::var_os(EWDK_ENV_VAR)
+            .filter(|x| !x.is_empty())
+            .map(Self::new)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Path of the script that sets up the build environment without starting a shell
+    pub fn setup_script(&self) -> Result<PathBuf, MsvcEnvError> {
+        let script = self.root.join("BuildEnv").join("SetupBuildEnv.cmd");
+        if !script.exists() {
+            tracing::trace!("No EWDK found at {}", self.root.display());
+            return Err(MsvcEnvError::NoVisualStudio);
+        }
+
+        tracing::trace!("Found EWDK setup script at {}", script.display());
+        Ok(script)
+    }
+}
+
+impl MsvcEnvProvider for EwdkProvider {
+    fn name(&self) -> &str {
+        "ewdk"
+    }
+
+    fn environment(&self, selection: &Selection) -> Result<MsvcEnvironment, MsvcEnvError> {
+        let script = self.setup_script()?;
+        let arch = match selection.arch {
+            MsvcArch::X64 => "amd64",
+            MsvcArch::X86 => "x86",
+            MsvcArch::Arm64 => "arm64",
+            arch @ (MsvcArch::Arm | MsvcArch::All) => {
+                return Err(MsvcEnvError::ArchNotSupported(
+                    arch,
+                    "EWDK build environment".to_string(),
+                ));
+            }
+        };
+
+        let vars = capture::run_script(&script, [arch], selection.base_env.as_ref(), &[])?;
+        Ok(MsvcEnvironment::new(vars))
+    }
+}
+
+/// Builds the environment from a toolchain copied or extracted into a directory,
+/// such as one produced by xwin or taken from another machine. The directory must
+/// contain `VC\Tools\MSVC\<version>` and `Windows Kits\10`; no setup scripts are run.
+#[derive(Debug, Clone)]
+pub struct FixedPathProvider {
+    root: PathBuf,
+}
+
+impl FixedPathProvider {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Checks that the directory holds everything an `arch` build needs
+    pub fn validate(&self, arch: MsvcArch) -> Result<(), MsvcEnvError> {
+        self.layout(&Selection::new(arch)).map(|_| ())
+    }
+
+    fn layout(&self, selection: &Selection) -> Result<ToolchainLayout, MsvcEnvError> {
+        let arch = selection.arch;
+        if !self.root.is_dir() {
+            tracing::trace!("No toolchain directory at {}", self.root.display());
+            return Err(MsvcEnvError::NoVisualStudio);
+        }
+
+        let layout = ToolchainLayout::detect(&self.root)
+            .map_err(|_| {
+                MsvcEnvError::InvalidToolchain(format!(
+                    "{} has no VC\\Tools\\MSVC and Windows Kits\\10\\Include versions",
+                    self.root.display()
+                ))
+            })?
+            .pin(selection)?;
+
+        if arch == MsvcArch::All {
+            return Err(MsvcEnvError::ArchNotSupported(
+                arch,
+                "a single target architecture".to_string(),
+            ));
+        }
+
+        let missing = layout.missing_paths(arch);
+        if !missing.is_empty() {
+            return Err(MsvcEnvError::InvalidToolchain(format!(
+                "missing {}",
+                missing
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )));
+        }
+
+        Ok(layout)
+    }
+}
+
+impl MsvcEnvProvider for FixedPathProvider {
+    fn name(&self) -> &str {
+        "fixed-path"
+    }
+
+    fn environment(&self, selection: &Selection) -> Result<MsvcEnvironment, MsvcEnvError> {
+        self.layout(selection)?.environment(selection.arch)
+    }
+}
+
+/// Returns canned environments without touching the filesystem, network or any
+/// child process, so code depending on an MSVC environment can be tested on any OS
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug, Clone)]
+pub struct MockProvider {
+    environments: HashMap<MsvcArch, MsvcEnvironment>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Default for MockProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl MockProvider {
+    /// A provider with a [`fake_environment`](Self::fake_environment) for every target architecture
+    pub fn new() -> Self {
+        let mut provider = Self::empty();
+        for arch in [MsvcArch::X86, MsvcArch::X64, MsvcArch::Arm, MsvcArch::Arm64] {
+            provider = provider.with_environment(arch, Self::fake_environment(arch));
+        }
+        provider
+    }
+
+    /// A provider that knows no architectures and reports every one as unsupported
+    pub fn empty() -> Self {
+        Self {
+            environments: HashMap::new(),
+        }
+    }
+
+    /// Returns `env` whenever `arch` is requested
+    pub fn with_environment(mut self, arch: MsvcArch, env: MsvcEnvironment) -> Self {
+        self.environments.insert(arch, env);
+        self
+    }
+
+    /// A deterministic environment shaped like one VsDevCmd produces for an
+    /// x64-hosted Build Tools 2022 installation
+    pub fn fake_environment(arch: MsvcArch) -> MsvcEnvironment {
+        const VS: &str = r"C:\Program Files\Microsoft Visual Studio\2022\BuildTools";
+        const MSVC: &str =
+            r"C:\Program Files\Microsoft Visual Studio\2022\BuildTools\VC\Tools\MSVC\14.40.33807";
+        const SDK: &str = r"C:\Program Files (x86)\Windows Kits\10";
+        const SDK_VERSION: &str = "10.0.22621.0";
+
+        let target = arch.as_str();
+        let vars = [
+            (
+                "INCLUDE",
+                format!(
+                    r"{MSVC}\include;{SDK}\include\{SDK_VERSION}\ucrt;{SDK}\include\{SDK_VERSION}\um;{SDK}\include\{SDK_VERSION}\shared"
+                ),
+            ),
+            (
+                "LIB",
+                format!(
+                    r"{MSVC}\lib\{target};{SDK}\lib\{SDK_VERSION}\ucrt\{target};{SDK}\lib\{SDK_VERSION}\um\{target}"
+                ),
+            ),
+            ("LIBPATH", format!(r"{MSVC}\lib\{target}")),
+            (
+                "Path",
+                format!(
+                    r"{MSVC}\bin\HostX64\{target};{SDK}\bin\{SDK_VERSION}\x64;C:\Windows\system32;C:\Windows"
+                ),
+            ),
+            ("Platform", target.to_string()),
+            ("VCINSTALLDIR", format!(r"{VS}\VC\")),
+            ("VCToolsInstallDir", format!(r"{MSVC}\")),
+            ("VCToolsVersion", "14.40.33807".to_string()),
+            ("VisualStudioVersion", "17.0".to_string()),
+            ("VSCMD_ARG_HOST_ARCH", "x64".to_string()),
+            ("VSCMD_ARG_TGT_ARCH", target.to_string()),
+            ("VSCMD_VER", "17.10.3".to_string()),
+            ("VSINSTALLDIR", format!(r"{VS}\")),
+            ("WindowsSdkDir", format!(r"{SDK}\")),
+            ("WindowsSDKVersion", format!(r"{SDK_VERSION}\")),
+        ];
+
+        MsvcEnvironment::new(
+            vars.into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl MsvcEnvProvider for MockProvider {
+    fn name(&self) -> &str {
+        "mock"
+    }
+
+    fn environment(&self, selection: &Selection) -> Result<MsvcEnvironment, MsvcEnvError> {
+        self.environments
+            .get(&selection.arch)
+            .cloned()
+            .ok_or_else(|| {
+                MsvcEnvError::ArchNotSupported(selection.arch, "mock environment".to_string())
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_fixed_path_provider() {
+        let root = std::env::temp_dir().join(format!("msvc-env-fixed-{}", std::process::id()));
+        let msvc = root
+            .join("VC")
+            .join("Tools")
+            .join("MSVC")
+            .join("14.40.33807");
+        let sdk = root.join("Windows Kits").join("10");
+        let sdk_version = "10.0.22621.0";
+
+        fs::create_dir_all(msvc.join("bin").join("Hostx64").join("x64")).unwrap();
+        fs::write(
+            msvc.join("bin").join("Hostx64").join("x64").join("cl.exe"),
+            "",
+        )
+        .unwrap();
+        fs::create_dir_all(msvc.join("include")).unwrap();
+        fs::create_dir_all(msvc.join("lib").join("x64")).unwrap();
+        for dir in ["ucrt", "shared", "um", "winrt", "cppwinrt"] {
+            fs::create_dir_all(sdk.join("Include").join(sdk_version).join(dir)).unwrap();
+        }
+        fs::create_dir_all(sdk.join("Lib").join(sdk_version).join("um").join("x64")).unwrap();
+
+        let provider = FixedPathProvider::new(&root);
+        match provider.validate(MsvcArch::X64) {
+            Err(MsvcEnvError::InvalidToolchain(message)) => {
+                let lib = sdk.join("Lib").join(sdk_version);
+                let ucrt = lib.join("ucrt").join("x64").display().to_string();
+                let um = lib.join("um").join("x64").display().to_string();
+                assert!(message.contains(&ucrt), "{}", message);
+                assert!(!message.contains(&um), "{}", message);
+            }
+            other => panic!("expected InvalidToolchain, got {:?}", other),
+        }
+
+        fs::create_dir_all(sdk.join("Lib").join(sdk_version).join("ucrt").join("x64")).unwrap();
+        let env = provider
+            .environment(&Selection::new(MsvcArch::X64))
+            .unwrap();
+        assert_eq!(env.get("VCToolsVersion"), Some("14.40.33807"));
+        assert_eq!(env.get("WindowsSDKVersion"), Some("10.0.22621.0\\"));
+        assert!(env.get("LIB").unwrap().contains("ucrt"));
+
+        assert!(matches!(
+            provider.validate(MsvcArch::Arm64),
+            Err(MsvcEnvError::InvalidToolchain(_))
+        ));
+        assert!(matches!(
+            FixedPathProvider::new(root.join("missing")).validate(MsvcArch::X64),
+            Err(MsvcEnvError::NoVisualStudio)
+        ));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_parse_vswhere_json() {
+        let output = r#"[
+            {
+                "instanceId": "1a2b3c4d",
+                "installationPath": "C:\\Program Files\\Microsoft Visual Studio\\2022\\Preview",
+                "channelId": "VisualStudio.17.Preview",
+                "productId": "Microsoft.VisualStudio.Product.Community",
+                "isPrerelease": true,
+                "state": 4294967295,
+                "packages": [],
+                "catalog": {"productLineVersion": "2022"}
+            },
+            {
+                "instanceId": "5e6f7a8b",
+                "installationPath": "C:\\Program Files\\Microsoft Visual Studio\\2022\\Community",
+                "channelId": "VisualStudio.17.Release",
+                "productId": "Microsoft.VisualStudio.Product.Community"
+            }
+        ]"#;
+        let instances = parse_vswhere_json(output).unwrap();
+        assert_eq!(instances.len(), 2);
+        assert_eq!(instances[0]["channelId"], "VisualStudio.17.Preview");
+        assert_eq!(
+            instances[1]["installationPath"],
+            "C:\\Program Files\\Microsoft Visual Studio\\2022\\Community"
+        );
+        assert_eq!(instances[0]["isPrerelease"], "1");
+        assert_eq!(instances[0]["state"], "4294967295");
+        assert_eq!(instances[0]["catalog_productLineVersion"], "2022");
+        assert!(!instances[0].contains_key("packages"));
+        assert!(!instances[1].contains_key("isPrerelease"));
+
+        assert!(parse_vswhere_json("[]").unwrap().is_empty());
+        assert!(matches!(
+            parse_vswhere_json("instanceId: 1a2b3c4d"),
+            Err(MsvcEnvError::ParseError(_))
+        ));
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let date = |text: &str| {
+            let instance = HashMap::from([("installDate".to_string(), text.to_string())]);
+            timestamp(&instance, "installDate")
+                .map(|x| x.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs())
+        };
+        assert_eq!(date("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(date("2000-02-29T00:00:00Z"), Some(951_782_400));
+        assert_eq!(date("2024-05-10T09:12:45.250Z"), Some(1_715_332_365));
+        assert_eq!(date("5/10/2024 9:12:45 AM"), None);
+        assert_eq!(date("2024-13-10T09:12:45Z"), None);
+    }
+
+    #[test]
+    fn test_installation_from() {
+        let output = r#"[
+            {
+                "instanceId": "1a2b3c4d",
+                "installationPath": "C:\\Program Files\\Microsoft Visual Studio\\2022\\Preview",
+                "installationVersion": "17.11.0",
+                "isPrerelease": true,
+                "displayName": "Visual Studio Community 2022",
+                "channelId": "VisualStudio.17.Preview",
+                "productId": "Microsoft.VisualStudio.Product.Community",
+                "installDate": "2024-05-10T09:12:45Z",
+                "updateDate": "2024-06-02T16:03:10Z",
+                "isComplete": false,
+                "isLaunchable": true,
+                "catalog": {
+                    "productLineVersion": "2022"
+                }
+            },
+            {
+                "instanceId": "5e6f7a8b",
+                "displayName": "Visual Studio Build Tools 2022"
+            }
+        ]"#;
+        let instances = parse_vswhere_json(output).unwrap();
+        let installation = installation_from(&instances[0]).unwrap();
+        assert_eq!(installation.instance_id.as_deref(), Some("1a2b3c4d"));
+        assert_eq!(
+            installation.display_name.as_deref(),
+            Some("Visual Studio Community 2022")
+        );
+        assert_eq!(
+            installation.channel_id.as_deref(),
+            Some("VisualStudio.17.Preview")
+        );
+        assert_eq!(installation.product(), Some("Community"));
+        assert!(installation.prerelease);
+        assert!(!installation.legacy);
+        assert_eq!(installation.product_line_version.as_deref(), Some("2022"));
+        assert_eq!(
+            installation.update_date,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_717_344_190))
+        );
+        assert_eq!(installation.complete, Some(false));
+        assert_eq!(installation.launchable, Some(true));
+        assert!(!installation.is_usable());
+        // Incomplete instances from `-all` are never picked
+        let selection = Selection::new(MsvcArch::X64).prerelease(true);
+        assert!(select_instance(&instances, &selection).is_err());
+        // Without a path
+        assert_eq!(installation_from(&instances[1]), None);
+    }
+
+    #[test]
+    fn test_installations() {
+        // What `vswhere -all -prerelease -products * -format json` lists, trimmed
+        let output = r#"[
+            {
+                "instanceId": "5e6f7a8b",
+                "installDate": "2024-05-10T09:12:45Z",
+                "installationName": "VisualStudio/17.10.3+35013.160",
+                "installationPath": "C:\\Program Files (x86)\\Microsoft Visual Studio\\2022\\BuildTools",
+                "installationVersion": "17.10.35013.160",
+                "productId": "Microsoft.VisualStudio.Product.BuildTools",
+                "state": 4294967295,
+                "isComplete": true,
+                "isLaunchable": true,
+                "isPrerelease": false,
+                "isRebootRequired": false,
+                "displayName": "Visual Studio Build Tools 2022",
+                "channelId": "VisualStudio.17.Release",
+                "updateDate": "2024-06-02T16:03:10.4831234Z",
+                "catalog": {
+                    "buildVersion": "17.10.35013.160",
+                    "productDisplayVersion": "17.10.3",
+                    "productLineVersion": "2022"
+                },
+                "properties": {
+                    "nickname": "",
+                    "setupEngineFilePath": "C:\\Program Files (x86)\\Microsoft Visual Studio\\Installer\\setup.exe"
+                }
+            },
+            {
+                "instanceId": "9c0d1e2f",
+                "installationPath": "C:\\Program Files\\Microsoft Visual Studio\\2022\\Preview",
+                "installationVersion": "17.11.0",
+                "productId": "Microsoft.VisualStudio.Product.Community",
+                "isComplete": false,
+                "isLaunchable": false,
+                "isPrerelease": true
+            }
+        ]"#;
+        let installations = parse_vswhere_json(output)
+            .unwrap()
+            .iter()
+            .filter_map(installation_from)
+            .collect::<Vec<_>>();
+        assert_eq!(installations.len(), 2);
+        let build_tools = &installations[0];
+        assert_eq!(build_tools.product(), Some("BuildTools"));
+        assert_eq!(build_tools.product_line_version.as_deref(), Some("2022"));
+        assert_eq!(
+            build_tools.install_date,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_715_332_365))
+        );
+        assert!(build_tools.update_date > build_tools.install_date);
+        assert!(build_tools.is_usable() && !build_tools.prerelease);
+        let preview = &installations[1];
+        assert!(preview.prerelease && !preview.is_usable());
+        assert_eq!(preview.install_date, None);
+    }
+
+    #[test]
+    fn test_prerelease_order() {
+        let output = r#"[
+            {
+                "instanceId": "preview",
+                "installationPath": "C:\\Program Files\\Microsoft Visual Studio\\2022\\Preview",
+                "installationVersion": "17.11.0",
+                "isPrerelease": true
+            },
+            {
+                "instanceId": "stable",
+                "installationPath": "C:\\Program Files\\Microsoft Visual Studio\\2022\\Community",
+                "installationVersion": "17.10.0",
+                "isPrerelease": false
+            }
+        ]"#;
+        let instances = parse_vswhere_json(output).unwrap();
+        let id =
+            |selection: &Selection| select_instance(&instances, selection).unwrap().instance_id;
+        let selection = Selection::new(MsvcArch::X64);
+        assert_eq!(id(&selection).as_deref(), Some("stable"));
+        // The newest wins unless an order is given
+        let selection = selection.prerelease(true);
+        assert_eq!(id(&selection).as_deref(), Some("preview"));
+        let stable_first = selection
+            .clone()
+            .prerelease_order(PrereleaseOrder::StableFirst);
+        assert_eq!(id(&stable_first).as_deref(), Some("stable"));
+        let preview_first = selection.prerelease_order(PrereleaseOrder::PreviewFirst);
+        assert_eq!(id(&preview_first).as_deref(), Some("preview"));
+
+        // Only a preview installed
+        let preview_only = &instances[..1];
+        let selection = Selection::new(MsvcArch::X64);
+        assert!(select_instance(preview_only, &selection).is_err());
+        let selection = selection
+            .prerelease(true)
+            .prerelease_order(PrereleaseOrder::StableFirst);
+        assert!(
+            select_instance(preview_only, &selection)
+                .unwrap()
+                .prerelease
+        );
+    }
+
+    #[test]
+    fn test_vswhere_args() {
+        let selection = Selection::new(MsvcArch::X64);
+        assert_eq!(vswhere_args(&selection), ["-products", "*"]);
+        let legacy = selection.clone().legacy(true);
+        assert_eq!(vswhere_args(&legacy), ["-legacy"]);
+
+        let tools = "Microsoft.VisualStudio.Component.VC.Tools.x86.x64";
+        let atl = "Microsoft.VisualStudio.Component.VC.ATL";
+        let required = legacy.required_components(&[tools, atl]);
+        assert_eq!(
+            vswhere_args(&required),
+            ["-products", "*", "-requires", tools, atl]
+        );
+        let any = required
+            .requires_any(true)
+            .vs_version("[17.0,18.0)".parse().unwrap());
+        assert_eq!(
+            vswhere_args(&any),
+            [
+                "-products",
+                "*",
+                "-version",
+                "[17.0,18.0)",
+                "-requires",
+                tools,
+                atl,
+                "-requiresAny"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_select_instance_id() {
+        let output = r#"[
+            {
+                "instanceId": "newest",
+                "installationPath": "C:\\Program Files\\Microsoft Visual Studio\\2022\\Community",
+                "installationVersion": "17.10.0"
+            },
+            {
+                "instanceId": "preview",
+                "installationPath": "C:\\Program Files\\Microsoft Visual Studio\\2022\\Preview",
+                "installationVersion": "17.11.0",
+                "isPrerelease": true
+            },
+            {
+                "instanceId": "older",
+                "installationPath": "C:\\Program Files (x86)\\Microsoft Visual Studio\\2019\\BuildTools",
+                "installationVersion": "16.11.0"
+            }
+        ]"#;
+        let instances = parse_vswhere_json(output).unwrap();
+        let selection = Selection::new(MsvcArch::X64);
+        for id in ["older", "preview"] {
+            let installation = select_instance(&instances, &selection.clone().instance_id(id));
+            assert_eq!(installation.unwrap().instance_id.as_deref(), Some(id));
+        }
+        assert!(matches!(
+            select_instance(&instances, &selection.clone().instance_id("missing")),
+            Err(MsvcEnvError::NoVisualStudio)
+        ));
+
+        // By path, as written differently from vswhere
+        let path = r"c:\program files\microsoft visual studio\2022\preview\";
+        let installation = select_instance(&instances, &selection.clone().installation_path(path));
+        assert_eq!(
+            installation.unwrap().instance_id.as_deref(),
+            Some("preview")
+        );
+        let missing = selection.installation_path(r"C:\Program Files\Microsoft Visual Studio\2022");
+        assert!(select_instance(&instances, &missing).is_err());
+    }
+
+    #[test]
+    fn test_legacy_instance() {
+        let output = r#"[
+            {
+                "instanceId": "VisualStudio.14.0",
+                "installationPath": "C:\\Program Files (x86)\\Microsoft Visual Studio 14.0\\",
+                "installationVersion": "14.0"
+            }
+        ]"#;
+        let instances = parse_vswhere_json(output).unwrap();
+        let selection = Selection::new(MsvcArch::X64);
+        assert!(select_instance(&instances, &selection).is_err());
+
+        let selection = selection.legacy(true);
+        let installation = select_instance(&instances, &selection).unwrap();
+        assert!(installation.legacy);
+        assert_eq!(installation.version, Some("14.0".parse().unwrap()));
+        assert_eq!(installation.product_id, None);
+        assert!(installation.to_string().ends_with("(legacy)"));
+
+        // Which components it has is unknown
+        let required =
+            selection.required_components(&["Microsoft.VisualStudio.Component.VC.Tools.x86.x64"]);
+        assert!(select_instance(&instances, &required).is_err());
+    }
+
+    #[test]
+    fn test_system_vswhere_candidates() {
+        let path = std::env::join_paths(["/opt/bin", "/usr/bin"]).unwrap();
+        let candidates = system_vswhere_candidates(|key| match key {
+            "ProgramFiles(x86)" => Some(OsString::from("/pf86")),
+            "PATH" => Some(path.clone()),
+            _ => None,
+        });
+        assert_eq!(
+            candidates,
+            [
+                Path::new("/pf86/Microsoft Visual Studio/Installer/vswhere.exe"),
+                Path::new("/opt/bin/vswhere.exe"),
+                Path::new("/usr/bin/vswhere.exe"),
+            ]
+        );
+        assert!(system_vswhere_candidates(|_| None).is_empty());
+    }
+
+    #[test]
+    fn test_parse_vswhere_version() {
+        let banner = "Visual Studio Locator version 3.1.7+f39851e70f [query version 3.6.2112.28831]\r\nCopyright (C) Microsoft Corporation. All rights reserved.\r\n";
+        assert_eq!(parse_vswhere_version(banner).as_deref(), Some("3.1.7"));
+        assert_eq!(
+            parse_vswhere_version("Visual Studio Locator version 2.8.4 [query version 1.18.21]"),
+            Some("2.8.4".to_string())
+        );
+        assert_eq!(parse_vswhere_version("'-?' is not recognized"), None);
+        assert!(version_key("2.8.4") < version_key(cache::VSWHERE_VERSION));
+    }
+
+    #[test]
+    fn test_has_name() {
+        let output = r#"[
+            {
+                "instanceId": "1a2b3c4d",
+                "displayName": "Visual Studio Community 2022",
+                "properties": {
+                    "nickname": "VS2022 ASAN"
+                }
+            },
+            {
+                "instanceId": "5e6f7a8b",
+                "displayName": "Visual Studio Build Tools 2022"
+            }
+        ]"#;
+        let instances = parse_vswhere_json(output).unwrap();
+        assert!(has_name(&instances[0], "vs2022 asan"));
+        assert!(has_name(&instances[0], "Visual Studio Community 2022"));
+        assert!(!has_name(&instances[1], "VS2022 ASAN"));
+        assert!(has_name(&instances[1], "Visual Studio Build Tools 2022"));
+    }
+
+    #[test]
+    fn test_decode_utf8() {
+        let json = r#"[{"installationPath": "C:\\Users\\Jörg\\Visual Studio 2022"}]"#;
+        let mut output = b"\xEF\xBB\xBF".to_vec();
+        output.extend(json.as_bytes());
+        let decoded = decode_utf8(output).unwrap();
+        assert_eq!(decoded, json);
+        assert_eq!(
+            parse_vswhere_json(&decoded).unwrap()[0]["installationPath"],
+            "C:\\Users\\Jörg\\Visual Studio 2022"
+        );
+
+        // "Jörg" in code page 850, as vswhere wrote it without -utf8
+        assert!(decode_utf8(b"J\x94rg".to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_vcvarsall_arch() {
+        let arch = |arch, legacy| vcvarsall_arch(&Selection::new(arch), legacy);
+        assert_eq!(arch(MsvcArch::X64, false).unwrap(), "amd64");
+        assert_eq!(arch(MsvcArch::Arm64, false).unwrap(), "amd64_arm64");
+        assert_eq!(arch(MsvcArch::X86, true).unwrap(), "amd64_x86");
+        assert!(arch(MsvcArch::Arm64, true).is_err());
+        assert!(arch(MsvcArch::All, false).is_err());
+
+        let arm64_host = Selection::new(MsvcArch::X64).host_arch(MsvcArch::Arm64);
+        assert_eq!(vcvarsall_arch(&arm64_host, false).unwrap(), "arm64_amd64");
+        let x86_host = Selection::new(MsvcArch::X86).host_arch(MsvcArch::X86);
+        assert_eq!(vcvarsall_arch(&x86_host, false).unwrap(), "x86");
+    }
+
+    #[test]
+    fn test_vcvarsall_in() {
+        let root = std::env::temp_dir().join(format!("msvc-env-vcvarsall-{}", std::process::id()));
+        let vc = root.join("VC");
+        fs::create_dir_all(vc.join("Auxiliary").join("Build")).unwrap();
+        assert_eq!(vcvarsall_in(&root), None);
+
+        fs::write(vc.join("vcvarsall.bat"), "").unwrap();
+        assert_eq!(vcvarsall_in(&root), Some((vc.join("vcvarsall.bat"), true)));
+
+        let current = vc.join("Auxiliary").join("Build").join("vcvarsall.bat");
+        fs::write(&current, "").unwrap();
+        assert_eq!(vcvarsall_in(&root), Some((current, false)));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_check_components() {
+        let root = std::env::temp_dir().join(format!("msvc-env-components-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        match check_components(&root, &Selection::new(MsvcArch::X64)) {
+            Err(MsvcEnvError::MissingComponent { components, .. }) => {
+                assert!(components.contains(&"Microsoft.VisualStudio.Component.VC.Tools.x86.x64"));
+            }
+            other => panic!("expected MissingComponent, got {:?}", other),
+        }
+
+        let msvc = root
+            .join("VC")
+            .join("Tools")
+            .join("MSVC")
+            .join("14.40.33807");
+        fs::create_dir_all(msvc.join("bin").join("Hostx64").join("x64")).unwrap();
+        check_components(&root, &Selection::new(MsvcArch::X64)).unwrap();
+        assert_eq!(
+            installed_targets(&root, &Selection::new(MsvcArch::All)),
+            [MsvcArch::X64]
+        );
+        check_components(
+            &root,
+            &Selection::new(MsvcArch::X64).toolset("14.29".parse().unwrap()),
+        )
+        .unwrap();
+
+        let error = check_components(&root, &Selection::new(MsvcArch::Arm64)).unwrap_err();
+        assert!(
+            error.to_string().ends_with(
+                "add Microsoft.VisualStudio.Component.VC.Tools.ARM64 in the Visual Studio Installer"
+            ),
+            "{}",
+            error
+        );
+        assert_eq!(
+            error.install_guidance(MsvcArch::Arm64).unwrap().components,
+            ["Microsoft.VisualStudio.Component.VC.Tools.ARM64"]
+        );
+
+        // Only x64-hosted compilers are installed
+        let arm64_host = Selection::new(MsvcArch::X64).host_arch(MsvcArch::Arm64);
+        assert!(check_components(&root, &arm64_host).is_err());
+        assert_eq!(with_host_fallback(&root, &arm64_host), None);
+        let fallback = with_host_fallback(&root, &arm64_host.clone().host_fallback(true)).unwrap();
+        assert_eq!(fallback.host_arch, MsvcArch::X64);
+        assert_eq!(
+            with_host_fallback(&root, &Selection::new(MsvcArch::Arm64).host_fallback(true)),
+            None
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_devshell_script() {
+        let vs = Path::new(r"C:\Program Files\Bob's VS\2022\Community");
+        let script = devshell_script(
+            &vs.join("Common7")
+                .join("Tools")
+                .join("Microsoft.VisualStudio.DevShell.dll"),
+            vs,
+            &Selection::new(MsvcArch::Arm64).toolset("14.29".parse().unwrap()),
+        )
+        .unwrap();
+        assert!(script.contains(r"-VsInstallPath 'C:\Program Files\Bob''s VS\2022\Community'"));
+        assert!(script.contains("Community' -SkipAutomaticLocation -Arch arm64 -HostArch amd64"));
+        assert!(script.contains("-DevCmdArguments '-no_logo -vcvars_ver=14.29'"));
+        assert!(script.contains(capture::ENV_MARKER));
+
+        let selection =
+            Selection::new(MsvcArch::X64).start_dir(StartDir::Dir(PathBuf::from(r"D:\src")));
+        let script = devshell_script(vs, vs, &selection).unwrap();
+        assert!(script.contains(r"Community' -StartInPath 'D:\src' -Arch"));
+    }
+
+    #[test]
+    fn test_startdir_args() {
+        let selection = Selection::new(MsvcArch::X64);
+        assert_eq!(
+            startdir_args(&selection),
+            ("-startdir=none".to_string(), Vec::new())
+        );
+        let selection = selection.start_dir("auto".parse().unwrap());
+        assert_eq!(startdir_args(&selection).0, "-startdir=auto");
+        let selection = selection.start_dir(r"C:\src".parse().unwrap());
+        assert_eq!(
+            startdir_args(&selection),
+            (
+                "-startdir=auto".to_string(),
+                vec![("VSCMD_START_DIR", OsStr::new(r"C:\src"))]
+            )
+        );
+        assert!("".parse::<StartDir>().is_err());
+    }
+}
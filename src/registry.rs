@@ -0,0 +1,93 @@
+//! The registry entries Visual Studio 2015 and older were located through, for
+//! machines where vswhere finds nothing or cannot be run
+
+use crate::provider::{select_instance, vcvarsall_in};
+use crate::{Selection, VsInstallation, VsVersion, capture};
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+
+/// Maps each Visual Studio version to its `VC` directory. It lives in the
+/// 32-bit view of the registry.
+const VC7_KEY: &str = r"HKLM\SOFTWARE\Microsoft\VisualStudio\SxS\VC7";
+
+/// The newest installation registered under [`VC7_KEY`] that `selection`
+/// accepts, reported as legacy. Pinning a product, channel, name or required
+/// components rules all of them out, as the registry does not record those.
+pub(crate) fn find_instance(selection: &Selection) -> Option<VsInstallation> {
+    let mut command = Command::new("reg");
+    crate::native::hide_window(&mut command);
+    command.args(["query", VC7_KEY, "/reg:32"]);
+    capture::log_command(&command);
+    let output = command.output().ok()?;
+    if !output.status.success() {
+        tracing::trace!("{} is not in the registry", VC7_KEY);
+        return None;
+    }
+    let mut instances = parse_reg_query(&String::from_utf8_lossy(&output.stdout));
+    instances.retain(|instance| vcvarsall_in(Path::new(&instance["installationPath"])).is_some());
+    let installation = select_instance(&instances, &selection.clone().legacy(true)).ok()?;
+    tracing::trace!("Found {} in the registry", installation);
+    Some(installation)
+}
+
+/// Reads the `REG_SZ` values `reg query` lists for [`VC7_KEY`], e.g.
+/// `14.0    REG_SZ    C:\...\Microsoft Visual Studio 14.0\VC\`, as vswhere
+/// would report the installations they belong to with `-legacy`
+fn parse_reg_query(output: &str) -> Vec<HashMap<String, String>> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (name, vc_dir) = line.split_once("REG_SZ")?;
+            let name = name.trim();
+            name.parse::<VsVersion>().ok()?;
+            let (vs_path, _) = vc_dir.trim().trim_end_matches('\\').rsplit_once('\\')?;
+            Some(HashMap::from([
+                ("instanceId".to_string(), format!("VisualStudio.{}", name)),
+                ("installationPath".to_string(), vs_path.to_string()),
+                ("installationVersion".to_string(), name.to_string()),
+            ]))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MsvcArch, MsvcEnvError};
+
+    #[test]
+    fn test_parse_reg_query() {
+        let output = "\r
+HKEY_LOCAL_MACHINE\\SOFTWARE\\WOW6432Node\\Microsoft\\VisualStudio\\SxS\\VC7\r
+    12.0    REG_SZ    C:\\Program Files (x86)\\Microsoft Visual Studio 12.0\\VC\\\r
+    14.0    REG_SZ    C:\\Program Files (x86)\\Microsoft Visual Studio 14.0\\VC\\\r
+    FsharpInstallDir    REG_SZ    C:\\Program Files (x86)\\Microsoft SDKs\\F#\\4.0\\Framework\\v4.0\\\r
+\r
+";
+        let instances = parse_reg_query(output);
+        assert_eq!(instances.len(), 2);
+        assert_eq!(instances[1]["instanceId"], "VisualStudio.14.0");
+        assert_eq!(
+            instances[1]["installationPath"],
+            r"C:\Program Files (x86)\Microsoft Visual Studio 14.0"
+        );
+
+        let selection = Selection::new(MsvcArch::X64).legacy(true);
+        let installation = select_instance(&instances, &selection).unwrap();
+        assert!(installation.legacy);
+        assert_eq!(installation.version, Some("14.0".parse().unwrap()));
+
+        let selection = selection.vs_version("[12.0,14.0)".parse().unwrap());
+        let installation = select_instance(&instances, &selection).unwrap();
+        assert_eq!(installation.version, Some("12.0".parse().unwrap()));
+
+        let pinned = Selection::new(MsvcArch::X64)
+            .legacy(true)
+            .product_id("Microsoft.VisualStudio.Product.BuildTools");
+        assert!(matches!(
+            select_instance(&instances, &pinned),
+            Err(MsvcEnvError::NoVisualStudio)
+        ));
+    }
+}
@@ -0,0 +1,220 @@
+//! Records what vswhere and the setup scripts report on a machine with Visual
+//! Studio as fixtures, and replays them through the provider chain elsewhere,
+//! so discovery, selection and caching can be tested without an installation.
+//!
+//! A fixture directory holds `vswhere.json`, the JSON output of vswhere listing
+//! every instance, and one dotenv [snapshot](crate::snapshot) per selection:
+//!
+//! ```no_run
+//! # use msvc_env::replay::{RecordingProvider, ReplayProvider, record_vswhere};
+//! # use msvc_env::{MsvcArch, MsvcEnv, Selection, VswhereProvider};
+//! # use std::path::Path;
+//! let fixtures = Path::new("tests/fixtures/vs2022");
+//! // On a machine with Visual Studio
+//! record_vswhere(fixtures)?;
+//! let recording = MsvcEnv::builder()
+//!     .provider(RecordingProvider::new(VswhereProvider::new(), fixtures))
+//!     .build();
+//! recording.resolve(&Selection::new(MsvcArch::Arm64))?;
+//!
+//! // Anywhere
+//! let replay = MsvcEnv::builder().provider(ReplayProvider::new(fixtures)).build();
+//! let env = replay.resolve(&Selection::new(MsvcArch::Arm64))?;
+//! # Ok::<(), msvc_env::MsvcEnvError>(())
+//! ```
+
+use crate::provider::{parse_vswhere_json, select_instance};
+use crate::{
+    MsvcEnvError, MsvcEnvProvider, MsvcEnvironment, Selection, Stage, VsInstallation,
+    VswhereProvider, cache, capture, snapshot,
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Name of the recorded vswhere output in a fixture directory
+pub const VSWHERE_FIXTURE: &str = "vswhere.json";
+
+/// The fixture for `selection` in `dir`, named after its target and a hash of
+/// the whole selection
+pub fn fixture_path(dir: &Path, selection: &Selection) -> PathBuf {
+    dir.join(cache::env_file_name(selection))
+}
+
+/// Runs vswhere for every instance, including previews, and writes its output
+/// to [`VSWHERE_FIXTURE`] in `dir`
+pub fn record_vswhere(dir: &Path) -> Result<PathBuf, MsvcEnvError> {
+    let vswhere = VswhereProvider::new().download_vswhere()?;
+    let mut command = Command::new(vswhere);
+    command.args([
+        "-nologo",
+        "-utf8",
+        "-all",
+        "-prerelease",
+        "-sort",
+        "-products",
+        "*",
+        "-format",
+        "json",
+    ]);
+    crate::capture::log_command(&command);
+    let output = command
+        .output()
+        .map_err(|e| MsvcEnvError::VswhereError(e.to_string()))?;
+    if !output.status.success() {
+        return Err(capture::process_failed(Stage::Discovery, &command, &output));
+    }
+    fs::create_dir_all(dir)?;
+    let path = dir.join(VSWHERE_FIXTURE);
+    fs::write(&path, output.stdout)?;
+    Ok(path)
+}
+
+/// Passes environments through from another provider, saving each one as the
+/// fixture for its selection
+pub struct RecordingProvider<P> {
+    inner: P,
+    dir: PathBuf,
+}
+
+impl<P: MsvcEnvProvider> RecordingProvider<P> {
+    pub fn new(inner: P, dir: impl Into<PathBuf>) -> Self {
+        Self {
+            inner,
+            dir: dir.into(),
+        }
+    }
+}
+
+impl<P: MsvcEnvProvider> MsvcEnvProvider for RecordingProvider<P> {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn environment(&self, selection: &Selection) -> Result<MsvcEnvironment, MsvcEnvError> {
+        let env = self.inner.environment(selection)?;
+        fs::create_dir_all(&self.dir)?;
+        let path = fixture_path(&self.dir, selection);
+        tracing::trace!("Recording {:?} to {}", selection, path.display());
+        snapshot::save(&env, &path)?;
+        Ok(env)
+    }
+}
+
+/// Serves environments from a fixture directory. Selections without a fixture
+/// get [`MsvcEnvError::NoVisualStudio`], so the next provider is tried.
+#[derive(Debug, Clone)]
+pub struct ReplayProvider {
+    dir: PathBuf,
+}
+
+impl ReplayProvider {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// The installation the recorded vswhere output yields for `selection`,
+    /// picked the way [`VswhereProvider::find_instance`] picks one
+    pub fn find_instance(&self, selection: &Selection) -> Result<VsInstallation, MsvcEnvError> {
+        let path = self.dir.join(VSWHERE_FIXTURE);
+        let json = fs::read_to_string(&path)?;
+        select_instance(&parse_vswhere_json(&json)?, selection)
+    }
+}
+
+impl MsvcEnvProvider for ReplayProvider {
+    fn name(&self) -> &str {
+        "replay"
+    }
+
+    fn environment(&self, selection: &Selection) -> Result<MsvcEnvironment, MsvcEnvError> {
+        let path = fixture_path(&self.dir, selection);
+        if !path.is_file() {
+            tracing::trace!("No fixture for {:?} at {}", selection, path.display());
+            return Err(MsvcEnvError::NoVisualStudio);
+        }
+        let env = snapshot::load(&path)?;
+        if !self.dir.join(VSWHERE_FIXTURE).is_file() {
+            return Ok(env);
+        }
+        Ok(env.installed_from(self.find_instance(selection)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MockProvider, MsvcArch, MsvcEnv};
+
+    const VSWHERE: &str = r#"[
+        {
+            "instanceId": "1a2b3c4d",
+            "installationPath": "C:\\Program Files\\Microsoft Visual Studio\\2022\\Preview",
+            "installationVersion": "17.12.35309.182",
+            "isPrerelease": true,
+            "displayName": "Visual Studio Community 2022 Preview",
+            "productId": "Microsoft.VisualStudio.Product.Community",
+            "channelId": "VisualStudio.17.Preview"
+        },
+        {
+            "instanceId": "5e6f7a8b",
+            "installationPath": "C:\\Program Files (x86)\\Microsoft Visual Studio\\2022\\BuildTools",
+            "installationVersion": "17.10.35013.160",
+            "isPrerelease": false,
+            "displayName": "Visual Studio Build Tools 2022",
+            "productId": "Microsoft.VisualStudio.Product.BuildTools",
+            "channelId": "VisualStudio.17.Release"
+        },
+        {
+            "instanceId": "9c0d1e2f",
+            "installationPath": "C:\\Program Files (x86)\\Microsoft Visual Studio\\2019\\BuildTools",
+            "installationVersion": "16.11.35327.3",
+            "isPrerelease": false,
+            "displayName": "Visual Studio Build Tools 2019",
+            "productId": "Microsoft.VisualStudio.Product.BuildTools",
+            "channelId": "VisualStudio.16.Release"
+        }
+    ]"#;
+
+    #[test]
+    fn test_record_replay() {
+        let dir = std::env::temp_dir().join(format!("msvc-env-replay-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(VSWHERE_FIXTURE), VSWHERE).unwrap();
+
+        let selection = Selection::new(MsvcArch::Arm64);
+        let recorded = MsvcEnv::builder()
+            .provider(RecordingProvider::new(MockProvider::new(), &dir))
+            .build()
+            .resolve(&selection)
+            .unwrap();
+        assert!(fixture_path(&dir, &selection).is_file());
+
+        let replay = MsvcEnv::builder()
+            .provider(ReplayProvider::new(&dir))
+            .build();
+        let env = replay.resolve(&selection).unwrap();
+        assert_eq!(env.get("LIB"), recorded.get("LIB"));
+        let installation = env.installation().unwrap();
+        assert_eq!(
+            installation.version,
+            Some("17.10.35013.160".parse().unwrap())
+        );
+        assert!(matches!(
+            replay.resolve(&Selection::new(MsvcArch::X86)),
+            Err(MsvcEnvError::NoVisualStudio)
+        ));
+
+        let provider = ReplayProvider::new(&dir);
+        let preview = provider
+            .find_instance(&selection.clone().prerelease(true))
+            .unwrap();
+        assert!(preview.path.to_string_lossy().ends_with(r"2022\Preview"));
+        let vs2019 = provider
+            .find_instance(&selection.vs_version("[16.0,17.0)".parse().unwrap()))
+            .unwrap();
+        assert_eq!(vs2019.version, Some("16.11.35327.3".parse().unwrap()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,397 @@
+//! Describes which MSVC environment a caller wants
+
+use crate::{MsvcArch, MsvcEnvError, SdkVersion, ToolsetVersion, VsVersionRange, overrides};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// The parameters an environment is resolved for. Providers receive the whole
+/// selection and environments are cached per selection.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Selection {
+    /// Target architecture
+    pub arch: MsvcArch,
+    /// Architecture of the compilers themselves; x64 unless set
+    pub host_arch: MsvcArch,
+    /// MSVC toolset version or prefix, e.g. `14.29` (VsDevCmd `-vcvars_ver`)
+    pub toolset: Option<ToolsetVersion>,
+    /// Windows SDK version, e.g. `10.0.22621.0` (VsDevCmd `-winsdk`)
+    pub sdk: Option<SdkVersion>,
+    /// Oldest Windows SDK the resolved environment may use
+    pub min_sdk: Option<SdkVersion>,
+    /// Versions of acceptable installations, e.g. `[17.0,18.0)`
+    pub vs_version: Option<VsVersionRange>,
+    /// Only use installations from this channel, e.g. `VisualStudio.17.Release`
+    /// or `VisualStudio.17.Preview`
+    pub channel_id: Option<String>,
+    /// Only use installations of this product, e.g.
+    /// `Microsoft.VisualStudio.Product.BuildTools`
+    pub product_id: Option<String>,
+    /// Only use the installation with this display name or installer nickname,
+    /// e.g. `VS2022 ASAN`, for telling side-by-side installations apart
+    pub installation_name: Option<String>,
+    /// Only use the installation with this vswhere `instanceId`, e.g. one from
+    /// [`MsvcEnv::installations`](crate::MsvcEnv::installations), whether or
+    /// not it is a preview or legacy installation
+    pub instance_id: Option<String>,
+    /// Only use the installation at this path, ignoring case and trailing
+    /// separators, whether or not it is a preview or legacy installation. One
+    /// that is not registered, such as a copy, is used as long as it has the
+    /// setup scripts.
+    pub installation_path: Option<PathBuf>,
+    /// Also consider preview installations; implied by `channel_id` and
+    /// `installation_name`
+    pub prerelease: bool,
+    /// Whether stable or preview installations win when both are considered;
+    /// the newest of any wins if unset
+    pub prerelease_order: Option<PrereleaseOrder>,
+    /// Also consider installations only registered the pre-2017 way, such as
+    /// Visual Studio 2015 (vswhere `-legacy`). Has no effect with `product_id`,
+    /// which vswhere cannot search for together with it.
+    pub legacy: bool,
+    /// Only use installations with these component or workload IDs, e.g.
+    /// `Microsoft.VisualStudio.Component.VC.Tools.x86.x64` (vswhere
+    /// `-requires`). Rules out legacy installations, which do not report any.
+    pub required_components: Vec<String>,
+    /// Accept installations with any of `required_components` rather than all
+    /// of them (vswhere `-requiresAny`)
+    pub requires_any: bool,
+    /// Where VsDevCmd leaves the working directory of the setup shell
+    pub start_dir: StartDir,
+    /// Use the x64-hosted cross compilers when the installation has none running
+    /// on `host_arch`, instead of failing
+    pub host_fallback: bool,
+    /// The complete environment setup scripts start from instead of this
+    /// process's one. Must provide what cmd and the scripts rely on, such as
+    /// `SystemRoot`, `PATH` and `TEMP`.
+    pub base_env: Option<BTreeMap<String, String>>,
+}
+
+/// What VsDevCmd does with the working directory (`-startdir`). Extension
+/// scripts run by VsDevCmd see the result.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum StartDir {
+    /// Leave it unchanged (`-startdir=none`)
+    #[default]
+    None,
+    /// Change to `%VSCMD_START_DIR%` or else `%USERPROFILE%\source`
+    /// (`-startdir=auto`)
+    Auto,
+    /// Change to this directory, through `-startdir=auto` with `VSCMD_START_DIR`
+    Dir(PathBuf),
+}
+
+impl fmt::Display for StartDir {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartDir::None => f.write_str("none"),
+            StartDir::Auto => f.write_str("auto"),
+            StartDir::Dir(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// `none` or `auto`, any other value is taken as a directory
+impl FromStr for StartDir {
+    type Err = MsvcEnvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" => Err(MsvcEnvError::ConfigError(
+                "empty start directory".to_string(),
+            )),
+            "none" => Ok(StartDir::None),
+            "auto" => Ok(StartDir::Auto),
+            _ => Ok(StartDir::Dir(PathBuf::from(s))),
+        }
+    }
+}
+
+/// A Visual Studio edition, for picking one of several installed side by side
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum MsvcProduct {
+    BuildTools,
+    Community,
+    Professional,
+    Enterprise,
+    /// Whichever is newest
+    Any,
+}
+
+impl MsvcProduct {
+    const EDITIONS: [MsvcProduct; 4] = [
+        MsvcProduct::BuildTools,
+        MsvcProduct::Community,
+        MsvcProduct::Professional,
+        MsvcProduct::Enterprise,
+    ];
+
+    /// e.g. `BuildTools`, as in [`install::PRODUCTS`](crate::install::PRODUCTS)
+    fn as_str(self) -> &'static str {
+        match self {
+            MsvcProduct::BuildTools => "BuildTools",
+            MsvcProduct::Community => "Community",
+            MsvcProduct::Professional => "Professional",
+            MsvcProduct::Enterprise => "Enterprise",
+            MsvcProduct::Any => "any",
+        }
+    }
+
+    /// The product ID vswhere reports, e.g.
+    /// `Microsoft.VisualStudio.Product.BuildTools`; `None` for [`MsvcProduct::Any`]
+    pub fn product_id(self) -> Option<&'static str> {
+        match self {
+            MsvcProduct::BuildTools => Some("Microsoft.VisualStudio.Product.BuildTools"),
+            MsvcProduct::Community => Some("Microsoft.VisualStudio.Product.Community"),
+            MsvcProduct::Professional => Some("Microsoft.VisualStudio.Product.Professional"),
+            MsvcProduct::Enterprise => Some("Microsoft.VisualStudio.Product.Enterprise"),
+            MsvcProduct::Any => None,
+        }
+    }
+}
+
+impl fmt::Display for MsvcProduct {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A name of [`install::PRODUCTS`](crate::install::PRODUCTS) or a full product
+/// ID, ignoring case, or `any` or `*`
+impl FromStr for MsvcProduct {
+    type Err = MsvcEnvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "*" || s.eq_ignore_ascii_case("any") {
+            return Ok(MsvcProduct::Any);
+        }
+        let name = crate::install::parse_product(s)?;
+        Self::EDITIONS
+            .into_iter()
+            .find(|x| x.as_str() == name)
+            .ok_or_else(|| MsvcEnvError::ConfigError(format!("unknown product {:?}", s)))
+    }
+}
+
+/// Which installations are preferred when stable and preview ones are both
+/// acceptable, regardless of which is newer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum PrereleaseOrder {
+    /// Use a preview only when no stable installation matches
+    StableFirst,
+    /// Use a stable installation only when no preview matches
+    PreviewFirst,
+}
+
+impl fmt::Display for PrereleaseOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrereleaseOrder::StableFirst => f.write_str("stable-first"),
+            PrereleaseOrder::PreviewFirst => f.write_str("preview-first"),
+        }
+    }
+}
+
+/// `stable-first` or `preview-first`
+impl FromStr for PrereleaseOrder {
+    type Err = MsvcEnvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stable-first" => Ok(PrereleaseOrder::StableFirst),
+            "preview-first" => Ok(PrereleaseOrder::PreviewFirst),
+            _ => Err(MsvcEnvError::ConfigError(format!(
+                "unknown prerelease order {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+impl Selection {
+    pub fn new(arch: MsvcArch) -> Self {
+        Self {
+            arch,
+            host_arch: MsvcArch::X64,
+            toolset: None,
+            sdk: None,
+            min_sdk: None,
+            vs_version: None,
+            channel_id: None,
+            product_id: None,
+            installation_name: None,
+            instance_id: None,
+            installation_path: None,
+            prerelease: false,
+            prerelease_order: None,
+            legacy: false,
+            required_components: Vec::new(),
+            requires_any: false,
+            start_dir: StartDir::None,
+            host_fallback: false,
+            base_env: None,
+        }
+    }
+
+    /// Selection for the target in `MSVC_ENV_ARCH`, x64 if unset, with the other
+    /// [`overrides`](crate::overrides) applied
+    pub fn from_env() -> Result<Self, MsvcEnvError> {
+        Self::new(MsvcArch::X64).with_env_overrides()
+    }
+
+    /// Replaces the fields set through [`overrides`](crate::overrides)
+    pub fn with_env_overrides(self) -> Result<Self, MsvcEnvError> {
+        overrides::apply(self, overrides::var, true)
+    }
+
+    pub fn host_arch(mut self, host_arch: MsvcArch) -> Self {
+        self.host_arch = host_arch;
+        self
+    }
+
+    pub fn toolset(mut self, toolset: ToolsetVersion) -> Self {
+        self.toolset = Some(toolset);
+        self
+    }
+
+    pub fn sdk(mut self, sdk: SdkVersion) -> Self {
+        self.sdk = Some(sdk);
+        self
+    }
+
+    /// Fails resolving with [`MsvcEnvError::SdkTooOld`] when the environment
+    /// would use an older SDK, rather than letting builds fail on missing APIs
+    pub fn require_sdk_at_least(mut self, sdk: SdkVersion) -> Self {
+        self.min_sdk = Some(sdk);
+        self
+    }
+
+    pub fn vs_version(mut self, range: VsVersionRange) -> Self {
+        self.vs_version = Some(range);
+        self
+    }
+
+    pub fn channel_id(mut self, channel_id: impl Into<String>) -> Self {
+        self.channel_id = Some(channel_id.into());
+        self
+    }
+
+    pub fn product_id(mut self, product_id: impl Into<String>) -> Self {
+        self.product_id = Some(product_id.into());
+        self
+    }
+
+    /// Sets [`product_id`](Self::product_id) to that of `product`, or clears it
+    /// for [`MsvcProduct::Any`]
+    pub fn product(mut self, product: MsvcProduct) -> Self {
+        self.product_id = product.product_id().map(String::from);
+        self
+    }
+
+    pub fn installation_name(mut self, name: impl Into<String>) -> Self {
+        self.installation_name = Some(name.into());
+        self
+    }
+
+    pub fn instance_id(mut self, instance_id: impl Into<String>) -> Self {
+        self.instance_id = Some(instance_id.into());
+        self
+    }
+
+    pub fn installation_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.installation_path = Some(path.into());
+        self
+    }
+
+    pub fn prerelease(mut self, prerelease: bool) -> Self {
+        self.prerelease = prerelease;
+        self
+    }
+
+    pub fn prerelease_order(mut self, order: PrereleaseOrder) -> Self {
+        self.prerelease_order = Some(order);
+        self
+    }
+
+    pub fn legacy(mut self, legacy: bool) -> Self {
+        self.legacy = legacy;
+        self
+    }
+
+    /// Sets [`required_components`](Self::required_components)
+    pub fn required_components<S: AsRef<str>>(mut self, components: &[S]) -> Self {
+        self.required_components = components.iter().map(|x| x.as_ref().to_string()).collect();
+        self
+    }
+
+    pub fn requires_any(mut self, requires_any: bool) -> Self {
+        self.requires_any = requires_any;
+        self
+    }
+
+    pub fn start_dir(mut self, start_dir: StartDir) -> Self {
+        self.start_dir = start_dir;
+        self
+    }
+
+    pub fn host_fallback(mut self, host_fallback: bool) -> Self {
+        self.host_fallback = host_fallback;
+        self
+    }
+
+    /// Captures on top of exactly `vars`, so the result does not depend on the
+    /// calling process's environment
+    pub fn base_env<K, V>(mut self, vars: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let vars = vars.into_iter().map(|(k, v)| (k.into(), v.into()));
+        self.base_env = Some(vars.collect());
+        self
+    }
+}
+
+impl From<MsvcArch> for Selection {
+    fn from(arch: MsvcArch) -> Self {
+        Self::new(arch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_product() {
+        assert_eq!(
+            "buildtools".parse::<MsvcProduct>().unwrap(),
+            MsvcProduct::BuildTools
+        );
+        assert_eq!(
+            "Microsoft.VisualStudio.Product.Enterprise"
+                .parse::<MsvcProduct>()
+                .unwrap(),
+            MsvcProduct::Enterprise
+        );
+        assert_eq!("*".parse::<MsvcProduct>().unwrap(), MsvcProduct::Any);
+        assert!("Express".parse::<MsvcProduct>().is_err());
+
+        for product in [MsvcProduct::Community, MsvcProduct::Any] {
+            assert_eq!(product.to_string().parse::<MsvcProduct>().unwrap(), product);
+        }
+        let selection = Selection::new(MsvcArch::X64).product(MsvcProduct::Professional);
+        assert_eq!(
+            selection.product_id.as_deref(),
+            Some("Microsoft.VisualStudio.Product.Professional")
+        );
+        assert_eq!(selection.product(MsvcProduct::Any).product_id, None);
+    }
+}
@@ -0,0 +1,310 @@
+//! Discovery through the Visual Studio Setup Configuration COM API, which vswhere
+//! is built on, so installations are found without downloading or running it.
+//! Instances are described with the keys vswhere's output is flattened to, so
+//! selections apply to them the same way.
+
+use crate::provider::select_instance;
+use crate::{MsvcEnvError, Selection, VsInstallation};
+use std::collections::HashMap;
+
+/// The installation `selection` picks from those the setup API knows. `None` if
+/// the API is not available or none matches, e.g. because a channel or name is
+/// pinned, which the API is not asked for, so vswhere gets to look.
+pub(crate) fn find_instance(selection: &Selection) -> Option<VsInstallation> {
+    // The packages of each instance are not read, so vswhere checks them
+    if !selection.required_components.is_empty() {
+        return None;
+    }
+    let instances = match instances() {
+        Ok(instances) => instances,
+        Err(e) => {
+            tracing::trace!("Setup Configuration API unavailable: {}", e);
+            return None;
+        }
+    };
+    match select_instance(&instances, selection) {
+        Ok(installation) => Some(installation),
+        Err(e) => {
+            tracing::trace!(
+                "No match among {} instances from the Setup Configuration API: {}",
+                instances.len(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Every complete installation, with its `instanceId`, `installationPath`,
+/// `installationVersion`, `productId` and `isPrerelease`
+#[cfg(windows)]
+fn instances() -> Result<Vec<HashMap<String, String>>, MsvcEnvError> {
+    Ok(com::instances()?)
+}
+
+#[cfg(not(windows))]
+fn instances() -> Result<Vec<HashMap<String, String>>, MsvcEnvError> {
+    Err(MsvcEnvError::NoVisualStudio)
+}
+
+/// The interfaces of `Setup.Configuration.h`, declared up to the last method
+/// used; the rest of each vtable is never read, nor are the slots in between
+#[cfg(windows)]
+mod com {
+    use std::collections::HashMap;
+    use std::ffi::c_void;
+    use std::io;
+    use std::ptr;
+    use windows_sys::Win32::Foundation::{RPC_E_CHANGED_MODE, SysFreeString, SysStringLen};
+    use windows_sys::Win32::System::Com::{
+        CLSCTX_ALL, COINIT_MULTITHREADED, CoCreateInstance, CoInitializeEx, CoUninitialize,
+    };
+    use windows_sys::core::{GUID, HRESULT};
+
+    type Bstr = *const u16;
+    type GetString = unsafe extern "system" fn(*mut c_void, *mut Bstr) -> HRESULT;
+    type GetObject = unsafe extern "system" fn(*mut c_void, *mut *mut c_void) -> HRESULT;
+
+    const CLSID_SETUP_CONFIGURATION: GUID = GUID::from_u128(0x177f0c4a_1cd3_4de7_a32c_71dbbb9fa36d);
+    const IID_ISETUP_CONFIGURATION2: GUID = GUID::from_u128(0x26aab78c_4a60_49d6_af3b_3c35bc93365d);
+    const IID_ISETUP_INSTANCE2: GUID = GUID::from_u128(0x89143c9a_05af_49b0_b717_72e218a2185c);
+    const IID_ISETUP_INSTANCE_CATALOG: GUID =
+        GUID::from_u128(0x9ad8e40f_39a2_40f1_bf64_0a6c50dd9eeb);
+
+    /// `eComplete` of `InstanceState`: every flag set
+    const STATE_COMPLETE: u32 = u32::MAX;
+
+    #[repr(C)]
+    #[allow(dead_code)]
+    struct IUnknownVtbl {
+        query_interface:
+            unsafe extern "system" fn(*mut c_void, *const GUID, *mut *mut c_void) -> HRESULT,
+        add_ref: unsafe extern "system" fn(*mut c_void) -> u32,
+        release: unsafe extern "system" fn(*mut c_void) -> u32,
+    }
+
+    #[repr(C)]
+    #[allow(dead_code)]
+    struct ISetupConfiguration2Vtbl {
+        base: IUnknownVtbl,
+        enum_instances: GetObject,
+        get_instance_for_current_process: GetObject,
+        get_instance_for_path:
+            unsafe extern "system" fn(*mut c_void, *const u16, *mut *mut c_void) -> HRESULT,
+        enum_all_instances: GetObject,
+    }
+
+    #[repr(C)]
+    #[allow(dead_code)]
+    struct IEnumSetupInstancesVtbl {
+        base: IUnknownVtbl,
+        next: unsafe extern "system" fn(*mut c_void, u32, *mut *mut c_void, *mut u32) -> HRESULT,
+    }
+
+    #[repr(C)]
+    #[allow(dead_code)]
+    struct ISetupInstance2Vtbl {
+        base: IUnknownVtbl,
+        get_instance_id: GetString,
+        get_install_date: unsafe extern "system" fn(*mut c_void, *mut u64) -> HRESULT,
+        get_installation_name: GetString,
+        get_installation_path: GetString,
+        get_installation_version: GetString,
+        get_display_name: unsafe extern "system" fn(*mut c_void, u32, *mut Bstr) -> HRESULT,
+        get_description: unsafe extern "system" fn(*mut c_void, u32, *mut Bstr) -> HRESULT,
+        resolve_path: unsafe extern "system" fn(*mut c_void, *const u16, *mut Bstr) -> HRESULT,
+        get_state: unsafe extern "system" fn(*mut c_void, *mut u32) -> HRESULT,
+        get_packages: GetObject,
+        get_product: GetObject,
+    }
+
+    #[repr(C)]
+    #[allow(dead_code)]
+    struct ISetupPackageReferenceVtbl {
+        base: IUnknownVtbl,
+        get_id: GetString,
+    }
+
+    #[repr(C)]
+    #[allow(dead_code)]
+    struct ISetupInstanceCatalogVtbl {
+        base: IUnknownVtbl,
+        get_catalog_info: GetObject,
+        is_prerelease: unsafe extern "system" fn(*mut c_void, *mut i16) -> HRESULT,
+    }
+
+    /// An owned interface pointer, released on drop
+    struct ComPtr(*mut c_void);
+
+    impl ComPtr {
+        /// Takes ownership of the pointer a successful call stored in `object`
+        fn new(object: *mut c_void) -> io::Result<ComPtr> {
+            if object.is_null() {
+                return Err(io::Error::other("null interface pointer"));
+            }
+            Ok(ComPtr(object))
+        }
+
+        /// SAFETY: `T` must describe the start of the object's vtable
+        unsafe fn vtbl<T>(&self) -> &T {
+            unsafe { &**(self.0 as *const *const T) }
+        }
+
+        fn query(&self, iid: &GUID) -> io::Result<ComPtr> {
+            let mut object = ptr::null_mut();
+            // SAFETY: every interface starts with IUnknown
+            check(unsafe {
+                (self.vtbl::<IUnknownVtbl>().query_interface)(self.0, iid, &mut object)
+            })?;
+            ComPtr::new(object)
+        }
+
+        fn string(&self, method: GetString) -> io::Result<String> {
+            let mut bstr: Bstr = ptr::null();
+            // SAFETY: `method` comes from this object's vtable
+            check(unsafe { method(self.0, &mut bstr) })?;
+            if bstr.is_null() {
+                return Ok(String::new());
+            }
+            // SAFETY: a BSTR holds as many UTF-16 units as SysStringLen reports,
+            // and the caller frees it
+            unsafe {
+                let units = std::slice::from_raw_parts(bstr, SysStringLen(bstr) as usize);
+                let string = String::from_utf16_lossy(units);
+                SysFreeString(bstr);
+                Ok(string)
+            }
+        }
+
+        fn object(&self, method: GetObject) -> io::Result<ComPtr> {
+            let mut object = ptr::null_mut();
+            // SAFETY: `method` comes from this object's vtable
+            check(unsafe { method(self.0, &mut object) })?;
+            ComPtr::new(object)
+        }
+    }
+
+    impl Drop for ComPtr {
+        fn drop(&mut self) {
+            // SAFETY: the pointer is owned and every interface starts with IUnknown
+            unsafe { (self.vtbl::<IUnknownVtbl>().release)(self.0) };
+        }
+    }
+
+    /// COM initialized on this thread until dropped
+    struct ComInit {
+        initialized: bool,
+    }
+
+    impl ComInit {
+        fn new() -> io::Result<ComInit> {
+            // SAFETY: no reserved pointer is passed
+            let hr = unsafe { CoInitializeEx(ptr::null(), COINIT_MULTITHREADED) };
+            // A thread the caller set up as single-threaded apartment works as well
+            if hr == RPC_E_CHANGED_MODE {
+                return Ok(ComInit { initialized: false });
+            }
+            check(hr)?;
+            Ok(ComInit { initialized: true })
+        }
+    }
+
+    impl Drop for ComInit {
+        fn drop(&mut self) {
+            if self.initialized {
+                // SAFETY: balances the successful CoInitializeEx in `new`
+                unsafe { CoUninitialize() };
+            }
+        }
+    }
+
+    fn check(hr: HRESULT) -> io::Result<()> {
+        if hr < 0 {
+            return Err(io::Error::from_raw_os_error(hr));
+        }
+        Ok(())
+    }
+
+    pub(super) fn instances() -> io::Result<Vec<HashMap<String, String>>> {
+        let _com = ComInit::new()?;
+        let mut config = ptr::null_mut();
+        // SAFETY: the GUIDs are those of the setup configuration class and its
+        // interface, and `config` receives the new object
+        check(unsafe {
+            CoCreateInstance(
+                &CLSID_SETUP_CONFIGURATION,
+                ptr::null_mut(),
+                CLSCTX_ALL,
+                &IID_ISETUP_CONFIGURATION2,
+                &mut config,
+            )
+        })?;
+        let config = ComPtr::new(config)?;
+        // SAFETY: `config` was created for ISetupConfiguration2
+        let all = config
+            .object(unsafe { config.vtbl::<ISetupConfiguration2Vtbl>() }.enum_all_instances)?;
+
+        let mut found = Vec::new();
+        loop {
+            let mut instance = ptr::null_mut();
+            let mut fetched = 0;
+            // SAFETY: `all` is an IEnumSetupInstances with room for one instance
+            check(unsafe {
+                (all.vtbl::<IEnumSetupInstancesVtbl>().next)(all.0, 1, &mut instance, &mut fetched)
+            })?;
+            if fetched == 0 {
+                break;
+            }
+            let instance = ComPtr::new(instance)?.query(&IID_ISETUP_INSTANCE2)?;
+            if let Some(properties) = properties(&instance)? {
+                found.push(properties);
+            }
+        }
+        Ok(found)
+    }
+
+    /// The vswhere keys of a complete `instance`, `None` for one that is still
+    /// being installed or was left broken
+    fn properties(instance: &ComPtr) -> io::Result<Option<HashMap<String, String>>> {
+        // SAFETY: `instance` was queried for ISetupInstance2
+        let vtbl = unsafe { instance.vtbl::<ISetupInstance2Vtbl>() };
+        let mut state = 0;
+        // SAFETY: `state` is valid for writes
+        check(unsafe { (vtbl.get_state)(instance.0, &mut state) })?;
+        if state != STATE_COMPLETE {
+            return Ok(None);
+        }
+
+        let mut properties = HashMap::new();
+        properties.insert(
+            "instanceId".to_string(),
+            instance.string(vtbl.get_instance_id)?,
+        );
+        properties.insert(
+            "installationPath".to_string(),
+            instance.string(vtbl.get_installation_path)?,
+        );
+        properties.insert(
+            "installationVersion".to_string(),
+            instance.string(vtbl.get_installation_version)?,
+        );
+        properties.insert("isComplete".to_string(), "1".to_string());
+        if let Ok(product) = instance.object(vtbl.get_product) {
+            // SAFETY: GetProduct returns an ISetupPackageReference
+            let get_id = unsafe { product.vtbl::<ISetupPackageReferenceVtbl>() }.get_id;
+            properties.insert("productId".to_string(), product.string(get_id)?);
+        }
+        if let Ok(catalog) = instance.query(&IID_ISETUP_INSTANCE_CATALOG) {
+            let mut prerelease = 0;
+            // SAFETY: `catalog` was queried for ISetupInstanceCatalog
+            let is_prerelease =
+                unsafe { catalog.vtbl::<ISetupInstanceCatalogVtbl>() }.is_prerelease;
+            // SAFETY: `prerelease` is valid for writes
+            if check(unsafe { is_prerelease(catalog.0, &mut prerelease) }).is_ok() {
+                let value = if prerelease != 0 { "1" } else { "0" };
+                properties.insert("isPrerelease".to_string(), value.to_string());
+            }
+        }
+        Ok(Some(properties))
+    }
+}
@@ -0,0 +1,145 @@
+//! 8.3 short names for search path entries, for legacy tools that cannot handle
+//! spaces such as the one in `Program Files (x86)`
+
+use crate::{MsvcEnvError, MsvcEnvironment};
+use std::collections::HashMap;
+
+/// Variables whose entries [`MsvcEnvironment::shorten_paths`] converts
+pub const SHORT_PATH_VARS: &[&str] = &["PATH", "INCLUDE", "LIB"];
+
+/// Room left for `cmd /d /c for ...` itself below cmd's command line limit
+#[cfg_attr(all(windows, feature = "windows-sys"), allow(dead_code))]
+const MAX_BATCH_LEN: usize = crate::limits::MAX_CMD_LINE - 100;
+
+impl MsvcEnvironment {
+    /// Replaces the entries of PATH, INCLUDE and LIB that contain spaces with
+    /// their 8.3 short names (`C:\PROGRA~2\...`). Entries that do not exist, or
+    /// live on volumes without short names, are kept as they are. Does nothing
+    /// outside Windows.
+    pub fn shorten_paths(&mut self) -> Result<(), MsvcEnvError> {
+        if !cfg!(windows) {
+            return Ok(());
+        }
+        let mut long = Vec::new();
+        for key in SHORT_PATH_VARS {
+            for entry in self.get(key).unwrap_or_default().split(';') {
+                if entry.contains(' ') && !long.contains(&entry.to_string()) {
+                    long.push(entry.to_string());
+                }
+            }
+        }
+        if long.is_empty() {
+            return Ok(());
+        }
+        let short = short_names(&long)?;
+
+        for (key, value) in self.vars.iter_mut() {
+            if SHORT_PATH_VARS.iter().any(|x| x.eq_ignore_ascii_case(key)) {
+                *value = value
+                    .split(';')
+                    .map(|x| short.get(x).map_or(x, |x| x.as_str()))
+                    .collect::<Vec<_>>()
+                    .join(";");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Asks Windows for the short name of every path
+#[cfg(all(windows, feature = "windows-sys"))]
+fn short_names(paths: &[String]) -> Result<HashMap<String, String>, MsvcEnvError> {
+    Ok(paths
+        .iter()
+        .filter_map(|path| Some((path.clone(), crate::native::short_path(path)?)))
+        .collect())
+}
+
+/// Asks cmd for the short name of every path, as `%~sI` of a `for` loop does
+#[cfg(not(all(windows, feature = "windows-sys")))]
+fn short_names(paths: &[String]) -> Result<HashMap<String, String>, MsvcEnvError> {
+    use crate::{Stage, capture};
+    use std::process::Command;
+
+    let mut names = HashMap::new();
+    for batch in batches(paths) {
+        let mut command = Command::new("cmd");
+        crate::native::hide_window(&mut command);
+        command.arg("/d").arg("/c");
+        // cmd does not understand the `\"` escaping of regular arguments
+        #[cfg(windows)]
+        std::os::windows::process::CommandExt::raw_arg(&mut command, for_loop(batch));
+        #[cfg(not(windows))]
+        command.arg(for_loop(batch));
+        capture::set_safe_current_dir(&mut command, None);
+        capture::log_command(&command);
+        let output = command
+            .output()
+            .map_err(|e| MsvcEnvError::VcvarsError(e.to_string()))?;
+        if !output.status.success() {
+            return Err(capture::process_failed(Stage::Capture, &command, &output));
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        for (path, short) in batch.iter().zip(stdout.lines()) {
+            // An empty result means the path does not exist
+            if !short.trim().is_empty() {
+                names.insert(path.clone(), short.trim().to_string());
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// Splits `paths` so the `for` loop of each part fits on a cmd command line
+#[cfg_attr(all(windows, feature = "windows-sys"), allow(dead_code))]
+fn batches(paths: &[String]) -> Vec<&[String]> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let mut len = 0;
+    for (i, path) in paths.iter().enumerate() {
+        if len + path.len() + 3 > MAX_BATCH_LEN && i > start {
+            batches.push(&paths[start..i]);
+            start = i;
+            len = 0;
+        }
+        len += path.len() + 3;
+    }
+    if start < paths.len() {
+        batches.push(&paths[start..]);
+    }
+    batches
+}
+
+/// `for %I in ("a" "b") do @echo %~sI`, which prints one short name per line
+#[cfg_attr(all(windows, feature = "windows-sys"), allow(dead_code))]
+fn for_loop(paths: &[String]) -> String {
+    let quoted = paths
+        .iter()
+        .map(|x| format!("\"{}\"", x))
+        .collect::<Vec<_>>();
+    format!("for %I in ({}) do @echo %~sI", quoted.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_for_loop() {
+        let paths = [
+            r"C:\Program Files (x86)\Windows Kits\10\bin",
+            r"C:\Program Files\Microsoft Visual Studio",
+        ]
+        .map(String::from);
+        assert_eq!(
+            for_loop(&paths),
+            r#"for %I in ("C:\Program Files (x86)\Windows Kits\10\bin" "C:\Program Files\Microsoft Visual Studio") do @echo %~sI"#
+        );
+
+        let many = vec!["x".repeat(1000); 20];
+        let parts = batches(&many);
+        assert_eq!(parts.iter().map(|x| x.len()).sum::<usize>(), 20);
+        assert!(parts.iter().all(|x| for_loop(x).len() < MAX_BATCH_LEN));
+        assert!(batches(&[]).is_empty());
+    }
+}
@@ -0,0 +1,163 @@
+//! Reads environments back from the files [`format`](crate::format) writes, so a
+//! captured environment can be stored and applied later, even on a machine
+//! without Visual Studio
+
+use crate::{MsvcEnvError, MsvcEnvironment};
+use std::fs;
+use std::path::Path;
+
+/// Reads a snapshot, as JSON if `path` ends in `.json` and as a dotenv file
+/// otherwise
+pub fn load(path: &Path) -> Result<MsvcEnvironment, MsvcEnvError> {
+    if is_json(path) {
+        #[cfg(feature = "serde")]
+        return MsvcEnvironment::from_json(path);
+        #[cfg(not(feature = "serde"))]
+        return Err(json_unsupported(path));
+    }
+    MsvcEnvironment::from_dotenv(path)
+}
+
+/// Writes `env` in the format [`load`] expects for `path`
+pub fn save(env: &MsvcEnvironment, path: &Path) -> Result<(), MsvcEnvError> {
+    let text = if is_json(path) {
+        #[cfg(feature = "serde")]
+        {
+            crate::format::json(env)
+        }
+        #[cfg(not(feature = "serde"))]
+        return Err(json_unsupported(path));
+    } else {
+        crate::format::docker_env_file(env)
+    };
+    fs::write(path, text)?;
+    Ok(())
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|x| x.eq_ignore_ascii_case("json"))
+}
+
+#[cfg(not(feature = "serde"))]
+fn json_unsupported(path: &Path) -> MsvcEnvError {
+    MsvcEnvError::ConfigError(format!(
+        "{}: JSON snapshots need the serde feature",
+        path.display()
+    ))
+}
+
+/// Variables every usable environment sets; a snapshot without them is rejected
+pub const REQUIRED_VARS: &[&str] = &["PATH", "INCLUDE", "LIB"];
+
+impl MsvcEnvironment {
+    /// Loads a `KEY=value` file as written by
+    /// [`docker_env_file`](crate::format::docker_env_file). Values are read
+    /// verbatim; blank lines and lines starting with `#` are skipped.
+    pub fn from_dotenv(path: &Path) -> Result<Self, MsvcEnvError> {
+        let text = fs::read_to_string(path)?;
+        Self::parse_dotenv(&text)
+            .map_err(|e| MsvcEnvError::ParseError(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Loads a JSON object of variables as written by [`json`](crate::format::json)
+    #[cfg(feature = "serde")]
+    pub fn from_json(path: &Path) -> Result<Self, MsvcEnvError> {
+        let text = fs::read_to_string(path)?;
+        Self::parse_json(&text)
+            .map_err(|e| MsvcEnvError::ParseError(format!("{}: {}", path.display(), e)))
+    }
+
+    fn parse_dotenv(text: &str) -> Result<Self, String> {
+        let mut env = MsvcEnvironment::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim_start();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once('=') {
+                Some((key, value)) if !key.trim().is_empty() => {
+                    env.vars.insert(key.trim().to_string(), value.to_string());
+                }
+                _ => return Err(format!("line {} is not KEY=value", number + 1)),
+            }
+        }
+        env.checked()
+    }
+
+    #[cfg(feature = "serde")]
+    fn parse_json(text: &str) -> Result<Self, String> {
+        let vars = serde_json::from_str::<indexmap::IndexMap<String, String>>(text)
+            .map_err(|e| e.to_string())?;
+        MsvcEnvironment::new(vars).checked()
+    }
+
+    /// `self` in the order of [`sort`](Self::sort) if it has every one of the
+    /// [`REQUIRED_VARS`]
+    fn checked(mut self) -> Result<Self, String> {
+        let missing = REQUIRED_VARS
+            .iter()
+            .filter(|key| self.get(key).is_none())
+            .copied()
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(format!("missing required variables {}", missing.join(", ")));
+        }
+        self.sort();
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MsvcArch;
+    use crate::provider::MockProvider;
+
+    #[test]
+    fn test_parse_dotenv() {
+        let env = MockProvider::fake_environment(MsvcArch::X64);
+        let text = crate::format::docker_env_file(&env);
+        let parsed = MsvcEnvironment::parse_dotenv(&format!("# snapshot\n\n{}", text)).unwrap();
+        assert_eq!(parsed.vars, env.vars);
+
+        assert_eq!(
+            MsvcEnvironment::parse_dotenv("PATH=C:\\Windows\nINCLUDE=").unwrap_err(),
+            "missing required variables LIB"
+        );
+        assert_eq!(
+            MsvcEnvironment::parse_dotenv("PATH\n").unwrap_err(),
+            "line 1 is not KEY=value"
+        );
+    }
+
+    #[test]
+    fn test_save_load() {
+        let root = std::env::temp_dir().join(format!("msvc-env-snapshot-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let env = MockProvider::fake_environment(MsvcArch::X86);
+        let names = if cfg!(feature = "serde") {
+            &["env", "snapshot.JSON"][..]
+        } else {
+            &["env"]
+        };
+        for name in names {
+            let path = root.join(name);
+            save(&env, &path).unwrap();
+            assert_eq!(load(&path).unwrap().vars, env.vars);
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_parse_json() {
+        let env = MockProvider::fake_environment(MsvcArch::Arm64);
+        let parsed = MsvcEnvironment::parse_json(&crate::format::json(&env)).unwrap();
+        assert_eq!(parsed.vars, env.vars);
+
+        assert!(MsvcEnvironment::parse_json(r#"{"PATH": "C:\\Windows"}"#).is_err());
+        assert!(MsvcEnvironment::parse_json(r#"["PATH"]"#).is_err());
+    }
+}
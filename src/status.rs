@@ -0,0 +1,103 @@
+//! Marks processes that have an MSVC environment applied, so prompts can show it
+//! and tools can avoid applying a second one on top
+
+use crate::{MsvcArch, MsvcEnvError, MsvcEnvironment, ToolsetVersion};
+use std::fmt;
+use std::str::FromStr;
+
+/// Set to `<arch>/<toolset>`, e.g. `x64/14.40.33807`, wherever this crate
+/// applies an environment: by [`CommandExt::msvc_env`](crate::CommandExt::msvc_env)
+/// and by the scripts `msvc-env print` emits
+pub const ACTIVE_VAR: &str = "MSVC_ENV_ACTIVE";
+
+/// Which environment [`ACTIVE_VAR`] says is applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ActiveEnvironment {
+    pub arch: MsvcArch,
+    /// `None` if the environment did not name its toolset
+    pub toolset: Option<ToolsetVersion>,
+}
+
+impl ActiveEnvironment {
+    /// Describes `env` by its target architecture and toolset; `None` if it does
+    /// not say which architecture it targets
+    pub fn of(env: &MsvcEnvironment) -> Option<Self> {
+        Some(Self {
+            arch: env.get("VSCMD_ARG_TGT_ARCH")?.trim().parse().ok()?,
+            toolset: env
+                .get("VCToolsVersion")
+                .and_then(|x| x.trim().parse().ok()),
+        })
+    }
+}
+
+impl fmt::Display for ActiveEnvironment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.arch.as_str())?;
+        if let Some(toolset) = &self.toolset {
+            write!(f, "/{}", toolset)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ActiveEnvironment {
+    type Err = MsvcEnvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (arch, toolset) = match s.trim().split_once('/') {
+            Some((arch, toolset)) => (arch, Some(toolset.parse()?)),
+            None => (s.trim(), None),
+        };
+        Ok(Self {
+            arch: arch.parse()?,
+            toolset,
+        })
+    }
+}
+
+/// The environment applied to the current process, if any
+pub fn active() -> Option<ActiveEnvironment> {
+    let value = crate::overrides::var(ACTIVE_VAR)?;
+    value
+        .parse()
+        .inspect_err(|e| tracing::debug!("Ignoring {}={:?}: {}", ACTIVE_VAR, value, e))
+        .ok()
+}
+
+impl MsvcEnvironment {
+    /// Adds [`ACTIVE_VAR`] describing this environment, so whatever it is applied
+    /// to can tell that it is active
+    pub fn mark_active(&mut self) {
+        if let Some(active) = ActiveEnvironment::of(self) {
+            self.vars.insert(ACTIVE_VAR.to_string(), active.to_string());
+            self.sort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockProvider;
+
+    #[test]
+    fn test_active_environment() {
+        let mut env = MockProvider::fake_environment(MsvcArch::Arm64);
+        env.mark_active();
+        assert_eq!(env.get(ACTIVE_VAR), Some("arm64/14.40.33807"));
+
+        let active = "arm64/14.40.33807".parse::<ActiveEnvironment>().unwrap();
+        assert_eq!(active, ActiveEnvironment::of(&env).unwrap());
+        assert_eq!(
+            "x86".parse::<ActiveEnvironment>().unwrap(),
+            ActiveEnvironment {
+                arch: MsvcArch::X86,
+                toolset: None
+            }
+        );
+        assert!("amd64/14.40".parse::<ActiveEnvironment>().is_err());
+        assert!("x64/latest".parse::<ActiveEnvironment>().is_err());
+    }
+}
@@ -0,0 +1,399 @@
+//! Structured checks of a resolved environment, for tools that need to tell users
+//! exactly what is wrong with their installation
+
+use crate::install::InstallGuidance;
+use crate::provider::vcvarsall_in;
+use crate::{MsvcArch, MsvcEnv, MsvcEnvError, MsvcEnvironment, Selection, Stage, VswhereProvider};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A single problem found while validating an environment
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Finding {
+    /// No provider produced an environment; holds the error message
+    ResolveFailed(String),
+    /// The installation has no `vcvarsall.bat`
+    MissingBatFile(PathBuf),
+    /// A directory listed in INCLUDE, LIB or LIBPATH does not exist
+    MissingDirectory {
+        variable: &'static str,
+        path: PathBuf,
+    },
+    /// The environment was set up for a different target than requested
+    WrongTargetArch { expected: MsvcArch, actual: String },
+    /// `cl /?` could not be run or exited with an error
+    CompilerFailed(String),
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Finding::ResolveFailed(message) => write!(f, "no environment: {}", message),
+            Finding::MissingBatFile(path) => write!(f, "missing {}", path.display()),
+            Finding::MissingDirectory { variable, path } => {
+                write!(f, "{} entry does not exist: {}", variable, path.display())
+            }
+            Finding::WrongTargetArch { expected, actual } => write!(
+                f,
+                "expected target architecture {} but VSCMD_ARG_TGT_ARCH is {:?}",
+                expected, actual
+            ),
+            Finding::CompilerFailed(message) => write!(f, "cl failed to run: {}", message),
+        }
+    }
+}
+
+/// Everything found wrong with the environment for a selection
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ValidationReport {
+    pub selection: Selection,
+    pub findings: Vec<Finding>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.findings.is_empty()
+    }
+}
+
+/// An error VsDevCmd reported while verifying its own setup, printed as
+/// `[ERROR:<script>] <message>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SelfTestError {
+    /// The setup script that failed, e.g. `vcvars.bat`
+    pub script: String,
+    pub message: String,
+}
+
+impl fmt::Display for SelfTestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.script, self.message)
+    }
+}
+
+/// Result of `VsDevCmd.bat -test`, Visual Studio's own check of the environment
+/// it sets up
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SelfTestReport {
+    pub selection: Selection,
+    /// The `VsDevCmd.bat` that ran the tests
+    pub script: PathBuf,
+    pub exit_code: Option<i32>,
+    pub errors: Vec<SelfTestError>,
+    /// Everything the script printed
+    pub output: String,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.exit_code == Some(0) && self.errors.is_empty()
+    }
+}
+
+impl MsvcEnv {
+    /// Has VsDevCmd verify the environment it sets up for `selection`, which
+    /// catches broken extension scripts that [`validate`](Self::validate) can't
+    /// see. Only installations found with vswhere can be tested this way.
+    pub fn self_test(&self, selection: &Selection) -> Result<SelfTestReport, MsvcEnvError> {
+        VswhereProvider::new().self_test(&self.pins.apply(selection))
+    }
+
+    /// Resolves the environment for `selection` and checks that it is usable: the
+    /// setup script for the architecture exists, the include and library
+    /// directories exist, the target matches and the compiler runs
+    pub fn validate(&self, selection: &Selection) -> ValidationReport {
+        let findings = match self.resolve(selection) {
+            Ok(env) => check(&env, selection.arch),
+            Err(e) => vec![Finding::ResolveFailed(e.to_string())],
+        };
+
+        ValidationReport {
+            selection: selection.clone(),
+            findings,
+        }
+    }
+}
+
+/// Outcome of asking one provider in the chain for an environment
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ProviderCheck {
+    pub name: String,
+    /// `None` if the provider produced an environment
+    pub error: Option<String>,
+    pub stage: Option<Stage>,
+}
+
+/// Preflight report for IDE plugins and installers: what each provider did, what
+/// is wrong with the environment that was chosen, and how to fix a missing install
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DiagnosisReport {
+    pub selection: Selection,
+    /// Providers in chain order, up to and including the one that succeeded
+    pub providers: Vec<ProviderCheck>,
+    /// Problems with the environment the chain resolved to
+    pub findings: Vec<Finding>,
+    /// Set when installing or modifying Build Tools would fix the failure
+    pub install: Option<InstallGuidance>,
+    /// Command lines from `install`, for reports rendered without this crate
+    pub install_commands: Vec<String>,
+}
+
+impl DiagnosisReport {
+    pub fn is_healthy(&self) -> bool {
+        self.findings.is_empty() && self.install.is_none()
+    }
+}
+
+impl fmt::Display for DiagnosisReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for provider in &self.providers {
+            match &provider.error {
+                Some(error) => writeln!(f, "provider {}: {}", provider.name, error)?,
+                None => writeln!(f, "provider {}: ok", provider.name)?,
+            }
+        }
+        for finding in &self.findings {
+            writeln!(f, "problem: {}", finding)?;
+        }
+        if let Some(install) = &self.install {
+            writeln!(f, "{}", install)?;
+        }
+        Ok(())
+    }
+}
+
+impl MsvcEnv {
+    /// Runs every provider in the chain until one succeeds, bypassing the cache,
+    /// and validates the resulting environment
+    pub fn diagnose(&self, selection: &Selection) -> DiagnosisReport {
+        let selection = &self.pins.apply(selection);
+        let mut providers = Vec::new();
+        let mut findings = Vec::new();
+        let mut first_error = None;
+        let mut resolved = false;
+
+        for provider in &self.providers {
+            match provider.environment(selection) {
+                Ok(env) => {
+                    providers.push(ProviderCheck {
+                        name: provider.name().to_string(),
+                        error: None,
+                        stage: None,
+                    });
+                    findings = check(&env, selection.arch);
+                    resolved = true;
+                    break;
+                }
+                Err(e) => {
+                    providers.push(ProviderCheck {
+                        name: provider.name().to_string(),
+                        error: Some(e.to_string()),
+                        stage: e.stage(),
+                    });
+                    if first_error.is_none() && !matches!(e, MsvcEnvError::NoVisualStudio) {
+                        first_error = Some(e);
+                    }
+                }
+            }
+        }
+
+        let install = if resolved {
+            None
+        } else {
+            first_error
+                .unwrap_or(MsvcEnvError::NoVisualStudio)
+                .install_guidance(selection.arch)
+        };
+        let install_commands = install
+            .iter()
+            .flat_map(|x| [x.winget_command(), x.vs_installer_command()])
+            .collect();
+
+        DiagnosisReport {
+            selection: selection.clone(),
+            providers,
+            findings,
+            install,
+            install_commands,
+        }
+    }
+}
+
+/// Collects the `[ERROR:<script>] <message>` lines VsDevCmd prints
+pub(crate) fn parse_self_test_output(output: &str) -> Vec<SelfTestError> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (script, message) = line.trim().strip_prefix("[ERROR:")?.split_once(']')?;
+            Some(SelfTestError {
+                script: script.to_string(),
+                message: message.trim().to_string(),
+            })
+        })
+        .collect()
+}
+
+fn check(env: &MsvcEnvironment, arch: MsvcArch) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    // Only installations set up by VsDevCmd carry the vcvars scripts; fixed
+    // directories and portable toolchains don't. Which per-target script there
+    // is depends on the host and the layout, so only vcvarsall is looked for.
+    if let Some(vs_path) = env.get("VSINSTALLDIR")
+        && vcvarsall_in(Path::new(vs_path)).is_none()
+    {
+        let bat = Path::new(vs_path)
+            .join("VC")
+            .join("Auxiliary")
+            .join("Build")
+            .join("vcvarsall.bat");
+        findings.push(Finding::MissingBatFile(bat));
+    }
+
+    if let Some(actual) = env.get("VSCMD_ARG_TGT_ARCH")
+        && arch != MsvcArch::All
+        && !actual.eq_ignore_ascii_case(arch.as_str())
+    {
+        findings.push(Finding::WrongTargetArch {
+            expected: arch,
+            actual: actual.to_string(),
+        });
+    }
+
+    findings.extend(
+        env.missing_paths()
+            .into_iter()
+            .filter(|(variable, _)| *variable != "PATH")
+            .map(|(variable, path)| Finding::MissingDirectory { variable, path }),
+    );
+
+    match Command::new("cl").arg("/?").envs(&env.vars).output() {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => findings.push(Finding::CompilerFailed(format!(
+            "exited with {}",
+            output.status
+        ))),
+        Err(e) => findings.push(Finding::CompilerFailed(e.to_string())),
+    }
+
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockProvider;
+
+    #[test]
+    fn test_validate() {
+        let mut env = MockProvider::fake_environment(MsvcArch::X86);
+        env.vars
+            .insert("VSCMD_ARG_TGT_ARCH".to_string(), "x86".to_string());
+        let msvc_env = MsvcEnv::builder()
+            .provider(MockProvider::empty().with_environment(MsvcArch::X64, env))
+            .build();
+
+        let report = msvc_env.validate(&Selection::new(MsvcArch::X64));
+        assert!(!report.is_valid());
+        assert!(report.findings.iter().any(
+            |f| matches!(f, Finding::MissingBatFile(path) if path.ends_with("vcvarsall.bat"))
+        ));
+        assert!(report.findings.contains(&Finding::WrongTargetArch {
+            expected: MsvcArch::X64,
+            actual: "x86".to_string(),
+        }));
+        assert!(report.findings.iter().any(|f| matches!(
+            f,
+            Finding::MissingDirectory {
+                variable: "INCLUDE",
+                ..
+            }
+        )));
+        assert!(
+            report
+                .findings
+                .iter()
+                .any(|f| matches!(f, Finding::CompilerFailed(_)))
+        );
+
+        let report = msvc_env.validate(&Selection::new(MsvcArch::Arm64));
+        assert!(matches!(report.findings[..], [Finding::ResolveFailed(_)]));
+    }
+
+    #[test]
+    fn test_diagnose() {
+        let msvc_env = MsvcEnv::builder()
+            .provider(crate::FixedPathProvider::new("/nonexistent/msvc-env"))
+            .provider(MockProvider::new())
+            .build();
+
+        let report = msvc_env.diagnose(&Selection::new(MsvcArch::X64));
+        assert_eq!(report.providers.len(), 2);
+        assert_eq!(report.providers[0].stage, Some(Stage::Discovery));
+        assert_eq!(report.providers[1].error, None);
+        assert!(report.install.is_none());
+        assert!(!report.findings.is_empty());
+        assert!(!report.is_healthy());
+
+        let msvc_env = MsvcEnv::builder().provider(MockProvider::empty()).build();
+        let report = msvc_env.diagnose(&Selection::new(MsvcArch::Arm64));
+        assert_eq!(
+            report.install.unwrap().components,
+            crate::install::required_components(MsvcArch::Arm64)
+        );
+        assert_eq!(report.install_commands.len(), 2);
+    }
+
+    #[test]
+    fn test_check_vs2015_layout() {
+        let root = std::env::temp_dir().join(format!("msvc-env-check-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("VC")).unwrap();
+        std::fs::write(root.join("VC").join("vcvarsall.bat"), "").unwrap();
+        let env = MsvcEnvironment::new(indexmap::IndexMap::from([(
+            "VSINSTALLDIR".to_string(),
+            root.display().to_string(),
+        )]));
+
+        let findings = check(&env, MsvcArch::Arm64);
+        assert!(
+            !findings
+                .iter()
+                .any(|f| matches!(f, Finding::MissingBatFile(_)))
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_parse_self_test_output() {
+        let output = "\
+**********************************************************************
+** Visual Studio 2022 Developer Command Prompt v17.10.3
+**********************************************************************
+[ERROR:VsDevCmd.bat] Script \"vsdevcmd\\ext\\vcvars.bat\" could not be found.
+[ERROR:vcvars.bat] Toolset directory for version '14.29' was not found.
+";
+        assert_eq!(
+            parse_self_test_output(output),
+            [
+                SelfTestError {
+                    script: "VsDevCmd.bat".to_string(),
+                    message: r#"Script "vsdevcmd\ext\vcvars.bat" could not be found."#.to_string(),
+                },
+                SelfTestError {
+                    script: "vcvars.bat".to_string(),
+                    message: "Toolset directory for version '14.29' was not found.".to_string(),
+                },
+            ]
+        );
+        assert!(parse_self_test_output("** Developer Command Prompt\n").is_empty());
+    }
+}
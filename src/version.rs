@@ -0,0 +1,378 @@
+//! Version numbers of Visual Studio and its components, compared numerically
+//! instead of as strings
+
+use crate::MsvcEnvError;
+use std::fmt;
+use std::str::FromStr;
+
+/// A Visual Studio `installationVersion` such as `17.10.35013.160`. Missing
+/// trailing parts are zero, so `17.10` sorts before `17.10.35013.160`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct VsVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub build: u32,
+    pub revision: u32,
+}
+
+impl VsVersion {
+    pub fn new(major: u32, minor: u32) -> Self {
+        Self {
+            major,
+            minor,
+            build: 0,
+            revision: 0,
+        }
+    }
+}
+
+impl FromStr for VsVersion {
+    type Err = MsvcEnvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || MsvcEnvError::ParseError(format!("invalid Visual Studio version {:?}", s));
+        let parts = s
+            .trim()
+            .split('.')
+            .map(|x| x.parse::<u32>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>, _>>()?;
+        if parts.len() > 4 {
+            return Err(invalid());
+        }
+
+        let part = |i: usize| parts.get(i).copied().unwrap_or(0);
+        Ok(Self {
+            major: part(0),
+            minor: part(1),
+            build: part(2),
+            revision: part(3),
+        })
+    }
+}
+
+/// Writes `major.minor`, followed by the build and revision only when they
+/// carry information
+impl fmt::Display for VsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)?;
+        if self.build != 0 || self.revision != 0 {
+            write!(f, ".{}", self.build)?;
+        }
+        if self.revision != 0 {
+            write!(f, ".{}", self.revision)?;
+        }
+        Ok(())
+    }
+}
+
+/// A range of Visual Studio versions in vswhere's `-version` syntax: `[17.0,18.0)`
+/// for 2022 only, `[17.8,]` or just `17.8` for 17.8 and newer, `[17.8]` for
+/// exactly 17.8
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct VsVersionRange {
+    pub min: Option<VsVersion>,
+    pub min_inclusive: bool,
+    pub max: Option<VsVersion>,
+    pub max_inclusive: bool,
+}
+
+impl VsVersionRange {
+    /// Versions with the major version of `major`, e.g. 17 for Visual Studio 2022
+    pub fn major(major: u32) -> Self {
+        Self {
+            min: Some(VsVersion::new(major, 0)),
+            min_inclusive: true,
+            max: Some(VsVersion::new(major + 1, 0)),
+            max_inclusive: false,
+        }
+    }
+
+    pub fn contains(&self, version: &VsVersion) -> bool {
+        let above_min = self.min.is_none_or(|min| {
+            if self.min_inclusive {
+                *version >= min
+            } else {
+                *version > min
+            }
+        });
+        let below_max = self.max.is_none_or(|max| {
+            if self.max_inclusive {
+                *version <= max
+            } else {
+                *version < max
+            }
+        });
+        above_min && below_max
+    }
+}
+
+impl FromStr for VsVersionRange {
+    type Err = MsvcEnvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || MsvcEnvError::ParseError(format!("invalid version range {:?}", s));
+        let bound = |x: &str| {
+            let x = x.trim();
+            if x.is_empty() {
+                Ok(None)
+            } else {
+                x.parse::<VsVersion>().map(Some).map_err(|_| invalid())
+            }
+        };
+
+        let min_inclusive = match s.chars().next() {
+            Some('[') => true,
+            Some('(') => false,
+            _ => {
+                // A bare version is a minimum
+                return Ok(Self {
+                    min: Some(s.parse().map_err(|_| invalid())?),
+                    min_inclusive: true,
+                    max: None,
+                    max_inclusive: false,
+                });
+            }
+        };
+        let max_inclusive = match s.chars().last() {
+            Some(']') => true,
+            Some(')') => false,
+            _ => return Err(invalid()),
+        };
+        let inner = &s[1..s.len() - 1];
+
+        match inner.split_once(',') {
+            Some((min, max)) => Ok(Self {
+                min: bound(min)?,
+                min_inclusive,
+                max: bound(max)?,
+                max_inclusive,
+            }),
+            None if min_inclusive && max_inclusive => {
+                let exact = bound(inner)?.ok_or_else(invalid)?;
+                Ok(Self {
+                    min: Some(exact),
+                    min_inclusive,
+                    max: Some(exact),
+                    max_inclusive,
+                })
+            }
+            None => Err(invalid()),
+        }
+    }
+}
+
+/// Writes the range in the syntax vswhere's `-version` accepts
+impl fmt::Display for VsVersionRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.min_inclusive { "[" } else { "(" })?;
+        if let Some(min) = &self.min {
+            write!(f, "{}", min)?;
+        }
+        f.write_str(",")?;
+        if let Some(max) = &self.max {
+            write!(f, "{}", max)?;
+        }
+        f.write_str(if self.max_inclusive { "]" } else { ")" })
+    }
+}
+
+/// An MSVC toolset version such as `14.38.33130`, as found in
+/// `VC\Tools\MSVC`. A shorter version like `14.38` can be used as a pin that
+/// [`matches`](Self::matches) every toolset it is a prefix of.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ToolsetVersion(Vec<u32>);
+
+impl ToolsetVersion {
+    pub fn major(&self) -> u32 {
+        self.0[0]
+    }
+
+    pub fn minor(&self) -> u32 {
+        self.0.get(1).copied().unwrap_or(0)
+    }
+
+    /// Whether this installed toolset satisfies the `pin`, e.g. `14.38.33130`
+    /// satisfies `14.38` and `14.38.33130` but not `14.3`
+    pub fn matches(&self, pin: &ToolsetVersion) -> bool {
+        self.0.starts_with(&pin.0)
+    }
+
+    /// The MSBuild platform toolset this version belongs to: `v140` for 14.0,
+    /// `v141` for 14.1x, `v142` for 14.2x and `v143` for 14.3x and 14.4x
+    pub fn platform_toolset(&self) -> Option<&'static str> {
+        match (self.major(), self.minor()) {
+            (14, 0) => Some("v140"),
+            (14, 10..=19) => Some("v141"),
+            (14, 20..=29) => Some("v142"),
+            (14, 30..=49) => Some("v143"),
+            _ => None,
+        }
+    }
+
+    /// Whether code built with `other` can be linked with code built with this
+    /// toolset without rebuilding, which holds within a platform toolset family
+    pub fn is_compatible_with(&self, other: &ToolsetVersion) -> bool {
+        self.platform_toolset()
+            .is_some_and(|x| Some(x) == other.platform_toolset())
+    }
+}
+
+impl FromStr for ToolsetVersion {
+    type Err = MsvcEnvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s
+            .trim()
+            .split('.')
+            .map(|x| x.parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| MsvcEnvError::ParseError(format!("invalid toolset version {:?}", s)))?;
+        Ok(Self(parts))
+    }
+}
+
+impl fmt::Display for ToolsetVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts = self.0.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        f.write_str(&parts.join("."))
+    }
+}
+
+/// A Windows SDK version such as `10.0.22621.0`, as found in
+/// `Windows Kits\10\Include`. Like a [`ToolsetVersion`], a shorter version such as
+/// `10.0.22621` pins every SDK it is a prefix of.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SdkVersion(Vec<u32>);
+
+impl SdkVersion {
+    /// Whether this installed SDK satisfies the `pin`
+    pub fn matches(&self, pin: &SdkVersion) -> bool {
+        self.0.starts_with(&pin.0)
+    }
+
+    /// Picks the oldest of the `installed` SDKs that is at least `sdk`, so a
+    /// project builds against the lowest SDK that has the APIs it needs
+    pub fn minimum<'a>(
+        installed: impl IntoIterator<Item = &'a SdkVersion>,
+        sdk: &SdkVersion,
+    ) -> Option<&'a SdkVersion> {
+        installed.into_iter().filter(|x| *x >= sdk).min()
+    }
+}
+
+impl FromStr for SdkVersion {
+    type Err = MsvcEnvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s
+            .trim()
+            .trim_end_matches('\\')
+            .split('.')
+            .map(|x| x.parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| MsvcEnvError::ParseError(format!("invalid SDK version {:?}", s)))?;
+        Ok(Self(parts))
+    }
+}
+
+impl fmt::Display for SdkVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts = self.0.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        f.write_str(&parts.join("."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(s: &str) -> VsVersion {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_vs_version() {
+        assert!(version("17.10.35013.160") > version("17.9.34728.123"));
+        assert!(version("17.10") < version("17.10.35013.160"));
+        assert_eq!(version("17.10.35013.160").to_string(), "17.10.35013.160");
+        assert_eq!(version("17").to_string(), "17.0");
+        assert!("17.x".parse::<VsVersion>().is_err());
+        assert!("1.2.3.4.5".parse::<VsVersion>().is_err());
+    }
+
+    #[test]
+    fn test_vs_version_range() {
+        let range = "[17.0,18.0)".parse::<VsVersionRange>().unwrap();
+        assert_eq!(range, VsVersionRange::major(17));
+        assert!(range.contains(&version("17.14.36301.6")));
+        assert!(!range.contains(&version("18.0")));
+        assert!(!range.contains(&version("16.11.5")));
+        assert_eq!(range.to_string(), "[17.0,18.0)");
+
+        let range = "17.8".parse::<VsVersionRange>().unwrap();
+        assert!(range.contains(&version("17.8")));
+        assert!(range.contains(&version("18.0")));
+        assert_eq!(range.to_string(), "[17.8,)");
+
+        let exact = "[17.8.3]".parse::<VsVersionRange>().unwrap();
+        assert!(exact.contains(&version("17.8.3")));
+        assert!(!exact.contains(&version("17.8.4")));
+
+        let below = "(,17.0)".parse::<VsVersionRange>().unwrap();
+        assert!(below.contains(&version("16.11")));
+        assert!(!below.contains(&version("17.0")));
+
+        assert!("[17.0,18.0".parse::<VsVersionRange>().is_err());
+        assert!("(17.0)".parse::<VsVersionRange>().is_err());
+    }
+
+    #[test]
+    fn test_toolset_version() {
+        let toolset = "14.38.33130".parse::<ToolsetVersion>().unwrap();
+        assert!(toolset > "14.29.30133".parse().unwrap());
+        assert!(toolset < "14.40.33807".parse().unwrap());
+        assert_eq!(toolset.to_string(), "14.38.33130");
+
+        assert!(toolset.matches(&"14.38".parse().unwrap()));
+        assert!(!toolset.matches(&"14.3".parse().unwrap()));
+        assert!(!toolset.matches(&"14.38.33131".parse().unwrap()));
+
+        assert_eq!(toolset.platform_toolset(), Some("v143"));
+        assert!(toolset.is_compatible_with(&"14.44.35207".parse().unwrap()));
+        assert!(!toolset.is_compatible_with(&"14.29.30133".parse().unwrap()));
+
+        assert!("14.x".parse::<ToolsetVersion>().is_err());
+        assert!("".parse::<ToolsetVersion>().is_err());
+    }
+
+    #[test]
+    fn test_sdk_version() {
+        let sdk = |s: &str| s.parse::<SdkVersion>().unwrap();
+        assert!(sdk("10.0.22621.0") > sdk("10.0.9200.0"));
+        assert_eq!(sdk("10.0.22621.0\\").to_string(), "10.0.22621.0");
+        assert!(sdk("10.0.22621.0").matches(&sdk("10.0.22621")));
+        assert!(!sdk("10.0.22621.0").matches(&sdk("10.0.2262")));
+
+        let installed = [
+            sdk("10.0.22621.0"),
+            sdk("10.0.19041.0"),
+            sdk("10.0.26100.0"),
+        ];
+        assert_eq!(
+            SdkVersion::minimum(&installed, &sdk("10.0.19042.0")),
+            Some(&sdk("10.0.22621.0"))
+        );
+        assert_eq!(
+            SdkVersion::minimum(&installed, &sdk("10.0.19041.0")),
+            Some(&sdk("10.0.19041.0"))
+        );
+        assert_eq!(SdkVersion::minimum(&installed, &sdk("10.0.26200.0")), None);
+
+        assert!("10.0.x".parse::<SdkVersion>().is_err());
+    }
+}
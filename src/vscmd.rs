@@ -0,0 +1,165 @@
+//! The `VSCMD_*` variables VsDevCmd leaves behind to describe how it was invoked
+
+use crate::{MsvcArch, MsvcEnvironment, SdkVersion, Selection, ToolsetVersion, VsVersion};
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+
+/// How VsDevCmd configured an environment, read back from the `VSCMD_*`
+/// variables it exports. Fields are `None` when VsDevCmd did not set the
+/// variable or its value could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct VsCmdInfo {
+    /// Version of the developer command prompt scripts (`VSCMD_VER`)
+    pub version: Option<VsVersion>,
+    /// `VSCMD_ARG_HOST_ARCH`
+    pub host_arch: Option<MsvcArch>,
+    /// `VSCMD_ARG_TGT_ARCH`
+    pub target_arch: Option<MsvcArch>,
+    /// `Desktop` or `UWP` (`VSCMD_ARG_app_plat`)
+    pub app_platform: Option<String>,
+    /// The toolset requested with `-vcvars_ver` (`VSCMD_ARG_VCVARS_VER`)
+    pub toolset: Option<ToolsetVersion>,
+    /// The SDK requested with `-winsdk` (`VSCMD_ARG_winsdk`)
+    pub sdk: Option<SdkVersion>,
+    /// `spectre` when the Spectre-mitigated libraries are used (`VSCMD_ARG_VCVARS_SPECTRE`)
+    pub spectre_libs: Option<String>,
+}
+
+impl VsCmdInfo {
+    /// Reads the `VSCMD_*` variables of `env`; `None` if VsDevCmd did not produce it
+    pub fn from_environment(env: &MsvcEnvironment) -> Option<Self> {
+        let version = env.get("VSCMD_VER")?;
+        let text = |key: &str| env.get(key).map(|x| x.trim().to_string());
+        Some(Self {
+            version: version.trim().parse().ok(),
+            host_arch: parse(env, "VSCMD_ARG_HOST_ARCH"),
+            target_arch: parse(env, "VSCMD_ARG_TGT_ARCH"),
+            app_platform: text("VSCMD_ARG_app_plat"),
+            toolset: parse(env, "VSCMD_ARG_VCVARS_VER"),
+            sdk: parse(env, "VSCMD_ARG_winsdk"),
+            spectre_libs: text("VSCMD_ARG_VCVARS_SPECTRE"),
+        })
+    }
+
+    /// Describes every way this configuration differs from what `selection`
+    /// asked for; empty if it is the requested one
+    pub fn mismatches(&self, selection: &Selection) -> Vec<String> {
+        let mut mismatches = Vec::new();
+        if selection.arch != MsvcArch::All && self.target_arch != Some(selection.arch) {
+            mismatches.push(format!(
+                "target architecture is {}, expected {}",
+                shown(self.target_arch),
+                selection.arch
+            ));
+        }
+        if self.host_arch != Some(selection.host_arch) {
+            mismatches.push(format!(
+                "host architecture is {}, expected {}",
+                shown(self.host_arch),
+                selection.host_arch
+            ));
+        }
+        if let Some(pin) = &selection.toolset
+            && !self.toolset.as_ref().is_some_and(|x| x.matches(pin))
+        {
+            mismatches.push(format!(
+                "toolset is {}, expected {}",
+                shown(self.toolset.as_ref()),
+                pin
+            ));
+        }
+        if let Some(pin) = &selection.sdk
+            && !self.sdk.as_ref().is_some_and(|x| x.matches(pin))
+        {
+            mismatches.push(format!(
+                "SDK is {}, expected {}",
+                shown(self.sdk.as_ref()),
+                pin
+            ));
+        }
+        mismatches
+    }
+}
+
+impl MsvcEnvironment {
+    /// The environment of this process when it already runs inside a developer
+    /// prompt, i.e. `VSCMD_VER` is set, as it is for tools launched from Visual
+    /// Studio. Nothing is spawned; `None` outside a developer prompt.
+    pub fn from_current_env() -> Option<Self> {
+        Self::from_vars(
+            env::vars_os()
+                .filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?))),
+        )
+    }
+
+    /// [`from_current_env`](Self::from_current_env) over the given variables
+    pub(crate) fn from_vars(vars: impl IntoIterator<Item = (String, String)>) -> Option<Self> {
+        let mut env = MsvcEnvironment::new(vars.into_iter().collect());
+        env.get("VSCMD_VER")?;
+        env.sort();
+        tracing::trace!("Using the developer prompt this process runs in");
+        Some(env)
+    }
+}
+
+fn parse<T: FromStr>(env: &MsvcEnvironment, key: &str) -> Option<T> {
+    env.get(key).and_then(|x| x.trim().parse().ok())
+}
+
+/// Writes a value that may be missing, for [`VsCmdInfo::mismatches`]
+fn shown(value: Option<impl fmt::Display>) -> String {
+    value.map_or("unset".to_string(), |x| x.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockProvider;
+
+    #[test]
+    fn test_vscmd_info() {
+        let mut env = MockProvider::fake_environment(MsvcArch::Arm64);
+        env.vars
+            .insert("VSCMD_ARG_app_plat".to_string(), "Desktop".to_string());
+        env.vars
+            .insert("VSCMD_ARG_VCVARS_VER".to_string(), "14.40".to_string());
+
+        let info = env.vscmd().unwrap();
+        assert_eq!(info.version, Some("17.10.3".parse().unwrap()));
+        assert_eq!(info.host_arch, Some(MsvcArch::X64));
+        assert_eq!(info.target_arch, Some(MsvcArch::Arm64));
+        assert_eq!(info.app_platform.as_deref(), Some("Desktop"));
+        assert_eq!(info.sdk, None);
+
+        let selection = Selection::new(MsvcArch::Arm64).toolset("14.40".parse().unwrap());
+        assert!(info.mismatches(&selection).is_empty());
+        let selection = Selection::new(MsvcArch::X86).sdk("10.0.26100".parse().unwrap());
+        assert_eq!(
+            info.mismatches(&selection),
+            [
+                r#"target architecture is "arm64", expected "x86""#,
+                "SDK is unset, expected 10.0.26100",
+            ]
+        );
+
+        env.vars.shift_remove("VSCMD_VER");
+        assert_eq!(env.vscmd(), None);
+    }
+
+    #[test]
+    fn test_from_vars() {
+        let vars = [
+            ("PATH", r"C:\Windows"),
+            ("VSCMD_ARG_TGT_ARCH", "x64"),
+            ("VSCMD_VER", "17.10.3"),
+        ]
+        .map(|(k, v)| (k.to_string(), v.to_string()));
+        let env = MsvcEnvironment::from_vars(vars.clone()).unwrap();
+        assert_eq!(env.vars.keys().last().map(|x| &**x), Some("PATH"));
+        assert_eq!(env.vscmd().unwrap().target_arch, Some(MsvcArch::X64));
+
+        assert!(MsvcEnvironment::from_vars(vars.into_iter().skip(1).take(1)).is_none());
+    }
+}
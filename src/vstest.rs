@@ -0,0 +1,182 @@
+//! Running test containers with the `vstest.console.exe` of an installation,
+//! inside a resolved environment, and reading back how many tests passed
+
+use crate::{MsvcArch, MsvcEnv, MsvcEnvError, MsvcEnvironment, Stage, capture};
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The component that installs vstest.console.exe into Build Tools
+const TEST_TOOLS_COMPONENT: &str = "Microsoft.VisualStudio.Component.TestTools.BuildTools";
+
+/// Where installations keep vstest.console.exe, newest layout first
+const VSTEST_DIRS: &[&str] = &[
+    r"Common7\IDE\Extensions\TestPlatform",
+    r"Common7\IDE\CommonExtensions\Microsoft\TestWindow",
+];
+
+const VSTEST_EXE: &str = "vstest.console.exe";
+
+/// Test counts from the summary vstest prints, added up over every container
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TestSummary {
+    pub total: u32,
+    pub passed: u32,
+    pub failed: u32,
+    pub skipped: u32,
+}
+
+impl TestSummary {
+    /// Whether tests ran and none failed
+    pub fn is_success(&self) -> bool {
+        self.total > 0 && self.failed == 0
+    }
+}
+
+impl fmt::Display for TestSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} tests: {} passed, {} failed, {} skipped",
+            self.total, self.passed, self.failed, self.skipped
+        )
+    }
+}
+
+/// vstest.console.exe in the installation at `vs_path`
+pub fn find(vs_path: &Path) -> Option<PathBuf> {
+    VSTEST_DIRS
+        .iter()
+        .map(|dir| vs_path.join(dir).join(VSTEST_EXE))
+        .find(|path| path.exists())
+}
+
+impl MsvcEnv {
+    /// vstest.console.exe of the installation, see [`find`]
+    pub fn vstest_path(&self) -> Result<PathBuf, MsvcEnvError> {
+        let vs_path = self.find_visual_studio()?;
+        find(&vs_path).ok_or_else(|| MsvcEnvError::MissingComponent {
+            arch: MsvcArch::All,
+            installation: vs_path,
+            what: "vstest.console.exe".to_string(),
+            components: vec![TEST_TOOLS_COMPONENT],
+        })
+    }
+}
+
+/// Runs the test `containers` with `vstest` in `env`, passing `args` such as
+/// `/Platform:x64` or `/TestCaseFilter:...` along. Failing tests are not an
+/// error; check [`TestSummary::is_success`]. Output without a summary, e.g.
+/// because a container could not be loaded, is.
+pub fn run<I, S>(
+    env: &MsvcEnvironment,
+    vstest: &Path,
+    containers: &[impl AsRef<Path>],
+    args: I,
+) -> Result<TestSummary, MsvcEnvError>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut command = Command::new(vstest);
+    command
+        .args(containers.iter().map(|x| x.as_ref()))
+        .args(args)
+        .envs(&env.vars);
+    capture::log_command(&command);
+    let output = command.output()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    match parse_summary(&stdout) {
+        Some(summary) => Ok(summary),
+        None if output.status.success() => Err(MsvcEnvError::ParseError(format!(
+            "no test summary in the output of {}",
+            vstest.display()
+        ))),
+        None => Err(capture::process_failed(Stage::Parse, &command, &output)),
+    }
+}
+
+/// Reads the summary of vstest output. Newer versions print one line per
+/// container, e.g. `Failed!  - Failed: 1, Passed: 11, Skipped: 0, Total: 12`,
+/// which are added up; older ones a single block of `Total tests: 12`,
+/// `Passed: 11` and `Failed: 1` lines.
+fn parse_summary(output: &str) -> Option<TestSummary> {
+    let mut lines = None::<TestSummary>;
+    let mut block = None::<TestSummary>;
+    for line in output.lines() {
+        let counts = line
+            .split([',', '.', '-'])
+            .filter_map(|part| {
+                let (key, value) = part.split_once(':')?;
+                let value = value.split_whitespace().next()?.parse::<u32>().ok()?;
+                Some((key.trim(), value))
+            })
+            .collect::<Vec<_>>();
+        let has_total = counts
+            .iter()
+            .any(|(key, _)| matches!(*key, "Total" | "Total tests"));
+        let target = if has_total && counts.len() > 1 {
+            lines.get_or_insert_default()
+        } else if !counts.is_empty() && (has_total || block.is_some()) {
+            block.get_or_insert_default()
+        } else {
+            continue;
+        };
+        for (key, value) in counts {
+            match key {
+                "Total" | "Total tests" => target.total += value,
+                "Passed" => target.passed += value,
+                "Failed" => target.failed += value,
+                "Skipped" => target.skipped += value,
+                _ => {}
+            }
+        }
+    }
+    lines.or(block)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_summary() {
+        let output = "\
+Starting test execution, please wait...
+A total of 2 test files matched the specified pattern.
+Failed!  - Failed:     1, Passed:    11, Skipped:     0, Total:    12, Duration: 1.5 s - a.dll (net8.0)
+Passed!  - Failed:     0, Passed:     3, Skipped:     1, Total:     4, Duration: 20 ms - b.dll (net8.0)
+";
+        let summary = parse_summary(output).unwrap();
+        assert_eq!(
+            summary,
+            TestSummary {
+                total: 16,
+                passed: 14,
+                failed: 1,
+                skipped: 1
+            }
+        );
+        assert!(!summary.is_success());
+
+        let output = "\
+Passed   Add
+Total tests: 2
+     Passed: 2
+ Total time: 0.5 Seconds
+";
+        let summary = parse_summary(output).unwrap();
+        assert_eq!((summary.total, summary.passed, summary.failed), (2, 2, 0));
+        assert!(summary.is_success());
+
+        assert_eq!(
+            parse_summary("Total tests: 3. Passed: 2. Failed: 1. Skipped: 0.")
+                .unwrap()
+                .failed,
+            1
+        );
+        assert_eq!(parse_summary("No test is available in a.dll"), None);
+    }
+}
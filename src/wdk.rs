@@ -0,0 +1,223 @@
+//! Windows Driver Kit detection, layered on top of a captured MSVC environment
+
+use crate::layout::latest_version_dir;
+use crate::{MsvcArch, MsvcEnv, MsvcEnvError, MsvcEnvironment};
+use std::path::PathBuf;
+
+/// The driver framework a build targets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DriverModel {
+    /// Plain kernel-mode driver without a framework
+    Wdm,
+    /// Kernel-Mode Driver Framework
+    Kmdf,
+    /// User-Mode Driver Framework
+    Umdf,
+}
+
+/// A WDK installed alongside the Windows SDK used by an environment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Wdk {
+    /// Kit root, e.g. `C:\Program Files (x86)\Windows Kits\10`
+    pub root: PathBuf,
+    /// Kit version shared with the SDK, e.g. `10.0.22621.0`
+    pub version: String,
+    /// Newest KMDF version with headers installed, e.g. `1.33`
+    pub kmdf_version: Option<String>,
+    /// Newest UMDF version with headers installed, e.g. `2.33`
+    pub umdf_version: Option<String>,
+}
+
+impl Wdk {
+    /// Looks for the WDK matching the SDK selected in `env`. Returns `None` when
+    /// the kernel-mode headers for that SDK version are not installed.
+    pub fn detect(env: &MsvcEnvironment) -> Option<Wdk> {
+        let root = PathBuf::from(env.get("WindowsSdkDir")?.trim_end_matches('\\'));
+        let version = env
+            .get("WindowsSDKVersion")?
+            .trim_end_matches('\\')
+            .to_string();
+        Wdk::at(root, version)
+    }
+
+    /// Looks for a WDK of `version` in the kit rooted at `root`
+    pub fn at(root: impl Into<PathBuf>, version: impl Into<String>) -> Option<Wdk> {
+        let root = root.into();
+        let version = version.into();
+
+        let km_include = root.join("Include").join(&version).join("km");
+        if !km_include.join("wdm.h").exists() {
+            tracing::trace!("No WDK headers at {}", km_include.display());
+            return None;
+        }
+
+        let wdf_include = root.join("Include").join("wdf");
+        let wdk = Wdk {
+            kmdf_version: latest_version_dir(&wdf_include.join("kmdf")),
+            umdf_version: latest_version_dir(&wdf_include.join("umdf")),
+            root,
+            version,
+        };
+        tracing::trace!("Found WDK {:?}", wdk);
+        Some(wdk)
+    }
+
+    /// Returns `env` with the include and library directories for `model` prepended
+    /// to INCLUDE and LIB, and `WDKContentRoot` pointing at the kit
+    pub fn apply(
+        &self,
+        env: &MsvcEnvironment,
+        arch: MsvcArch,
+        model: DriverModel,
+    ) -> Result<MsvcEnvironment, MsvcEnvError> {
+        let lib_arch = match arch {
+            MsvcArch::X86 => "x86",
+            MsvcArch::X64 => "x64",
+            MsvcArch::Arm => "arm",
+            MsvcArch::Arm64 => "arm64",
+            MsvcArch::All => {
+                return Err(MsvcEnvError::ArchNotSupported(
+                    arch,
+                    "WDK libraries".to_string(),
+                ));
+            }
+        };
+
+        let include = self.root.join("Include").join(&self.version);
+        let lib = self.root.join("Lib").join(&self.version);
+        let wdf_include = self.root.join("Include").join("wdf");
+        let wdf_lib = self.root.join("Lib").join("wdf");
+
+        let (includes, libs) = match model {
+            DriverModel::Wdm => (
+                vec![include.join("km"), include.join("km").join("crt")],
+                vec![lib.join("km").join(lib_arch)],
+            ),
+            DriverModel::Kmdf => {
+                let kmdf = self.framework_version(&self.kmdf_version, "KMDF")?;
+                (
+                    vec![
+                        include.join("km"),
+                        include.join("km").join("crt"),
+                        wdf_include.join("kmdf").join(kmdf),
+                    ],
+                    vec![
+                        lib.join("km").join(lib_arch),
+                        wdf_lib.join("kmdf").join(lib_arch).join(kmdf),
+                    ],
+                )
+            }
+            DriverModel::Umdf => {
+                let umdf = self.framework_version(&self.umdf_version, "UMDF")?;
+                (
+                    vec![wdf_include.join("umdf").join(umdf)],
+                    vec![wdf_lib.join("umdf").join(lib_arch).join(umdf)],
+                )
+            }
+        };
+
+        let mut env = env.clone();
+        prepend_paths(&mut env, "INCLUDE", &includes);
+        prepend_paths(&mut env, "LIB", &libs);
+        env.vars.insert(
+            "WDKContentRoot".to_string(),
+            format!("{}\\", self.root.display()),
+        );
+        env.sort();
+        Ok(env)
+    }
+
+    fn framework_version<'a>(
+        &self,
+        version: &'a Option<String>,
+        name: &str,
+    ) -> Result<&'a str, MsvcEnvError> {
+        version.as_deref().ok_or_else(|| {
+            MsvcEnvError::NoWdk(format!(
+                "{} headers not found in {}",
+                name,
+                self.root.display()
+            ))
+        })
+    }
+}
+
+impl MsvcEnv {
+    /// Gets the environment for `arch` with the WDK matching its SDK layered on top
+    pub fn wdk_environment(
+        &self,
+        arch: MsvcArch,
+        model: DriverModel,
+    ) -> Result<MsvcEnvironment, MsvcEnvError> {
+        let env = self.environment(arch)?;
+        let wdk = Wdk::detect(&env).ok_or_else(|| {
+            MsvcEnvError::NoWdk(format!(
+                "no kernel-mode headers for SDK {}",
+                env.get("WindowsSDKVersion").unwrap_or("(none)")
+            ))
+        })?;
+        wdk.apply(&env, arch, model)
+    }
+}
+
+/// Prepends `paths` to the `;`-separated list in `key`, keeping the variable's existing casing
+fn prepend_paths(env: &mut MsvcEnvironment, key: &str, paths: &[PathBuf]) {
+    let existing_key = env
+        .vars
+        .keys()
+        .find(|k| k.eq_ignore_ascii_case(key))
+        .cloned()
+        .unwrap_or_else(|| key.to_string());
+
+    let mut entries = paths
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect::<Vec<_>>();
+    if let Some(existing) = env.vars.get(&existing_key).filter(|x| !x.is_empty()) {
+        entries.push(existing.clone());
+    }
+
+    env.vars.insert(existing_key, entries.join(";"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indexmap::IndexMap;
+    use std::fs;
+
+    #[test]
+    fn test_wdk_apply() {
+        let root = std::env::temp_dir().join(format!("msvc-env-wdk-{}", std::process::id()));
+        let version = "10.0.22621.0";
+        let km = root.join("Include").join(version).join("km");
+        fs::create_dir_all(&km).unwrap();
+        fs::write(km.join("wdm.h"), "").unwrap();
+        for kmdf in ["1.31", "1.33", "1.9"] {
+            fs::create_dir_all(root.join("Include").join("wdf").join("kmdf").join(kmdf)).unwrap();
+        }
+
+        let mut vars = IndexMap::new();
+        vars.insert("WindowsSdkDir".to_string(), format!("{}\\", root.display()));
+        vars.insert("WindowsSDKVersion".to_string(), format!("{}\\", version));
+        vars.insert("INCLUDE".to_string(), "C:\\VC\\include".to_string());
+        let env = MsvcEnvironment::new(vars);
+
+        let wdk = Wdk::detect(&env).unwrap();
+        assert_eq!(wdk.version, version);
+        assert_eq!(wdk.kmdf_version.as_deref(), Some("1.33"));
+        assert_eq!(wdk.umdf_version, None);
+
+        let kmdf = wdk.apply(&env, MsvcArch::X64, DriverModel::Kmdf).unwrap();
+        let include = kmdf.get("INCLUDE").unwrap();
+        assert!(include.starts_with(&km.display().to_string()));
+        assert!(include.ends_with(";C:\\VC\\include"));
+        assert!(include.contains("1.33"));
+        assert!(kmdf.get("LIB").unwrap().contains("x64"));
+        assert!(kmdf.get("WDKContentRoot").is_some());
+
+        assert!(wdk.apply(&env, MsvcArch::X64, DriverModel::Umdf).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}